@@ -1,6 +1,17 @@
 #ifndef WKW_H
 #define WKW_H
 
+#define WKW_OK                      0
+#define WKW_ERR_IO                  1
+#define WKW_ERR_INVALID_HEADER      2
+#define WKW_ERR_CORRUPT_JUMP_TABLE  3
+#define WKW_ERR_COMPRESSION         4
+#define WKW_ERR_OUT_OF_BOUNDS       5
+#define WKW_ERR_VOXEL_MISMATCH      6
+#define WKW_ERR_ALREADY_EXISTS      7
+#define WKW_ERR_INVALID_ARGUMENT    8
+#define WKW_ERR_CHECKSUM_MISMATCH   9
+
 struct header {
     uint8_t version;
     uint8_t block_len;
@@ -8,6 +19,7 @@ struct header {
     uint8_t block_type;
     uint8_t voxel_type;
     uint8_t voxel_size;
+    uint8_t compression_level;
 };
 
 typedef struct dataset dataset_t;
@@ -16,9 +28,10 @@ dataset_t * dataset_open(const char * root);
 void   dataset_close(const dataset_t * handle);
 int    dataset_read(const dataset_t * handle, const uint32_t * bbox, void * data);
 int    dataset_write(const dataset_t * handle, const uint32_t * bbox, const void * data, bool data_in_c_order);
-void   dataset_get_header(const dataset_t * handle, struct header * header);
+int    dataset_get_header(const dataset_t * handle, struct header * header);
 void * dataset_create(const char * root, const struct header * header);
 int    file_compress(const char * src_path, const char * dst_path);
 char * get_last_error_msg();
+int    get_last_error_code();
 
 #endif
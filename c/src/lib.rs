@@ -20,16 +20,47 @@ pub struct Header {
     pub block_type: u8,
     pub voxel_type: u8,
     pub voxel_size: u8,
+    pub compression_level: u8,
 }
 
-fn as_log2(i: u8) -> Result<u8, &'static str> {
-    match i & (i - 1) == 0 {
+// error codes, see `WKW_ERR_*` in wkw.h
+const WKW_ERR_IO: c_int = 1;
+const WKW_ERR_INVALID_HEADER: c_int = 2;
+const WKW_ERR_CORRUPT_JUMP_TABLE: c_int = 3;
+const WKW_ERR_COMPRESSION: c_int = 4;
+const WKW_ERR_OUT_OF_BOUNDS: c_int = 5;
+const WKW_ERR_VOXEL_MISMATCH: c_int = 6;
+const WKW_ERR_ALREADY_EXISTS: c_int = 7;
+const WKW_ERR_INVALID_ARGUMENT: c_int = 8;
+const WKW_ERR_CHECKSUM_MISMATCH: c_int = 9;
+
+fn error_code(err: &wkw::Error) -> c_int {
+    match *err.root() {
+        wkw::Error::Io { .. } | wkw::Error::Rename { .. } => WKW_ERR_IO,
+        wkw::Error::InvalidHeader { .. } => WKW_ERR_INVALID_HEADER,
+        wkw::Error::CorruptJumpTable { .. } => WKW_ERR_CORRUPT_JUMP_TABLE,
+        wkw::Error::Compression(_) | wkw::Error::Decompression(_) => WKW_ERR_COMPRESSION,
+        wkw::Error::OutOfBounds(_) => WKW_ERR_OUT_OF_BOUNDS,
+        wkw::Error::VoxelTypeMismatch { .. }
+        | wkw::Error::VoxelSizeMismatch { .. }
+        | wkw::Error::ShapeMismatch { .. } => WKW_ERR_VOXEL_MISMATCH,
+        wkw::Error::AlreadyExists(_) => WKW_ERR_ALREADY_EXISTS,
+        wkw::Error::ChecksumMismatch { .. } => WKW_ERR_CHECKSUM_MISMATCH,
+        _ => WKW_ERR_INVALID_ARGUMENT,
+    }
+}
+
+fn as_log2(field: &'static str, i: u8) -> wkw::Result<u8> {
+    match i != 0 && i & (i - 1) == 0 {
         true => Ok(i.trailing_zeros() as u8),
-        false => Err("Input must be a power of two"),
+        false => Err(wkw::Error::InvalidHeader {
+            field: field,
+            value: format!("{} (must be a power of two)", i),
+        }),
     }
 }
 
-fn from_header(header_ptr: *const Header) -> Result<wkw::Header, String> {
+fn from_header(header_ptr: *const Header) -> wkw::Result<wkw::Header> {
     assert!(!header_ptr.is_null());
 
     let c_header = unsafe { &*header_ptr };
@@ -38,7 +69,13 @@ fn from_header(header_ptr: *const Header) -> Result<wkw::Header, String> {
         1 => wkw::BlockType::Raw,
         2 => wkw::BlockType::LZ4,
         3 => wkw::BlockType::LZ4HC,
-        other => return Err(format!("Block type '{}' is invalid", other)),
+        4 => wkw::BlockType::Zstd,
+        other => {
+            return Err(wkw::Error::InvalidHeader {
+                field: "block_type",
+                value: other.to_string(),
+            })
+        }
     };
 
     let voxel_type = match c_header.voxel_type {
@@ -52,37 +89,71 @@ fn from_header(header_ptr: *const Header) -> Result<wkw::Header, String> {
         8 => wkw::VoxelType::I16,
         9 => wkw::VoxelType::I32,
         10 => wkw::VoxelType::I64,
-        other => return Err(format!("Voxel type '{}' is invalid", other)),
+        other => {
+            return Err(wkw::Error::InvalidHeader {
+                field: "voxel_type",
+                value: other.to_string(),
+            })
+        }
     };
 
-    let block_len_log2 = as_log2(c_header.block_len)?;
-    let file_len_log2 = as_log2(c_header.file_len)?;
+    let block_len_log2 = as_log2("block_len", c_header.block_len)?;
+    let file_len_log2 = as_log2("file_len", c_header.file_len)?;
 
     Ok(wkw::Header {
         version: c_header.version,
-        block_len_log2: block_len_log2,
-        file_len_log2: file_len_log2,
+        block_shape_log2: wkw::Vec3::from(block_len_log2 as u32),
+        file_shape_log2: wkw::Vec3::from(file_len_log2 as u32),
         block_type: block_type,
         voxel_type: voxel_type,
         voxel_size: c_header.voxel_size,
+        compression_level: c_header.compression_level,
+        checksums: false,
         data_offset: 0,
         jump_table: None,
+        checksum_table: None,
+        metadata: None,
+    })
+}
+
+fn to_header(header: &wkw::Header) -> wkw::Result<Header> {
+    // only cubic blocks and files can be described
+    if !header.is_cubic() {
+        return Err(wkw::Error::InvalidArgument(String::from(
+            "Blocks and files are not cubic",
+        )));
+    }
+
+    let as_len = |field: &'static str, log2: u32| match log2 < 8 {
+        true => Ok(1u8 << log2),
+        false => Err(wkw::Error::InvalidHeader {
+            field: field,
+            value: format!("2^{} (must be less than 256)", log2),
+        }),
+    };
+
+    Ok(Header {
+        version: header.version,
+        block_len: as_len("block_len", header.block_shape_log2.x)?,
+        file_len: as_len("file_len", header.file_shape_log2.x)?,
+        block_type: 1u8 + header.block_type as u8,
+        voxel_type: 1u8 + header.voxel_type as u8,
+        voxel_size: header.voxel_size,
+        compression_level: header.compression_level,
     })
 }
 
-fn check_return<T>(ret: Result<T, String>) -> c_int {
+fn check_return<T>(ret: wkw::Result<T>) -> c_int {
     match ret {
         Ok(_) => 0,
-        Err(msg) => {
-            set_last_error_msg(&msg);
-            1
-        }
+        Err(err) => set_last_error(&err),
     }
 }
 
 lazy_static! {
     static ref LAST_ERR_MSG: Mutex<Box<CStr>> =
         Mutex::new(CString::new("".as_bytes()).unwrap().into_boxed_c_str());
+    static ref LAST_ERR_CODE: Mutex<c_int> = Mutex::new(0);
 }
 
 #[no_mangle]
@@ -90,9 +161,17 @@ pub extern "C" fn get_last_error_msg() -> *const c_char {
     LAST_ERR_MSG.lock().unwrap().as_ptr()
 }
 
-fn set_last_error_msg(msg: &str) {
-    let c_string = CString::new(msg.as_bytes()).unwrap();
+#[no_mangle]
+pub extern "C" fn get_last_error_code() -> c_int {
+    *LAST_ERR_CODE.lock().unwrap()
+}
+
+fn set_last_error(err: &wkw::Error) -> c_int {
+    let code = error_code(err);
+    let c_string = CString::new(err.to_string().as_bytes()).unwrap();
     *LAST_ERR_MSG.lock().unwrap() = c_string.into_boxed_c_str();
+    *LAST_ERR_CODE.lock().unwrap() = code;
+    code
 }
 
 #[no_mangle]
@@ -106,8 +185,8 @@ pub extern "C" fn dataset_open(root_ptr: *const c_char) -> *const Dataset {
             let dataset_ptr = Box::from(dataset);
             unsafe { std::mem::transmute(dataset_ptr) }
         }
-        Err(msg) => {
-            set_last_error_msg(&msg);
+        Err(err) => {
+            set_last_error(&err);
             std::ptr::null::<Dataset>()
         }
     }
@@ -125,23 +204,18 @@ pub extern "C" fn dataset_close(dataset_ptr: *const Dataset) {
 }
 
 #[no_mangle]
-pub extern "C" fn dataset_get_header(dataset_ptr: *const Dataset, header_ptr: *mut Header) {
+pub extern "C" fn dataset_get_header(
+    dataset_ptr: *const Dataset,
+    header_ptr: *mut Header,
+) -> c_int {
     assert!(!dataset_ptr.is_null());
     assert!(!header_ptr.is_null());
 
     let dataset = unsafe { Box::from_raw(dataset_ptr as *mut wkwrap::Dataset) };
 
-    unsafe {
-        let header = dataset.header();
-        (*header_ptr).version = header.version;
-        (*header_ptr).block_len = 1u8 << header.block_len_log2;
-        (*header_ptr).file_len = 1u8 << header.file_len_log2;
-        (*header_ptr).block_type = 1u8 + header.block_type as u8;
-        (*header_ptr).voxel_type = 1u8 + header.voxel_type as u8;
-        (*header_ptr).voxel_size = header.voxel_size;
-    }
-
+    let ret = to_header(dataset.header()).map(|c_header| unsafe { *header_ptr = c_header });
     std::mem::forget(dataset);
+    check_return(ret)
 }
 
 #[no_mangle]
@@ -160,8 +234,8 @@ pub extern "C" fn dataset_create(
             let dataset_ptr = Box::from(dataset);
             unsafe { std::mem::transmute(dataset_ptr) }
         }
-        Err(msg) => {
-            set_last_error_msg(&msg);
+        Err(err) => {
+            set_last_error(&err);
             std::ptr::null::<Dataset>()
         }
     }
@@ -252,3 +326,135 @@ pub extern "C" fn dataset_write(
     std::mem::forget(dataset);
     check_return(ret)
 }
+
+#[test]
+fn test_error_code() {
+    use std::io;
+    use std::path::PathBuf;
+
+    let io_err = || io::Error::new(io::ErrorKind::NotFound, "gone");
+    let errors = vec![
+        (
+            wkw::Error::Io {
+                context: "Could not open file",
+                path: None,
+                source: io_err(),
+            },
+            WKW_ERR_IO,
+        ),
+        (
+            wkw::Error::Rename {
+                from: PathBuf::from("a_tmp"),
+                to: PathBuf::from("a"),
+                source: io_err(),
+            },
+            WKW_ERR_IO,
+        ),
+        (
+            wkw::Error::InvalidHeader {
+                field: "version",
+                value: String::from("9"),
+            },
+            WKW_ERR_INVALID_HEADER,
+        ),
+        (
+            wkw::Error::CorruptJumpTable { block_idx: 0 },
+            WKW_ERR_CORRUPT_JUMP_TABLE,
+        ),
+        (wkw::Error::Compression("lz4"), WKW_ERR_COMPRESSION),
+        (
+            wkw::Error::Decompression(String::from("lz4")),
+            WKW_ERR_COMPRESSION,
+        ),
+        (
+            wkw::Error::OutOfBounds("Block index"),
+            WKW_ERR_OUT_OF_BOUNDS,
+        ),
+        (
+            wkw::Error::VoxelSizeMismatch {
+                expected: 1,
+                found: 2,
+            },
+            WKW_ERR_VOXEL_MISMATCH,
+        ),
+        (
+            wkw::Error::AlreadyExists(PathBuf::from("ds")),
+            WKW_ERR_ALREADY_EXISTS,
+        ),
+        (
+            wkw::Error::InvalidArgument(String::from("bad")),
+            WKW_ERR_INVALID_ARGUMENT,
+        ),
+        (
+            wkw::Error::ChecksumMismatch {
+                block_idx: 0,
+                expected: 0,
+                found: 1,
+            },
+            WKW_ERR_CHECKSUM_MISMATCH,
+        ),
+    ];
+
+    for (err, code) in errors {
+        // file contexts do not change the code
+        let err = wkw::Error::InFile {
+            path: PathBuf::from("x0.wkw"),
+            source: Box::new(err),
+        };
+        assert_eq!(error_code(&err), code);
+
+        assert_eq!(set_last_error(&err), code);
+        assert_eq!(get_last_error_code(), code);
+        let msg = unsafe { CStr::from_ptr(get_last_error_msg()) };
+        assert_eq!(msg.to_str().unwrap(), err.to_string());
+    }
+}
+
+#[test]
+fn test_dataset_get_header() {
+    let root = std::env::temp_dir().join(format!("wkw-c-header-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+
+    let mut header = wkw::Header {
+        version: 3,
+        block_shape_log2: wkw::Vec3::from(5u32),
+        file_shape_log2: wkw::Vec3::from(2u32),
+        block_type: wkw::BlockType::LZ4,
+        voxel_type: wkw::VoxelType::U16,
+        voxel_size: 2,
+        compression_level: 0,
+        checksums: false,
+        data_offset: 0,
+        jump_table: None,
+        checksum_table: None,
+        metadata: None,
+    };
+    wkw::Dataset::create(&root, header.clone()).unwrap();
+
+    let root_c = CString::new(root.to_str().unwrap()).unwrap();
+    let dataset_ptr = dataset_open(root_c.as_ptr());
+    assert!(!dataset_ptr.is_null());
+
+    let mut c_header = Header {
+        version: 0,
+        block_len: 0,
+        file_len: 0,
+        block_type: 0,
+        voxel_type: 0,
+        voxel_size: 0,
+        compression_level: 0,
+    };
+    assert_eq!(dataset_get_header(dataset_ptr, &mut c_header), 0);
+    assert_eq!((c_header.block_len, c_header.file_len), (32, 4));
+    assert_eq!((c_header.block_type, c_header.voxel_type), (2, 2));
+    dataset_close(dataset_ptr);
+
+    // lengths of non-cubic blocks and files cannot be reported
+    header.file_shape_log2.z = 1;
+    match to_header(&header) {
+        Err(ref err) => assert_eq!(error_code(err), WKW_ERR_INVALID_ARGUMENT),
+        Ok(_) => panic!("Non-cubic header was reported"),
+    }
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
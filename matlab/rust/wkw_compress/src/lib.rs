@@ -17,5 +17,7 @@ mex_function!(_nlhs, _lhs, nrhs, rhs, {
     let dst_path = Path::new(mx_array_to_str(rhs[1])?);
 
     let mut file = wkwrap::File::open(&src_path)?;
-    file.compress(&dst_path)
+    file.compress(&dst_path)?;
+
+    Ok(())
 });
@@ -28,13 +28,17 @@ unsafe fn new(nrhs: c_int, rhs: *const MxArray) -> Result<()> {
 
     let header = wkwrap::Header {
         version: 1,
-        block_len_log2: block_len_log2,
-        file_len_log2: file_len_log2,
+        block_shape_log2: wkwrap::Vec3::from(block_len_log2 as u32),
+        file_shape_log2: wkwrap::Vec3::from(file_len_log2 as u32),
         block_type: block_type,
         voxel_type: voxel_type,
         voxel_size: voxel_size,
+        compression_level: 0,
+        checksums: false,
         data_offset: 0,
-        jump_table: None
+        jump_table: None,
+        checksum_table: None,
+        metadata: None
     };
 
     // create dataset
@@ -43,8 +47,17 @@ unsafe fn new(nrhs: c_int, rhs: *const MxArray) -> Result<()> {
     Ok(())
 }
 
+fn str_slice_to_block_type(block_type: &str) -> Result<wkwrap::BlockType> {
+    match block_type {
+        "lz4"   => Ok(wkwrap::BlockType::LZ4),
+        "lz4hc" => Ok(wkwrap::BlockType::LZ4HC),
+        "zstd"  => Ok(wkwrap::BlockType::Zstd),
+        _       => Err("Unknown block type".to_string())
+    }
+}
+
 unsafe fn compress(nrhs: c_int, rhs: *const MxArray) -> Result<()> {
-    let rhs = match nrhs == 2 {
+    let rhs = match nrhs >= 2 && nrhs <= 4 {
         true => slice::from_raw_parts(rhs, nrhs as usize),
         false => return Err("Invalid number of input arguments".to_string())
     };
@@ -52,8 +65,25 @@ unsafe fn compress(nrhs: c_int, rhs: *const MxArray) -> Result<()> {
     let src_path = Path::new(mx_array_to_str(rhs[0])?);
     let dst_path = Path::new(mx_array_to_str(rhs[1])?);
 
+    let block_type = match rhs.len() > 2 {
+        true => str_slice_to_block_type(mx_array_to_str(rhs[2])?)?,
+        false => wkwrap::BlockType::LZ4HC
+    };
+
+    let compression_level = match rhs.len() > 3 {
+        true => as_nat(mx_array_to_f64(rhs[3])?)?,
+        false => block_type.default_compression_level() as u64
+    };
+
+    let levels = block_type.compression_levels();
+    if compression_level < *levels.start() as u64 || compression_level > *levels.end() as u64 {
+        return Err(format!(
+            "Compression level must be in {}..={} for block type {:?}",
+            levels.start(), levels.end(), block_type));
+    }
+
     let dataset = wkwrap::Dataset::new(&src_path)?;
-    dataset.compress(&dst_path)?;
+    dataset.compress_with(&dst_path, block_type, compression_level as u8)?;
 
     Ok(())
 }
@@ -21,10 +21,10 @@ pub fn mx_array_to_wkwrap_box(pm: MxArray) -> Result<wkwrap::Box3> {
         return Err("Bounding box has invalid shape".to_string());
     }
 
-    wkwrap::Box3::new(
+    Ok(wkwrap::Box3::new(
         f64_slice_to_wkwrap_vec(&buf[0..3]).or(Err("Invalid lower bound".to_string()))? - 1,
         f64_slice_to_wkwrap_vec(&buf[3..6]).or(Err("Invalid upper bound".to_string()))?
-    )
+    )?)
 }
 
 pub fn mx_array_to_wkwrap_vec(pm: MxArray) -> Result<wkwrap::Vec3> {
@@ -98,5 +98,5 @@ pub fn mx_array_mut_to_wkwrap_mat<'a>(
     // voxel type
     let voxel_type = mx_class_id_to_voxel_type(unsafe { mxGetClassID(pm) })?;
 
-    wkwrap::Mat::new(buf, shape, voxel_size, voxel_type, false)
+    Ok(wkwrap::Mat::new(buf, shape, voxel_size, voxel_type, false)?)
 }
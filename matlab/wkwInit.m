@@ -1,7 +1,7 @@
 function wkwInit(command, varargin) %#ok
     % wkwInit(command, varargin)
     % wkwInit('new', rootDir, blockLen, fileLen, dataType, numChannels)
-    % wkwInit('compress', sourceDir, destinationDir)
+    % wkwInit('compress', sourceDir, destinationDir, blockType, level)
     %
     %   Initializes a new WKW dataset. This functions supports multiple
     %   operation modes
@@ -30,7 +30,7 @@ function wkwInit(command, varargin) %#ok
     %
     %
     % Creation of a compressed version of an existing dataset with
-    %   wkwInit('compress', sourceDir, destinationDir)
+    %   wkwInit('compress', sourceDir, destinationDir, blockType, level)
     %
     %   sourceDir
     %     String. Path to the root of the existing WKW dataset.
@@ -38,6 +38,15 @@ function wkwInit(command, varargin) %#ok
     %   destinationDir
     %     String. Path to the root of the new, compressed WKW dataset.
     %
+    %   blockType
+    %     String. Compression algorithm. Can be 'lz4', 'lz4hc', or 'zstd'.
+    %     (Default: 'lz4hc')
+    %
+    %   level
+    %     Double. Positive compression level, up to 12 for 'lz4hc' and
+    %     22 for 'zstd'. Other levels require a version-2 dataset. If
+    %     omitted, the default level of the compression algorithm is used.
+    %
     %
     % Written by
     %   Alessandro Motta <alessandro.motta@brain.mpg.de>
@@ -5,6 +5,7 @@ import platform
 import os
 from copy import deepcopy
 from glob import iglob
+from typing import Optional
 
 
 def _init_libwkw():
@@ -59,8 +60,23 @@ class Header:
     BLOCK_TYPE_RAW = 1
     BLOCK_TYPE_LZ4 = 2
     BLOCK_TYPE_LZ4HC = 3
+    BLOCK_TYPE_ZSTD = 4
+
+    VALID_BLOCK_TYPES = [
+        BLOCK_TYPE_RAW,
+        BLOCK_TYPE_LZ4,
+        BLOCK_TYPE_LZ4HC,
+        BLOCK_TYPE_ZSTD,
+    ]
+    # Inclusive bounds of the compression level per block type
+    COMPRESSION_LEVELS = {
+        BLOCK_TYPE_RAW: (0, 0),
+        BLOCK_TYPE_LZ4: (0, 0),
+        BLOCK_TYPE_LZ4HC: (0, 12),
+        BLOCK_TYPE_ZSTD: (1, 22),
+    }
+    DEFAULT_COMPRESSION_LEVELS = {BLOCK_TYPE_ZSTD: 3}
 
-    VALID_BLOCK_TYPES = [BLOCK_TYPE_RAW, BLOCK_TYPE_LZ4, BLOCK_TYPE_LZ4HC]
     VALID_VOXEL_TYPES = [
         np.uint8,
         np.uint16,
@@ -82,6 +98,7 @@ class Header:
         block_len: int = 32,
         file_len: int = 32,
         block_type: int = 1,
+        compression_level: Optional[int] = None,
     ):
         self.version = version
 
@@ -100,6 +117,14 @@ class Header:
         ), f"block_type must be one of {self.VALID_BLOCK_TYPES}, but is {block_type}"
         self.block_type = block_type
 
+        if compression_level is None:
+            compression_level = self.DEFAULT_COMPRESSION_LEVELS.get(block_type, 0)
+        min_level, max_level = self.COMPRESSION_LEVELS[block_type]
+        assert (
+            min_level <= compression_level <= max_level
+        ), f"compression_level must be in [{min_level}, {max_level}], but is {compression_level}"
+        self.compression_level = compression_level
+
         assert (
             voxel_type in self.VALID_VOXEL_TYPES
         ), f"voxel_type must be one of {self.VALID_VOXEL_TYPES}, but is {voxel_type}"
@@ -127,6 +152,7 @@ class Header:
             block_type=block_type,
             voxel_type=voxel_type,
             num_channels=num_channels,
+            compression_level=header_c.compression_level,
         )
 
     def to_c(self):
@@ -141,6 +167,7 @@ class Header:
         header_c.block_type = self.block_type
         header_c.voxel_type = voxel_type_c
         header_c.voxel_size = voxel_size
+        header_c.compression_level = self.compression_level
         return header_c
 
 
@@ -172,7 +199,12 @@ class Dataset:
         self.handle = handle
 
         header_c = ffi.new("struct header *")
-        libwkw.dataset_get_header(self.handle, header_c)
+        try:
+            # fails for datasets with non-cubic blocks or files
+            _check_wkw(libwkw.dataset_get_header(self.handle, header_c))
+        except WKWException:
+            libwkw.dataset_close(self.handle)
+            raise
         self.header = Header.from_c(header_c)
 
     def read(self, off, shape):
@@ -226,6 +258,7 @@ class Dataset:
     def compress(self, dst_path: str, compress_files: bool = False):
         header = deepcopy(self.header)
         header.block_type = Header.BLOCK_TYPE_LZ4HC
+        header.compression_level = 0
 
         dataset = Dataset.create(dst_path, header)
 
@@ -0,0 +1,442 @@
+extern crate wkwrap;
+
+use std::io::Write;
+use std::path::Path;
+use std::{env, fs, io, process};
+use wkwrap::convert::tiff;
+use wkwrap::{BlockType, Box3, Dataset, File, Header, Mat, Vec3, VoxelType};
+
+static USAGE: &str = "\
+Usage: wkw <command> [<args>]
+
+Commands:
+  info <dataset>                       Print header and size of dataset
+  ls <dataset>                         List all files of dataset
+  cat <dataset> <x> <y> <z> <w> <h> <d>
+                                       Write raw voxel data of bounding box
+                                       to standard output (Fortran order)
+  compress <src> <dst> [<type> [<level>]]
+                                       Compress dataset with block type
+                                       lz4, lz4hc (default), or zstd, and
+                                       level 0-12 (lz4hc) or 1-22 (zstd)
+  verify <dataset>                     Check integrity of every file
+  bbox <dataset> [--refine]            Print bounding box of all files,
+                                       optionally refined to non-zero blocks
+  tiff-import <dir> <dataset> [<type>] Create dataset from a directory of TIFF
+                                       slices, with block type raw (default),
+                                       lz4, lz4hc, or zstd
+  tiff-export <dataset> <dir> <x> <y> <z> <w> <h> <d>
+                                       Write bounding box as TIFF slices";
+
+type Result<T> = std::result::Result<T, String>;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let stdout = io::stdout();
+    let result = run(&args, &mut stdout.lock());
+
+    if let Err(msg) = result {
+        eprintln!("wkw: {}", msg);
+        process::exit(1);
+    }
+}
+
+// Runs the command in `args`, writing its output to `out`.
+fn run(args: &[String], out: &mut dyn Write) -> Result<()> {
+    match args.first().map(String::as_str) {
+        Some("info") => info(&args[1..], out),
+        Some("ls") => ls(&args[1..], out),
+        Some("cat") => cat(&args[1..], out),
+        Some("compress") => compress(&args[1..]),
+        Some("verify") => verify(&args[1..], out),
+        Some("bbox") => bbox(&args[1..], out),
+        Some("tiff-import") => tiff_import(&args[1..]),
+        Some("tiff-export") => tiff_export(&args[1..]),
+        Some("-h") | Some("--help") => write_line(out, USAGE),
+        _ => Err(String::from(USAGE)),
+    }
+}
+
+fn write_line(out: &mut dyn Write, line: &str) -> Result<()> {
+    writeln!(out, "{}", line).map_err(write_error)
+}
+
+fn write_error(err: io::Error) -> String {
+    format!("Could not write to standard output: {}", err)
+}
+
+fn open(args: &[String], num_args: usize) -> Result<Dataset> {
+    if args.len() != num_args {
+        return Err(String::from("Invalid number of arguments"));
+    }
+
+    Ok(Dataset::new(Path::new(&args[0]))?)
+}
+
+fn parse_u32(arg: &str) -> Result<u32> {
+    arg.parse::<u32>()
+        .map_err(|_| format!("Invalid integer {:?}", arg))
+}
+
+fn parse_block_type(arg: &str) -> Result<BlockType> {
+    match arg {
+        "lz4" => Ok(BlockType::LZ4),
+        "lz4hc" => Ok(BlockType::LZ4HC),
+        "zstd" => Ok(BlockType::Zstd),
+        _ => Err(format!("Invalid block type {:?}", arg)),
+    }
+}
+
+fn parse_compression_level(arg: &str, block_type: BlockType) -> Result<u8> {
+    let levels = block_type.compression_levels();
+    match parse_u32(arg)? {
+        level if level <= *levels.end() as u32 && levels.contains(&(level as u8)) => {
+            Ok(level as u8)
+        }
+        _ => Err(format!(
+            "Invalid compression level {:?} for block type {:?} (must be in {}..={})",
+            arg,
+            block_type,
+            levels.start(),
+            levels.end()
+        )),
+    }
+}
+
+fn info(args: &[String], out: &mut dyn Write) -> Result<()> {
+    let dataset = open(args, 1)?;
+    let header = dataset.header();
+    let files = dataset.list_files()?;
+
+    let mut size_on_disk = 0;
+    for (_, path) in &files {
+        let metadata =
+            fs::metadata(path).map_err(|err| format!("Could not stat {:?}: {}", path, err))?;
+        size_on_disk += metadata.len();
+    }
+
+    let (block_shape, file_shape) = (header.block_shape(), header.file_shape());
+    let mut lines = vec![
+        format!("version:           {}", header.version),
+        format!(
+            "block shape:       {}x{}x{}",
+            block_shape.x, block_shape.y, block_shape.z
+        ),
+        format!(
+            "file shape:        {}x{}x{}",
+            file_shape.x, file_shape.y, file_shape.z
+        ),
+        format!("block type:        {:?}", header.block_type),
+        format!("compression level: {}", header.compression_level),
+        format!("checksums:         {}", header.checksums),
+        format!("voxel type:        {:?}", header.voxel_type),
+        format!("channels:          {}", header.num_channels()),
+        format!("fill value:        {:?}", dataset.fill_value()),
+    ];
+
+    if let Some(ref metadata) = header.metadata {
+        if let Some(voxel_size_nm) = metadata.voxel_size_nm {
+            lines.push(format!("voxel size (nm):   {:?}", voxel_size_nm));
+        }
+        if let Some(ref axis_order) = metadata.axis_order {
+            lines.push(format!("axis order:        {}", axis_order));
+        }
+        if let Some(ref creation_tool) = metadata.creation_tool {
+            lines.push(format!("created by:        {}", creation_tool));
+        }
+    }
+
+    lines.push(format!("files:             {}", files.len()));
+    lines.push(format!("size on disk:      {} bytes", size_on_disk));
+
+    for line in &lines {
+        write_line(out, line)?;
+    }
+
+    Ok(())
+}
+
+fn ls(args: &[String], out: &mut dyn Write) -> Result<()> {
+    let dataset = open(args, 1)?;
+
+    for (ids, path) in dataset.list_files()? {
+        writeln!(out, "{} {} {} {}", ids.x, ids.y, ids.z, path.display()).map_err(write_error)?;
+    }
+
+    Ok(())
+}
+
+fn cat(args: &[String], out: &mut dyn Write) -> Result<()> {
+    let dataset = open(args, 7)?;
+    let header = dataset.header();
+
+    let bbox = parse_box(&args[1..])?;
+    let off = bbox.min();
+    let shape = bbox.width();
+
+    let voxel_size = header.voxel_size as usize;
+    let mut buf = vec![0u8; shape.product() as usize * voxel_size];
+
+    {
+        let mut mat = Mat::new(&mut buf, shape, voxel_size, header.voxel_type, false)?;
+        dataset.read_mat(off, &mut mat)?;
+    }
+
+    out.write_all(&buf)
+        .and_then(|_| out.flush())
+        .map_err(write_error)
+}
+
+fn compress(args: &[String]) -> Result<()> {
+    if args.len() < 2 || args.len() > 4 {
+        return Err(String::from("Invalid number of arguments"));
+    }
+
+    let dataset = open(&args[..1], 1)?;
+    let dst_root = Path::new(&args[1]);
+
+    let block_type = match args.get(2) {
+        Some(arg) => parse_block_type(arg)?,
+        None => BlockType::LZ4HC,
+    };
+
+    let compression_level = match args.get(3) {
+        Some(arg) => parse_compression_level(arg, block_type)?,
+        None => block_type.default_compression_level(),
+    };
+
+    dataset.compress_with(dst_root, block_type, compression_level)?;
+
+    for (ids, path) in dataset.list_files()? {
+        let mut dst_path = dst_root.to_owned();
+        dst_path.push(format!("z{}", ids.z));
+        dst_path.push(format!("y{}", ids.y));
+        dst_path.push(format!("x{}.wkw", ids.x));
+
+        let mut file = File::open(&path)?;
+        file.compress_with(&dst_path, block_type, compression_level)?;
+    }
+
+    Ok(())
+}
+
+fn verify(args: &[String], out: &mut dyn Write) -> Result<()> {
+    let dataset = open(args, 1)?;
+    let mut num_corrupt = 0;
+
+    for report in dataset.verify()? {
+        if report.is_ok() {
+            continue;
+        }
+
+        for problem in &report.problems {
+            writeln!(out, "{}: {}", report.path.display(), problem).map_err(write_error)?;
+        }
+
+        num_corrupt += 1;
+    }
+
+    match num_corrupt {
+        0 => Ok(()),
+        _ => Err(format!("{} corrupt files", num_corrupt)),
+    }
+}
+
+fn bbox(args: &[String], out: &mut dyn Write) -> Result<()> {
+    let refine = match args.len() {
+        1 => false,
+        2 if args[1] == "--refine" => true,
+        _ => return Err(String::from("Invalid arguments")),
+    };
+
+    let dataset = open(&args[..1], 1)?;
+
+    match dataset.bounding_box(refine)? {
+        Some(bbox) => write_line(out, &format_box(bbox)),
+        None => write_line(out, "empty"),
+    }
+}
+
+fn tiff_import(args: &[String]) -> Result<()> {
+    if args.len() < 2 || args.len() > 3 {
+        return Err(String::from("Invalid number of arguments"));
+    }
+
+    let block_type = match args.get(2).map(String::as_str) {
+        Some("raw") | None => BlockType::Raw,
+        Some(arg) => parse_block_type(arg)?,
+    };
+
+    // voxel type and size are taken from the TIFF files
+    let template = Header {
+        version: 1,
+        block_shape_log2: Vec3::from(5u32),
+        file_shape_log2: Vec3::from(5u32),
+        block_type,
+        voxel_type: VoxelType::U8,
+        voxel_size: 1,
+        compression_level: block_type.default_compression_level(),
+        checksums: false,
+        data_offset: 0,
+        jump_table: None,
+        checksum_table: None,
+        metadata: None,
+    };
+
+    tiff::import(Path::new(&args[0]), Path::new(&args[1]), &template)?;
+    Ok(())
+}
+
+fn tiff_export(args: &[String]) -> Result<()> {
+    if args.len() != 8 {
+        return Err(String::from("Invalid number of arguments"));
+    }
+
+    let dataset = open(&args[..1], 1)?;
+    let bbox = parse_box(&args[2..])?;
+
+    tiff::export(&dataset, bbox, Path::new(&args[1]))?;
+    Ok(())
+}
+
+fn parse_box(args: &[String]) -> Result<Box3> {
+    let nums = args
+        .iter()
+        .map(|arg| parse_u32(arg))
+        .collect::<Result<Vec<u32>>>()?;
+
+    let off = Vec3 {
+        x: nums[0],
+        y: nums[1],
+        z: nums[2],
+    };
+    let shape = Vec3 {
+        x: nums[3],
+        y: nums[4],
+        z: nums[5],
+    };
+
+    Ok(Box3::from(shape) + off)
+}
+
+fn format_box(bbox: Box3) -> String {
+    let off = bbox.min();
+    let shape = bbox.width();
+
+    format!(
+        "{} {} {} {} {} {}",
+        off.x, off.y, off.z, shape.x, shape.y, shape.z
+    )
+}
+
+#[cfg(test)]
+fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|&arg| String::from(arg)).collect()
+}
+
+#[test]
+fn test_parse_args() {
+    assert_eq!(parse_u32("42"), Ok(42));
+    assert!(parse_u32("-1").is_err());
+    assert!(parse_u32("x").is_err());
+
+    assert_eq!(parse_block_type("zstd"), Ok(BlockType::Zstd));
+    assert!(parse_block_type("raw").is_err());
+
+    assert_eq!(parse_compression_level("12", BlockType::LZ4HC), Ok(12));
+    assert!(parse_compression_level("13", BlockType::LZ4HC).is_err());
+    assert_eq!(parse_compression_level("22", BlockType::Zstd), Ok(22));
+    assert!(parse_compression_level("0", BlockType::Zstd).is_err());
+    assert!(parse_compression_level("257", BlockType::Zstd).is_err());
+    assert!(parse_compression_level("1", BlockType::LZ4).is_err());
+
+    let bbox = parse_box(&args(&["1", "2", "3", "4", "5", "6"])).unwrap();
+    assert_eq!(bbox.min(), Vec3 { x: 1, y: 2, z: 3 });
+    assert_eq!(bbox.width(), Vec3 { x: 4, y: 5, z: 6 });
+    assert_eq!(format_box(bbox), "1 2 3 4 5 6");
+
+    let mut out = Vec::new();
+    assert_eq!(run(&args(&[]), &mut out), Err(String::from(USAGE)));
+    assert_eq!(
+        run(&args(&["frobnicate"]), &mut out),
+        Err(String::from(USAGE))
+    );
+    assert!(run(&args(&["info"]), &mut out).is_err());
+    assert!(run(&args(&["bbox", "a", "--fine"]), &mut out).is_err());
+    assert!(run(&args(&["compress", "a", "b", "lz4hc", "13"]), &mut out).is_err());
+    assert!(out.is_empty());
+
+    run(&args(&["--help"]), &mut out).unwrap();
+    assert_eq!(out, format!("{}\n", USAGE).into_bytes());
+}
+
+#[test]
+fn test_commands() {
+    let root = env::temp_dir().join(format!("wkwrap-cli-{}", process::id()));
+    let _ = fs::remove_dir_all(&root);
+
+    let header = Header {
+        version: 1,
+        block_shape_log2: Vec3::from(2u32),
+        file_shape_log2: Vec3::from(1u32),
+        block_type: BlockType::Raw,
+        voxel_type: VoxelType::U8,
+        voxel_size: 1,
+        compression_level: 0,
+        checksums: false,
+        data_offset: 0,
+        jump_table: None,
+        checksum_table: None,
+        metadata: None,
+    };
+    let dataset = Dataset::create(&root, header).unwrap();
+
+    let path = root.to_str().unwrap();
+    let run_str = |cmd: &[&str]| -> Result<String> {
+        let mut out = Vec::new();
+        run(&args(cmd), &mut out).map(|_| String::from_utf8(out).unwrap())
+    };
+
+    assert_eq!(run_str(&["bbox", path]).unwrap(), "empty\n");
+
+    // spans 2 x 1 x 1 files of 8^3 voxels
+    let shape = Vec3 { x: 6, y: 3, z: 3 };
+    let mut data: Vec<u8> = (0..shape.product()).map(|i| (i % 13 + 1) as u8).collect();
+    let mat = Mat::new(&mut data, shape, 1, VoxelType::U8, false).unwrap();
+    dataset.write_mat(Vec3::from(5u32), &mat).unwrap();
+
+    let info = run_str(&["info", path]).unwrap();
+    assert!(info.starts_with("version:           1\n"));
+    assert!(info.contains("block shape:       4x4x4\n"));
+    assert!(info.contains("block type:        Raw\n"));
+    assert!(info.contains("files:             2\n"));
+    assert!(info.ends_with(&format!("size on disk:      {} bytes\n", 2 * (16 + 512))));
+
+    assert_eq!(run_str(&["bbox", path]).unwrap(), "0 0 0 16 8 8\n");
+    assert_eq!(
+        run_str(&["bbox", path, "--refine"]).unwrap(),
+        "4 4 4 8 4 4\n"
+    );
+
+    assert_eq!(run_str(&["verify", path]).unwrap(), "");
+
+    // truncated file
+    let file_path = root.join("z0").join("y0").join("x1.wkw");
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&file_path)
+        .and_then(|file| file.set_len(100))
+        .unwrap();
+
+    let mut out = Vec::new();
+    let err = run(&args(&["verify", path]), &mut out).unwrap_err();
+    assert_eq!(err, "1 corrupt files");
+
+    let out = String::from_utf8(out).unwrap();
+    let prefix = format!("{}: ", file_path.display());
+    assert!(!out.is_empty());
+    assert!(out.lines().all(|line| line.starts_with(&prefix)));
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,448 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use {File, Vec3};
+
+/// Map with a bounded total size of its entries. Unless given explicitly,
+/// each entry has size one. Once full, inserting evicts the least recently
+/// used entries.
+#[derive(Debug)]
+pub(crate) struct Lru<K, V> {
+    capacity: usize,
+    size: usize,
+    tick: u64,
+    // values with time of last use and size
+    entries: HashMap<K, (V, u64, usize)>,
+    // keys by time of last use
+    order: BTreeMap<u64, K>,
+}
+
+impl<K: Clone + Eq + Hash, V> Lru<K, V> {
+    pub fn new(capacity: usize) -> Lru<K, V> {
+        Lru {
+            capacity,
+            size: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.size > capacity {
+            self.evict();
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        let tick = self.next_tick();
+        match self.entries.get_mut(key) {
+            Some(&mut (ref value, ref mut last_used, _)) => {
+                let key = self.order.remove(last_used).unwrap();
+                self.order.insert(tick, key);
+                *last_used = tick;
+                Some(value)
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        self.insert_sized(key, value, 1)
+    }
+
+    /// Inserts an entry, replacing any previous entry with the same key.
+    /// Does nothing if the entry exceeds the capacity.
+    pub fn insert_sized(&mut self, key: K, value: V, size: usize) {
+        self.remove(&key);
+        if size > self.capacity {
+            return;
+        }
+
+        while self.size + size > self.capacity {
+            self.evict();
+        }
+
+        let tick = self.next_tick();
+        self.size += size;
+        self.order.insert(tick, key.clone());
+        self.entries.insert(key, (value, tick, size));
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        self.entries.remove(key).map(|(value, last_used, size)| {
+            self.order.remove(&last_used);
+            self.size -= size;
+            value
+        })
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+        self.size = 0;
+    }
+
+    fn evict(&mut self) {
+        let oldest = self.order.keys().next().cloned();
+        if let Some(oldest) = oldest {
+            let key = self.order[&oldest].clone();
+            self.remove(&key);
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+/// Generations of files, one of which is bumped whenever cached data of the
+/// file becomes stale. Data read without holding the lock of a cache is
+/// only put into the cache if the generation of its file is still the one
+/// from before the read, so that it cannot replace newer data.
+///
+/// Files share a fixed number of generations, so that the memory used does
+/// not grow with the number of files written. Bumping a file thus also
+/// bumps the files it shares its generation with, which only costs them
+/// cache misses.
+#[derive(Debug)]
+pub(crate) struct Generations {
+    // bumped for all files at once
+    epoch: u64,
+    slots: Vec<u64>,
+}
+
+const GENERATION_SLOTS: usize = 1024;
+
+impl Default for Generations {
+    fn default() -> Generations {
+        Generations {
+            epoch: 0,
+            slots: vec![0; GENERATION_SLOTS],
+        }
+    }
+}
+
+impl Generations {
+    pub fn get(&self, file_ids: Vec3) -> u64 {
+        self.epoch + self.slots[Self::slot(file_ids)]
+    }
+
+    pub fn bump(&mut self, file_ids: Vec3) {
+        self.slots[Self::slot(file_ids)] += 1;
+    }
+
+    pub fn bump_all(&mut self) {
+        self.epoch += 1;
+    }
+
+    fn slot(file_ids: Vec3) -> usize {
+        let mut hasher = DefaultHasher::new();
+        file_ids.hash(&mut hasher);
+        (hasher.finish() % GENERATION_SLOTS as u64) as usize
+    }
+}
+
+/// Open files, keyed by file coordinates. While in use, a file is checked
+/// out of the cache, so that other threads open their own.
+#[derive(Debug)]
+pub(crate) struct FileCache {
+    files: Lru<Vec3, File>,
+    generations: Generations,
+}
+
+impl FileCache {
+    pub fn new(capacity: usize) -> FileCache {
+        FileCache {
+            files: Lru::new(capacity),
+            generations: Generations::default(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.files.capacity()
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.files.set_capacity(capacity);
+    }
+
+    /// Removes a file from the cache, if present, and returns it together
+    /// with the current generation of the file. Files opened instead must
+    /// be opened after this call.
+    pub fn check_out(&mut self, file_ids: Vec3) -> (Option<File>, u64) {
+        (self.files.remove(&file_ids), self.generations.get(file_ids))
+    }
+
+    /// Puts a file back into the cache, unless it was invalidated since it
+    /// was checked out.
+    pub fn check_in(&mut self, file_ids: Vec3, file: File, generation: u64) {
+        if self.generations.get(file_ids) == generation {
+            self.files.insert(file_ids, file);
+        }
+    }
+
+    pub fn invalidate(&mut self, file_ids: Vec3) {
+        self.files.remove(&file_ids);
+        self.generations.bump(file_ids);
+    }
+
+    pub fn clear(&mut self) {
+        self.files.clear();
+        self.generations.bump_all();
+    }
+}
+
+/// Number of lookups in a cache which were answered from and which missed
+/// the cache, respectively, and the number of bytes held by the cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub size: usize,
+}
+
+// file coordinates, generation, and block index
+type BlockKey = (Vec3, u64, u64);
+
+/// Thread-safe cache of decompressed blocks, keyed by file, generation of
+/// the file, and block index, with a capacity in bytes. Blocks of older
+/// generations are not looked up anymore and age out of the cache.
+#[derive(Debug)]
+pub(crate) struct BlockCache {
+    blocks: Mutex<Lru<BlockKey, Arc<Vec<u8>>>>,
+    // only locked while holding the lock of `blocks`
+    generations: Mutex<Generations>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize) -> BlockCache {
+        BlockCache {
+            blocks: Mutex::new(Lru::new(capacity)),
+            generations: Mutex::new(Generations::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.blocks.lock().unwrap().capacity()
+    }
+
+    pub fn set_capacity(&self, capacity: usize) {
+        self.blocks.lock().unwrap().set_capacity(capacity);
+    }
+
+    /// Copies a cached block into `buf`. Returns whether the block was
+    /// cached.
+    pub fn read(&self, file_ids: Vec3, generation: u64, block_idx: u64, buf: &mut [u8]) -> bool {
+        let block = self
+            .blocks
+            .lock()
+            .unwrap()
+            .get(&(file_ids, generation, block_idx))
+            .cloned();
+
+        match block {
+            Some(block) => {
+                buf.copy_from_slice(&block);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+
+    /// Whether a block is cached, without touching it or the statistics.
+    pub fn contains(&self, file_ids: Vec3, generation: u64, block_idx: u64) -> bool {
+        let blocks = self.blocks.lock().unwrap();
+        blocks
+            .entries
+            .contains_key(&(file_ids, generation, block_idx))
+    }
+
+    /// Generation of the blocks of a file. Files whose blocks are to be
+    /// cached must be opened after this call.
+    pub fn generation(&self, file_ids: Vec3) -> u64 {
+        let _blocks = self.blocks.lock().unwrap();
+        self.generations.lock().unwrap().get(file_ids)
+    }
+
+    /// Inserts a block, unless its file was invalidated since `generation`
+    /// was taken.
+    pub fn insert(&self, file_ids: Vec3, generation: u64, block_idx: u64, block: &[u8]) {
+        let mut blocks = self.blocks.lock().unwrap();
+        let is_current = self.generations.lock().unwrap().get(file_ids) == generation;
+        if is_current && blocks.capacity() > 0 {
+            let size = block.len();
+            let key = (file_ids, generation, block_idx);
+            blocks.insert_sized(key, Arc::new(block.to_vec()), size);
+        }
+    }
+
+    pub fn clear(&self) {
+        let mut blocks = self.blocks.lock().unwrap();
+        blocks.clear();
+        self.generations.lock().unwrap().bump_all();
+    }
+
+    /// Makes all cached blocks of a file unreachable for files opened
+    /// after this call.
+    pub fn invalidate(&self, file_ids: Vec3) {
+        let _blocks = self.blocks.lock().unwrap();
+        self.generations.lock().unwrap().bump(file_ids);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            size: self.blocks.lock().unwrap().size(),
+        }
+    }
+}
+
+#[test]
+fn test_lru() {
+    let mut lru = Lru::new(2);
+    lru.insert(1, "a");
+    lru.insert(2, "b");
+
+    // replacing 1 makes it more recently used than 2
+    lru.insert(1, "c");
+    lru.insert(3, "d");
+    assert_eq!(lru.remove(&2), None);
+    assert_eq!(lru.remove(&1), Some("c"));
+    assert_eq!(lru.remove(&1), None);
+
+    lru.insert(4, "e");
+    lru.set_capacity(1);
+    assert_eq!(lru.remove(&3), None);
+    assert_eq!(lru.remove(&4), Some("e"));
+
+    lru.set_capacity(0);
+    lru.insert(5, "f");
+    assert_eq!(lru.remove(&5), None);
+
+    let mut lru = Lru::new(10);
+    lru.insert_sized(1, "a", 4);
+    lru.insert_sized(2, "b", 4);
+    assert_eq!(lru.get(&1), Some(&"a"));
+    lru.insert_sized(3, "c", 4);
+    assert_eq!(lru.get(&2), None);
+    assert_eq!(lru.size(), 8);
+    lru.insert_sized(4, "d", 11);
+    assert_eq!(lru.size(), 8);
+}
+
+#[test]
+fn test_generations() {
+    let mut generations = Generations::default();
+    let file_ids = Vec3::from(1u32);
+    let generation = generations.get(file_ids);
+
+    // many files are written without growing the generations
+    for x in 0..10000 {
+        generations.bump(Vec3 { x, y: 2, z: 3 });
+    }
+    assert_eq!(generations.slots.len(), GENERATION_SLOTS);
+
+    generations.bump(file_ids);
+    assert!(generations.get(file_ids) > generation);
+
+    let generation = generations.get(file_ids);
+    generations.bump_all();
+    assert!(generations.get(file_ids) > generation);
+}
+
+#[test]
+fn test_file_cache() {
+    use {BlockType, Header, VoxelType};
+
+    let header = Header::for_tests(1, 1, 1, BlockType::LZ4, VoxelType::U8);
+    let open = || File::open_or_create_buffer(Vec::new(), &header, &[0]).unwrap();
+    let file_ids = Vec3::from(1u32);
+
+    let mut cache = FileCache::new(2);
+    let (file, generation) = cache.check_out(file_ids);
+    assert!(file.is_none());
+    cache.check_in(file_ids, open(), generation);
+
+    let (file, generation) = cache.check_out(file_ids);
+    assert!(file.is_some());
+    cache.check_in(file_ids, file.unwrap(), generation);
+
+    // files invalidated while checked out are not put back
+    let (file, generation) = cache.check_out(file_ids);
+    cache.invalidate(file_ids);
+    cache.check_in(file_ids, file.unwrap(), generation);
+    assert!(cache.check_out(file_ids).0.is_none());
+
+    let (_, generation) = cache.check_out(file_ids);
+    cache.invalidate(Vec3::from(0u32));
+    cache.check_in(file_ids, open(), generation);
+    assert!(cache.check_out(file_ids).0.is_some());
+
+    let (_, generation) = cache.check_out(file_ids);
+    cache.clear();
+    cache.check_in(file_ids, open(), generation);
+    assert!(cache.check_out(file_ids).0.is_none());
+}
+
+#[test]
+fn test_block_cache() {
+    let cache = BlockCache::new(8);
+    let file_ids = Vec3::from(1u32);
+    let mut buf = [0u8; 4];
+
+    let generation = cache.generation(file_ids);
+    assert!(!cache.read(file_ids, generation, 0, &mut buf));
+    cache.insert(file_ids, generation, 0, &[1, 2, 3, 4]);
+    let other_generation = cache.generation(Vec3::from(0u32));
+    cache.insert(Vec3::from(0u32), other_generation, 0, &[5, 6, 7, 8]);
+    assert!(cache.read(file_ids, generation, 0, &mut buf));
+    assert_eq!(buf, [1, 2, 3, 4]);
+    assert_eq!(cache.stats().size, 8);
+
+    // blocks of the old generation stay until they are evicted
+    cache.invalidate(file_ids);
+    let new_generation = cache.generation(file_ids);
+    assert!(!cache.contains(file_ids, new_generation, 0));
+    assert!(cache.contains(Vec3::from(0u32), other_generation, 0));
+    assert_eq!(
+        cache.stats(),
+        CacheStats {
+            hits: 1,
+            misses: 1,
+            size: 8
+        }
+    );
+
+    // blocks read before the file was invalidated are not inserted
+    cache.insert(file_ids, generation, 1, &[1, 2, 3, 4]);
+    assert!(!cache.contains(file_ids, generation, 1));
+    cache.insert(file_ids, new_generation, 1, &[1, 2, 3, 4]);
+    assert!(cache.contains(file_ids, new_generation, 1));
+
+    // the old block is least recently used
+    cache.insert(Vec3::from(0u32), other_generation, 1, &[5, 6, 7, 8]);
+    assert!(!cache.contains(file_ids, generation, 0));
+    assert!(cache.contains(file_ids, new_generation, 1));
+}
@@ -0,0 +1,268 @@
+//! KNOSSOS cubes.
+//!
+//! A KNOSSOS dataset (at a single magnification) consists of raw cubes of
+//! 128³ voxels in Fortran order, stored as `x0000/y0000/z0000/<name>.raw`.
+
+use super::import_chunks;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use {Dataset, Error, Header, Result, Vec3};
+
+pub const CUBE_LEN_LOG2: u8 = 7;
+
+/// Creates a WKW dataset at `root` from the KNOSSOS cubes in `path`, with
+/// one WKW file per cube. The block length, block and voxel type, and voxel
+/// size are taken from `template`; the file length is chosen to match the
+/// cube length.
+///
+/// The extent of the dataset is read from the `boundary` entries of
+/// `knossos.conf`, if present, and otherwise derived from the cube
+/// directories. Returns the new dataset and the ids of all cubes within
+/// this extent which do not exist. Missing cubes are left empty.
+pub fn import(path: &Path, root: &Path, template: &Header) -> Result<(Dataset, Vec<Vec3>)> {
+    let cube_len = 1u32 << CUBE_LEN_LOG2;
+    let cube_shape = Vec3::from(cube_len);
+
+    if template.block_shape().elem_min(cube_shape) != template.block_shape() {
+        return Err(Error::invalid(format!(
+            "Block shape {:?} exceeds cube length",
+            template.block_shape()
+        )));
+    }
+    let cube_size = cube_shape.product() as usize * template.voxel_size as usize;
+
+    let shape = match read_boundary(path)? {
+        Some(shape) => shape,
+        None => find_cubes(path)? * cube_len,
+    };
+
+    let mut header = template.clone();
+    header.file_shape_log2 = Vec3::from(CUBE_LEN_LOG2 as u32) - header.block_shape_log2;
+    let dataset = Dataset::create(root, header)?;
+
+    let missing = Mutex::new(Vec::new());
+    import_chunks(&dataset, shape, cube_shape, |cube_ids| {
+        let cube_path = match find_cube(path, cube_ids)? {
+            Some(cube_path) => cube_path,
+            None => {
+                missing.lock().unwrap().push(cube_ids);
+                return Ok(None);
+            }
+        };
+
+        let buf = match super::read_file(&cube_path)? {
+            Some(buf) => buf,
+            None => return Ok(None),
+        };
+
+        if buf.len() != cube_size {
+            return Err(Error::invalid(format!(
+                "{:?} has {} bytes instead of {}",
+                cube_path,
+                buf.len(),
+                cube_size
+            )));
+        }
+
+        Ok(Some((cube_shape, buf)))
+    })?;
+
+    let mut missing = missing.into_inner().unwrap();
+    missing.sort_by_key(|ids| (ids.z, ids.y, ids.x));
+    Ok((dataset, missing))
+}
+
+fn cube_dir(path: &Path, cube_ids: Vec3) -> PathBuf {
+    path.join(format!("x{:04}", cube_ids.x))
+        .join(format!("y{:04}", cube_ids.y))
+        .join(format!("z{:04}", cube_ids.z))
+}
+
+// Path of the raw file of a cube, or `None` if the cube does not exist.
+fn find_cube(path: &Path, cube_ids: Vec3) -> Result<Option<PathBuf>> {
+    let dir = cube_dir(path, cube_ids);
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(ref err) if err.kind() == ::std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(Error::io_at("Could not list directory", &dir, err)),
+    };
+
+    let mut cube_path = None;
+    for entry in entries {
+        let entry_path = entry
+            .map_err(|err| Error::io_at("Could not list directory", &dir, err))?
+            .path();
+
+        if entry_path.extension().is_none_or(|ext| ext != "raw") {
+            continue;
+        }
+
+        if cube_path.is_some() {
+            return Err(Error::invalid(format!("Multiple raw files in {:?}", dir)));
+        }
+
+        cube_path = Some(entry_path);
+    }
+
+    Ok(cube_path)
+}
+
+// Parses the name of a cube directory, e.g. `x0012`.
+fn parse_dir_name(path: &Path, prefix: char) -> Option<u32> {
+    let name = path.file_name()?.to_str()?;
+    match name.starts_with(prefix) {
+        true => name[1..].parse().ok(),
+        false => None,
+    }
+}
+
+// Lists the subdirectories of `path` which are named after cube ids.
+fn list_dirs(path: &Path, prefix: char) -> Result<Vec<(u32, PathBuf)>> {
+    let entries =
+        fs::read_dir(path).map_err(|err| Error::io_at("Could not list directory", path, err))?;
+
+    let mut dirs = Vec::new();
+    for entry in entries {
+        let entry_path = entry
+            .map_err(|err| Error::io_at("Could not list directory", path, err))?
+            .path();
+
+        if !entry_path.is_dir() {
+            continue;
+        }
+
+        if let Some(id) = parse_dir_name(&entry_path, prefix) {
+            dirs.push((id, entry_path));
+        }
+    }
+
+    Ok(dirs)
+}
+
+// Number of cubes along each dimension, based on the cube directories.
+fn find_cubes(path: &Path) -> Result<Vec3> {
+    let mut num_cubes = Vec3::from(0u32);
+
+    for (x, x_path) in list_dirs(path, 'x')? {
+        for (y, y_path) in list_dirs(&x_path, 'y')? {
+            for (z, _) in list_dirs(&y_path, 'z')? {
+                num_cubes = num_cubes.elem_max(Vec3 { x, y, z } + 1);
+            }
+        }
+    }
+
+    Ok(num_cubes)
+}
+
+// Reads the dataset extent from `knossos.conf`, e.g. `boundary x 1024;`.
+fn read_boundary(path: &Path) -> Result<Option<Vec3>> {
+    let conf_path = path.join("knossos.conf");
+    let buf = match super::read_file(&conf_path)? {
+        Some(buf) => buf,
+        None => return Ok(None),
+    };
+
+    let mut boundary = [None; 3];
+    for line in String::from_utf8_lossy(&buf).lines() {
+        let parts: Vec<&str> = line
+            .trim()
+            .trim_end_matches(';')
+            .split_whitespace()
+            .collect();
+
+        if parts.len() != 3 || parts[0] != "boundary" {
+            continue;
+        }
+
+        let dim = match parts[1] {
+            "x" => 0,
+            "y" => 1,
+            "z" => 2,
+            _ => continue,
+        };
+
+        match parts[2].parse::<f64>() {
+            Ok(value) if value >= 0.0 => boundary[dim] = Some(value.ceil() as u32),
+            _ => {
+                return Err(Error::invalid(format!(
+                    "Invalid boundary {:?} in {:?}",
+                    parts[2], conf_path
+                )))
+            }
+        }
+    }
+
+    Ok(match boundary {
+        [Some(x), Some(y), Some(z)] => Some(Vec3 { x, y, z }),
+        _ => None,
+    })
+}
+
+#[test]
+fn test_import() {
+    use {BlockType, Mat, VoxelType};
+
+    let root = ::std::env::temp_dir().join(format!("wkwrap-knossos-{}", ::std::process::id()));
+    let _ = ::std::fs::remove_dir_all(&root);
+
+    let knossos_path = root.join("knossos");
+    let cube_numel = 128 * 128 * 128;
+
+    // cubes (0, 0, 0) and (1, 0, 1) exist, (1, 0, 0) and (0, 0, 1) are missing
+    for &(ids, offset) in &[
+        (Vec3 { x: 0, y: 0, z: 0 }, 1u16),
+        (Vec3 { x: 1, y: 0, z: 1 }, 7),
+    ] {
+        let dir = cube_dir(&knossos_path, ids);
+        ::std::fs::create_dir_all(&dir).unwrap();
+
+        let buf: Vec<u8> = (0..cube_numel)
+            .flat_map(|i| ((i % 1000) as u16 + offset).to_le_bytes())
+            .collect();
+        let name = format!("ex_mag1_x{:04}_y{:04}_z{:04}.raw", ids.x, ids.y, ids.z);
+        ::std::fs::write(dir.join(name), buf).unwrap();
+    }
+
+    let template = Header::for_tests(1, 5, 0, BlockType::LZ4, VoxelType::U16);
+
+    let (dataset, missing) = import(&knossos_path, &root.join("wkw"), &template).unwrap();
+    assert_eq!(dataset.header().file_shape_vx(), Vec3::from(128u32));
+    assert_eq!(
+        missing,
+        vec![Vec3 { x: 1, y: 0, z: 0 }, Vec3 { x: 0, y: 0, z: 1 }]
+    );
+
+    let shape = Vec3::from(2u32);
+    let mut out = vec![0u8; 16];
+    {
+        let mut mat = Mat::new(&mut out, shape, 2, VoxelType::U16, false).unwrap();
+        dataset
+            .read_mat(
+                Vec3 {
+                    x: 128,
+                    y: 0,
+                    z: 127,
+                },
+                &mut mat,
+            )
+            .unwrap();
+    }
+
+    // voxels (128, 0, 127) and (128, 0, 128) are in cubes (1, 0, 0) and (1, 0, 1)
+    assert_eq!(&out[..2], &0u16.to_le_bytes());
+    assert_eq!(&out[8..10], &7u16.to_le_bytes());
+
+    // with a knossos.conf, missing cubes are reported within its boundary
+    ::std::fs::write(
+        knossos_path.join("knossos.conf"),
+        "experiment name \"ex\";\nboundary x 300;\nboundary y 100;\nboundary z 256;\n",
+    )
+    .unwrap();
+
+    let (_, missing) = import(&knossos_path, &root.join("wkw-conf"), &template).unwrap();
+    assert_eq!(missing.len(), 4);
+    assert_eq!(missing[0], Vec3 { x: 1, y: 0, z: 0 });
+
+    ::std::fs::remove_dir_all(&root).unwrap();
+}
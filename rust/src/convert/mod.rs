@@ -0,0 +1,285 @@
+//! Conversion between WKW datasets and other volume formats.
+
+use serde_json::Value;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use {Box3, Dataset, Error, Header, Iter, Mat, Morton, Result, Vec3, VoxelType};
+
+pub mod knossos;
+pub mod n5;
+pub mod neuroglancer;
+pub mod tiff;
+pub mod zarr;
+
+// Creates a dataset at `root` with the layout and compression of `template`
+// and the given voxel type and size.
+fn create_dataset(
+    root: &Path,
+    template: &Header,
+    voxel_type: VoxelType,
+    voxel_size: usize,
+) -> Result<Dataset> {
+    if voxel_size == 0 || voxel_size > 255 || !voxel_size.is_multiple_of(voxel_type.size()) {
+        return Err(Error::invalid(format!(
+            "Invalid voxel size {} for voxel type {:?}",
+            voxel_size, voxel_type
+        )));
+    }
+
+    let mut header = template.clone();
+    header.voxel_type = voxel_type;
+    header.voxel_size = voxel_size as u8;
+    Dataset::create(root, header)
+}
+
+// Ids of all files which intersect the box from the origin to `shape`.
+fn file_ids(dataset: &Dataset, shape: Vec3) -> Vec<Vec3> {
+    let file_shape = dataset.header().file_shape_vx();
+    let num_files = (shape + file_shape - 1) / file_shape;
+
+    let mut file_ids = Vec::with_capacity(num_files.product() as usize);
+    for z in 0..num_files.z {
+        for y in 0..num_files.y {
+            for x in 0..num_files.x {
+                file_ids.push(Vec3 { x, y, z });
+            }
+        }
+    }
+
+    file_ids
+}
+
+// Ids of all chunks of shape `chunk_shape` which intersect `bbox`.
+fn chunk_ids(bbox: Box3, chunk_shape: Vec3) -> Vec<Vec3> {
+    if bbox.is_empty() {
+        return Vec::new();
+    }
+
+    let min = bbox.min() / chunk_shape;
+    let max = (bbox.max() + chunk_shape - 1) / chunk_shape;
+
+    let mut chunk_ids = Vec::new();
+    for z in min.z..max.z {
+        for y in min.y..max.y {
+            for x in min.x..max.x {
+                chunk_ids.push(Vec3 { x, y, z });
+            }
+        }
+    }
+
+    chunk_ids
+}
+
+fn chunk_box(chunk_ids: Vec3, chunk_shape: Vec3) -> Result<Box3> {
+    let min = Vec3 {
+        x: chunk_ids.x * chunk_shape.x,
+        y: chunk_ids.y * chunk_shape.y,
+        z: chunk_ids.z * chunk_shape.z,
+    };
+    Box3::new(min, min + chunk_shape)
+}
+
+// Shape of the box from the origin to the end of the last file.
+fn dataset_shape(dataset: &Dataset) -> Result<Vec3> {
+    Ok(match dataset.bounding_box(false)? {
+        Some(bbox) => bbox.max(),
+        None => Vec3::from(0u32),
+    })
+}
+
+// Reads `dataset` file by file and passes the ids and data (in Fortran
+// order) of all non-zero chunks to `f`. Within a file, chunks are visited
+// in Morton order. The chunk shape must divide the file shape.
+fn export_chunks<F>(dataset: &Dataset, chunk_shape: Vec3, f: F) -> Result<()>
+where
+    F: Fn(Vec3, &[u8]) -> Result<()> + Sync,
+{
+    let header = dataset.header();
+    let file_shape = header.file_shape_vx();
+    let voxel_size = header.voxel_size as usize;
+    let voxel_type = header.voxel_type;
+
+    if file_shape % chunk_shape != Vec3::from(0u32) {
+        return Err(Error::invalid(format!(
+            "Chunk shape {:?} does not divide file shape {:?}",
+            chunk_shape, file_shape
+        )));
+    }
+
+    let file_ids: Vec<Vec3> = dataset
+        .list_files()?
+        .into_iter()
+        .map(|(ids, _)| ids)
+        .collect();
+
+    let num_chunks = file_shape / chunk_shape;
+    let num_chunks_log2 = Vec3 {
+        x: num_chunks.x.trailing_zeros(),
+        y: num_chunks.y.trailing_zeros(),
+        z: num_chunks.z.trailing_zeros(),
+    };
+
+    dataset.for_each_file(&file_ids, |cur_file_ids| {
+        let file_box = Box3::from(file_shape) + cur_file_ids * file_shape;
+
+        let mut file_buf = vec![0u8; header.file_size()];
+        let mut file_mat = Mat::new(&mut file_buf, file_shape, voxel_size, voxel_type, false)?;
+        dataset.read_mat(file_box.min(), &mut file_mat)?;
+
+        let mut chunk_buf = vec![0u8; chunk_shape.product() as usize * voxel_size];
+        for chunk_idx in Iter::with_shape(num_chunks_log2, Box3::from(num_chunks))? {
+            let cur_chunk_ids =
+                Morton::from(chunk_idx).to_vec(num_chunks_log2) + file_box.min() / chunk_shape;
+            let cur_chunk_box = chunk_box(cur_chunk_ids, chunk_shape)?;
+
+            {
+                let mut chunk_mat =
+                    Mat::new(&mut chunk_buf, chunk_shape, voxel_size, voxel_type, false)?;
+                chunk_mat.copy_from(Vec3::from(0u32), &file_mat, cur_chunk_box - file_box.min())?;
+            }
+
+            if chunk_buf.iter().all(|&byte| byte == 0) {
+                continue;
+            }
+
+            f(cur_chunk_ids, &chunk_buf)?;
+        }
+
+        Ok(())
+    })
+}
+
+// Writes the box from the origin to `shape` into `dataset`, file by file.
+// For each chunk intersecting a file, `read_chunk` returns the shape and
+// data (in Fortran order) of the chunk, or `None` if it is missing. Chunks
+// may be smaller than `chunk_shape` at the upper end of the volume. Files
+// without any chunks are not written.
+fn import_chunks<F>(dataset: &Dataset, shape: Vec3, chunk_shape: Vec3, read_chunk: F) -> Result<()>
+where
+    F: Fn(Vec3) -> Result<Option<(Vec3, Vec<u8>)>> + Sync,
+{
+    let header = dataset.header();
+    let file_shape = header.file_shape_vx();
+    let voxel_size = header.voxel_size as usize;
+    let voxel_type = header.voxel_type;
+    let volume_box = Box3::from(shape);
+
+    dataset.for_each_file(&file_ids(dataset, shape), |cur_file_ids| {
+        let file_box = Box3::from(file_shape) + cur_file_ids * file_shape;
+        let valid_box = file_box.intersect(volume_box);
+
+        let mut file_buf = vec![0u8; header.file_size()];
+        let mut is_empty = true;
+
+        {
+            let mut file_mat = Mat::new(&mut file_buf, file_shape, voxel_size, voxel_type, false)?;
+
+            for cur_chunk_ids in chunk_ids(valid_box, chunk_shape) {
+                let (cur_chunk_shape, mut chunk_buf) = match read_chunk(cur_chunk_ids)? {
+                    Some(chunk) => chunk,
+                    None => continue,
+                };
+
+                let chunk_mat = Mat::new(
+                    &mut chunk_buf,
+                    cur_chunk_shape,
+                    voxel_size,
+                    voxel_type,
+                    false,
+                )?;
+
+                let cur_chunk_box = chunk_box(cur_chunk_ids, chunk_shape)?;
+                let cur_chunk_box =
+                    Box3::new(cur_chunk_box.min(), cur_chunk_box.min() + cur_chunk_shape)?;
+                let cur_box = cur_chunk_box.intersect(valid_box);
+                if cur_box.is_empty() {
+                    continue;
+                }
+
+                file_mat.copy_from(
+                    cur_box.min() - file_box.min(),
+                    &chunk_mat,
+                    cur_box - cur_chunk_box.min(),
+                )?;
+                is_empty = false;
+            }
+        }
+
+        if is_empty {
+            return Ok(());
+        }
+
+        let file_mat = Mat::new(&mut file_buf, file_shape, voxel_size, voxel_type, false)?;
+        dataset.write_mat(file_box.min(), &file_mat).map(|_| ())
+    })
+}
+
+// Reads a file, returning `None` if it does not exist.
+fn read_file(path: &Path) -> Result<Option<Vec<u8>>> {
+    let mut file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(Error::io_at("Could not open file", path, err)),
+    };
+
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)
+        .map_err(|err| Error::io_at("Could not read file", path, err))?;
+
+    Ok(Some(buf))
+}
+
+// Writes a file, creating its parent directories if needed.
+fn write_file(path: &Path, buf: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|err| Error::io_at("Could not create directory", parent, err))?;
+    }
+
+    fs::File::create(path)
+        .and_then(|mut file| file.write_all(buf))
+        .map_err(|err| Error::io_at("Could not write file", path, err))
+}
+
+fn read_json(path: &Path) -> Result<Value> {
+    let buf = match read_file(path)? {
+        Some(buf) => buf,
+        None => return Err(Error::invalid(format!("{:?} does not exist", path))),
+    };
+
+    serde_json::from_slice(&buf)
+        .map_err(|err| Error::invalid(format!("Invalid JSON in {:?}: {}", path, err)))
+}
+
+fn write_json(path: &Path, value: &Value) -> Result<()> {
+    let buf = serde_json::to_vec_pretty(value).unwrap();
+    write_file(path, &buf)
+}
+
+fn invalid_field(path: &Path, field: &str) -> Error {
+    Error::invalid(format!(
+        "Missing or invalid field {:?} in {:?}",
+        field, path
+    ))
+}
+
+fn get_str<'a>(value: &'a Value, path: &Path, field: &str) -> Result<&'a str> {
+    value[field]
+        .as_str()
+        .ok_or_else(|| invalid_field(path, field))
+}
+
+fn get_u32s(value: &Value, path: &Path, field: &str) -> Result<Vec<u32>> {
+    let values = value[field]
+        .as_array()
+        .ok_or_else(|| invalid_field(path, field))?;
+
+    values
+        .iter()
+        .map(|value| match value.as_u64() {
+            Some(value) if value <= u32::MAX as u64 => Ok(value as u32),
+            _ => Err(invalid_field(path, field)),
+        })
+        .collect()
+}
@@ -0,0 +1,509 @@
+//! N5 datasets in local filesystem containers.
+//!
+//! Datasets have the dimensions `x`, `y`, `z`, preceded by a channel
+//! dimension for WKW datasets with multiple channels. Chunks are stored in
+//! big-endian byte order.
+
+use super::{dataset_shape, export_chunks, get_str, get_u32s, import_chunks, invalid_field};
+use flate2::read::{MultiGzDecoder, ZlibDecoder};
+use flate2::write::GzEncoder;
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use xxhash_rust::xxh32::xxh32;
+use {flate2, lz4, Dataset, Error, Header, Result, Vec3, VoxelType};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Compression {
+    Raw,
+    /// Gzip with the given level (0 to 9).
+    Gzip(u32),
+    /// LZ4 block stream, as written by lz4-java.
+    Lz4,
+}
+
+#[derive(Debug)]
+struct Attributes {
+    shape: Vec3,
+    chunk_shape: Vec3,
+    num_channels: usize,
+    voxel_type: VoxelType,
+    compression: Compression,
+    use_zlib: bool,
+}
+
+// lz4-java block stream format
+const LZ4_MAGIC: &[u8] = b"LZ4Block";
+const LZ4_HEADER_LEN: usize = 21;
+const LZ4_BLOCK_SIZE: usize = 1 << 16;
+const LZ4_METHOD_RAW: u8 = 0x10;
+const LZ4_METHOD_LZ4: u8 = 0x20;
+const LZ4_CHECKSUM_SEED: u32 = 0x9747_b28c;
+
+/// Writes the bounding box (from the origin) of `dataset` into a new N5
+/// dataset at `path`, with one chunk per WKW block. All-zero chunks are not
+/// written. The attributes also mark `path` as N5 container root.
+pub fn export(dataset: &Dataset, path: &Path, compression: Compression) -> Result<()> {
+    let header = dataset.header();
+
+    let attrs = Attributes {
+        shape: dataset_shape(dataset)?,
+        chunk_shape: header.block_shape(),
+        num_channels: header.num_channels(),
+        voxel_type: header.voxel_type,
+        compression,
+        use_zlib: false,
+    };
+
+    let attrs_path = path.join("attributes.json");
+    if attrs_path.exists() {
+        return Err(Error::AlreadyExists(attrs_path));
+    }
+    super::write_json(&attrs_path, &attrs.to_json())?;
+
+    export_chunks(dataset, attrs.chunk_shape, |chunk_ids, buf| {
+        let encoded = attrs.encode_chunk(buf)?;
+        super::write_file(&attrs.chunk_path(path, chunk_ids), &encoded)
+    })
+}
+
+/// Creates a WKW dataset at `root` from the N5 dataset at `path`. Block and
+/// file lengths, as well as the block type, are taken from `template`; the
+/// voxel type and size from the N5 attributes. Missing chunks are zero.
+pub fn import(path: &Path, root: &Path, template: &Header) -> Result<Dataset> {
+    let attrs_path = path.join("attributes.json");
+    let attrs = Attributes::from_json(&super::read_json(&attrs_path)?, &attrs_path)?;
+
+    let voxel_size = attrs.num_channels * attrs.voxel_type.size();
+    let dataset = super::create_dataset(root, template, attrs.voxel_type, voxel_size)?;
+
+    import_chunks(&dataset, attrs.shape, attrs.chunk_shape, |chunk_ids| {
+        let chunk_path = attrs.chunk_path(path, chunk_ids);
+        match super::read_file(&chunk_path)? {
+            Some(encoded) => attrs
+                .decode_chunk(&encoded)
+                .map(Some)
+                .map_err(|err| err.in_file(&chunk_path)),
+            None => Ok(None),
+        }
+    })?;
+
+    Ok(dataset)
+}
+
+impl Attributes {
+    fn chunk_path(&self, path: &Path, chunk_ids: Vec3) -> PathBuf {
+        let mut chunk_path = path.to_owned();
+        if self.num_channels > 1 {
+            chunk_path.push("0");
+        }
+        chunk_path.push(chunk_ids.x.to_string());
+        chunk_path.push(chunk_ids.y.to_string());
+        chunk_path.push(chunk_ids.z.to_string());
+        chunk_path
+    }
+
+    fn dims(&self, xyz: Vec3) -> Vec<u32> {
+        let mut dims = Vec::with_capacity(4);
+        if self.num_channels > 1 {
+            dims.push(self.num_channels as u32);
+        }
+        dims.extend_from_slice(&[xyz.x, xyz.y, xyz.z]);
+        dims
+    }
+
+    fn to_json(&self) -> Value {
+        let compression = match self.compression {
+            Compression::Raw => json!({ "type": "raw" }),
+            Compression::Gzip(level) => json!({ "type": "gzip", "level": level }),
+            Compression::Lz4 => json!({ "type": "lz4", "blockSize": LZ4_BLOCK_SIZE }),
+        };
+
+        json!({
+            "n5": "4.0.0",
+            "dimensions": self.dims(self.shape),
+            "blockSize": self.dims(self.chunk_shape),
+            "dataType": data_type(self.voxel_type),
+            "compression": compression,
+        })
+    }
+
+    fn from_json(value: &Value, path: &Path) -> Result<Attributes> {
+        let voxel_type = parse_data_type(get_str(value, path, "dataType")?)
+            .ok_or_else(|| invalid_field(path, "dataType"))?;
+
+        // versions before 1.0.0 only had a compression type
+        let compression = &value["compression"];
+        let compression_type = match compression["type"].as_str() {
+            Some(compression_type) => compression_type,
+            None => get_str(value, path, "compressionType")?,
+        };
+
+        let compression = match compression_type {
+            "raw" => Compression::Raw,
+            "gzip" => Compression::Gzip(6),
+            "lz4" => Compression::Lz4,
+            other => {
+                return Err(Error::invalid(format!(
+                    "{:?}: N5 compression {:?} is not supported",
+                    path, other
+                )))
+            }
+        };
+        let use_zlib = value["compression"]["useZlib"].as_bool().unwrap_or(false);
+
+        let shape = get_u32s(value, path, "dimensions")?;
+        let chunks = get_u32s(value, path, "blockSize")?;
+        if shape.len() != chunks.len() || chunks.contains(&0) {
+            return Err(invalid_field(path, "blockSize"));
+        }
+
+        let (num_channels, shape, chunks) = match shape.len() {
+            3 => (1, &shape[..], &chunks[..]),
+            4 if chunks[0] == shape[0] => (shape[0] as usize, &shape[1..], &chunks[1..]),
+            _ => {
+                return Err(Error::invalid(format!(
+                    "{:?}: only 3D N5 datasets, optionally with unchunked channels, \
+                     are supported",
+                    path
+                )))
+            }
+        };
+
+        Ok(Attributes {
+            shape: Vec3 {
+                x: shape[0],
+                y: shape[1],
+                z: shape[2],
+            },
+            chunk_shape: Vec3 {
+                x: chunks[0],
+                y: chunks[1],
+                z: chunks[2],
+            },
+            num_channels,
+            voxel_type,
+            compression,
+            use_zlib,
+        })
+    }
+
+    fn encode_chunk(&self, buf: &[u8]) -> Result<Vec<u8>> {
+        let dims = self.dims(self.chunk_shape);
+
+        // default mode, followed by the chunk shape
+        let mut encoded = Vec::with_capacity(4 + 4 * dims.len() + buf.len());
+        encoded.extend_from_slice(&0u16.to_be_bytes());
+        encoded.extend_from_slice(&(dims.len() as u16).to_be_bytes());
+        for dim in dims {
+            encoded.extend_from_slice(&dim.to_be_bytes());
+        }
+
+        let mut data = buf.to_vec();
+        swap_bytes(&mut data, self.voxel_type.size());
+
+        match self.compression {
+            Compression::Raw => encoded.extend_from_slice(&data),
+            Compression::Gzip(level) => {
+                let level = flate2::Compression::new(level.min(9));
+                let mut encoder = GzEncoder::new(encoded, level);
+                encoder
+                    .write_all(&data)
+                    .map_err(|_| Error::Compression("Error in gzip"))?;
+                encoded = encoder
+                    .finish()
+                    .map_err(|_| Error::Compression("Error in gzip"))?;
+            }
+            Compression::Lz4 => lz4_block_encode(&data, &mut encoded)?,
+        }
+
+        Ok(encoded)
+    }
+
+    fn decode_chunk(&self, encoded: &[u8]) -> Result<(Vec3, Vec<u8>)> {
+        let invalid = |msg: &str| Error::Decompression(format!("Invalid N5 chunk: {}", msg));
+        let read_u16 = |pos: usize| -> Result<u16> {
+            match encoded.get(pos..(pos + 2)) {
+                Some(bytes) => Ok(u16::from_be_bytes([bytes[0], bytes[1]])),
+                None => Err(invalid("truncated header")),
+            }
+        };
+        let read_u32 = |pos: usize| -> Result<u32> {
+            match encoded.get(pos..(pos + 4)) {
+                Some(bytes) => Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+                None => Err(invalid("truncated header")),
+            }
+        };
+
+        let mode = read_u16(0)?;
+        let num_dims = read_u16(2)? as usize;
+        let dims = (0..num_dims)
+            .map(|idx| read_u32(4 + 4 * idx))
+            .collect::<Result<Vec<u32>>>()?;
+
+        // the varlength mode stores the number of elements after the shape
+        let mut pos = 4 + 4 * num_dims;
+        match mode {
+            0 => (),
+            1 => pos += 4,
+            _ => return Err(invalid("unsupported mode")),
+        }
+
+        let (num_channels, xyz) = match dims.len() {
+            3 => (1, &dims[..]),
+            4 => (dims[0] as usize, &dims[1..]),
+            _ => return Err(invalid("wrong number of dimensions")),
+        };
+
+        if num_channels != self.num_channels {
+            return Err(invalid("wrong number of channels"));
+        }
+
+        let shape = Vec3 {
+            x: xyz[0],
+            y: xyz[1],
+            z: xyz[2],
+        };
+
+        let data_len = shape.product() as usize * num_channels * self.voxel_type.size();
+        let encoded = &encoded[pos.min(encoded.len())..];
+
+        let mut data = match self.compression {
+            Compression::Raw => encoded.to_vec(),
+            Compression::Gzip(_) => {
+                let mut data = Vec::with_capacity(data_len);
+                let result = match self.use_zlib {
+                    true => ZlibDecoder::new(encoded).read_to_end(&mut data),
+                    false => MultiGzDecoder::new(encoded).read_to_end(&mut data),
+                };
+                result.map_err(|err| Error::Decompression(format!("Error in gzip: {}", err)))?;
+                data
+            }
+            Compression::Lz4 => lz4_block_decode(encoded, data_len)?,
+        };
+
+        if data.len() < data_len {
+            return Err(invalid("not enough data"));
+        }
+
+        data.truncate(data_len);
+        swap_bytes(&mut data, self.voxel_type.size());
+        Ok((shape, data))
+    }
+}
+
+// Converts between little- and big-endian elements.
+fn swap_bytes(buf: &mut [u8], elem_size: usize) {
+    if elem_size > 1 {
+        for elem in buf.chunks_mut(elem_size) {
+            elem.reverse();
+        }
+    }
+}
+
+fn lz4_checksum(buf: &[u8]) -> u32 {
+    xxh32(buf, LZ4_CHECKSUM_SEED) & 0x0fff_ffff
+}
+
+fn lz4_block_header(
+    method: u8,
+    compressed_len: usize,
+    original_len: usize,
+    checksum: u32,
+) -> Vec<u8> {
+    // compression level encodes the block size as 2^(10 + level)
+    let level = (LZ4_BLOCK_SIZE.trailing_zeros() - 10) as u8;
+
+    let mut header = Vec::with_capacity(LZ4_HEADER_LEN);
+    header.extend_from_slice(LZ4_MAGIC);
+    header.push(method | level);
+    header.extend_from_slice(&(compressed_len as u32).to_le_bytes());
+    header.extend_from_slice(&(original_len as u32).to_le_bytes());
+    header.extend_from_slice(&checksum.to_le_bytes());
+    header
+}
+
+fn lz4_block_encode(data: &[u8], encoded: &mut Vec<u8>) -> Result<()> {
+    let mut buf = vec![0u8; lz4::compress_bound(LZ4_BLOCK_SIZE)];
+
+    for block in data.chunks(LZ4_BLOCK_SIZE) {
+        let checksum = lz4_checksum(block);
+        let len = lz4::compress(block, &mut buf)?;
+
+        // incompressible blocks are stored as is
+        match len < block.len() {
+            true => {
+                encoded.extend(lz4_block_header(LZ4_METHOD_LZ4, len, block.len(), checksum));
+                encoded.extend_from_slice(&buf[..len]);
+            }
+            false => {
+                encoded.extend(lz4_block_header(
+                    LZ4_METHOD_RAW,
+                    block.len(),
+                    block.len(),
+                    checksum,
+                ));
+                encoded.extend_from_slice(block);
+            }
+        }
+    }
+
+    // end of stream
+    encoded.extend(lz4_block_header(LZ4_METHOD_RAW, 0, 0, 0));
+    Ok(())
+}
+
+fn lz4_block_decode(mut encoded: &[u8], capacity: usize) -> Result<Vec<u8>> {
+    let invalid = |msg: &str| Error::Decompression(format!("Invalid LZ4 block stream: {}", msg));
+    let read_u32 = |bytes: &[u8]| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+
+    let mut data = Vec::with_capacity(capacity);
+
+    while !encoded.is_empty() {
+        if encoded.len() < LZ4_HEADER_LEN || &encoded[..8] != LZ4_MAGIC {
+            return Err(invalid("bad block header"));
+        }
+
+        let method = encoded[8] & 0xf0;
+        let compressed_len = read_u32(&encoded[9..13]) as usize;
+        let original_len = read_u32(&encoded[13..17]) as usize;
+        let checksum = read_u32(&encoded[17..21]);
+        encoded = &encoded[LZ4_HEADER_LEN..];
+
+        if original_len == 0 {
+            break;
+        }
+
+        let block = match encoded.get(..compressed_len) {
+            Some(block) => block,
+            None => return Err(invalid("truncated block")),
+        };
+        encoded = &encoded[compressed_len..];
+
+        let start = data.len();
+        data.resize(start + original_len, 0);
+
+        let len = match method {
+            LZ4_METHOD_RAW if compressed_len == original_len => {
+                data[start..].copy_from_slice(block);
+                original_len
+            }
+            LZ4_METHOD_LZ4 => lz4::decompress_safe(block, &mut data[start..])?,
+            _ => return Err(invalid("unsupported block method")),
+        };
+
+        if len != original_len {
+            return Err(invalid("unexpected block length"));
+        }
+
+        if lz4_checksum(&data[start..]) != checksum {
+            return Err(invalid("checksum mismatch"));
+        }
+    }
+
+    Ok(data)
+}
+
+fn data_type(voxel_type: VoxelType) -> &'static str {
+    match voxel_type {
+        VoxelType::U8 => "uint8",
+        VoxelType::U16 => "uint16",
+        VoxelType::U32 => "uint32",
+        VoxelType::U64 => "uint64",
+        VoxelType::I8 => "int8",
+        VoxelType::I16 => "int16",
+        VoxelType::I32 => "int32",
+        VoxelType::I64 => "int64",
+        VoxelType::F32 => "float32",
+        VoxelType::F64 => "float64",
+    }
+}
+
+fn parse_data_type(name: &str) -> Option<VoxelType> {
+    [
+        VoxelType::U8,
+        VoxelType::U16,
+        VoxelType::U32,
+        VoxelType::U64,
+        VoxelType::I8,
+        VoxelType::I16,
+        VoxelType::I32,
+        VoxelType::I64,
+        VoxelType::F32,
+        VoxelType::F64,
+    ]
+    .iter()
+    .cloned()
+    .find(|&voxel_type| data_type(voxel_type) == name)
+}
+
+#[test]
+fn test_roundtrip() {
+    use {BlockType, Mat};
+
+    let root = ::std::env::temp_dir().join(format!("wkwrap-n5-{}", ::std::process::id()));
+    let _ = ::std::fs::remove_dir_all(&root);
+
+    let header = Header::for_tests(1, 2, 1, BlockType::LZ4HC, VoxelType::I32);
+    let src = Dataset::create(&root.join("src"), header.clone()).unwrap();
+
+    let shape = Vec3 { x: 16, y: 8, z: 8 };
+    let values: Vec<i32> = (0..shape.product() as i32)
+        .map(|i| (i % 97) * 1000 - 40_000)
+        .collect();
+    let mut data: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+    let mat = Mat::new(&mut data, shape, 4, VoxelType::I32, false).unwrap();
+    src.write_mat(Vec3::from(0u32), &mat).unwrap();
+
+    for &compression in &[Compression::Raw, Compression::Gzip(6), Compression::Lz4] {
+        let name = format!("{:?}", compression);
+        let n5_path = root.join(format!("{}.n5", name));
+        export(&src, &n5_path, compression).unwrap();
+
+        // elements are big-endian
+        if compression == Compression::Raw {
+            let chunk = ::std::fs::read(n5_path.join("0").join("0").join("0")).unwrap();
+            assert_eq!(chunk.len(), 16 + 64 * 4);
+            assert_eq!(&chunk[16..20], &values[0].to_be_bytes());
+        }
+
+        let dst = import(&n5_path, &root.join(name), &header).unwrap();
+        assert_eq!(dst.header().voxel_type, VoxelType::I32);
+
+        let mut out = vec![0u8; data.len()];
+        {
+            let mut out_mat = Mat::new(&mut out, shape, 4, VoxelType::I32, false).unwrap();
+            dst.read_mat(Vec3::from(0u32), &mut out_mat).unwrap();
+        }
+        assert!(out == data);
+    }
+
+    ::std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_truncated_edge_chunk() {
+    let attrs = Attributes {
+        shape: Vec3 { x: 6, y: 4, z: 4 },
+        chunk_shape: Vec3::from(4u32),
+        num_channels: 1,
+        voxel_type: VoxelType::U16,
+        compression: Compression::Lz4,
+        use_zlib: false,
+    };
+
+    // chunks at the upper end are cropped to the dataset
+    let mut encoded = Vec::new();
+    encoded.extend_from_slice(&0u16.to_be_bytes());
+    encoded.extend_from_slice(&3u16.to_be_bytes());
+    for &dim in &[2u32, 4, 4] {
+        encoded.extend_from_slice(&dim.to_be_bytes());
+    }
+
+    let data: Vec<u8> = (0..32u16).flat_map(|v| v.to_be_bytes()).collect();
+    lz4_block_encode(&data, &mut encoded).unwrap();
+
+    let (shape, decoded) = attrs.decode_chunk(&encoded).unwrap();
+    assert_eq!(shape, Vec3 { x: 2, y: 4, z: 4 });
+    assert_eq!(&decoded[2..4], &1u16.to_le_bytes());
+}
@@ -0,0 +1,393 @@
+//! Neuroglancer precomputed volumes.
+//!
+//! Each magnification becomes a scale with one chunk per WKW block. Chunks
+//! are stored as `<scale>/<x0>-<x1>_<y0>-<y1>_<z0>-<z1>`, either as raw
+//! little-endian data in `[x, y, z, channel]` Fortran order, or in the
+//! compressed segmentation format.
+
+use super::{dataset_shape, export_chunks};
+use pyramid::mag_name;
+use std::path::Path;
+use {Dataset, Error, Result, Vec3, VoxelType};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Encoding {
+    Raw,
+    /// Compressed segmentation with 8×8×8 blocks. Only supported for
+    /// `U32` and `U64` voxels.
+    CompressedSegmentation,
+}
+
+const SEGMENTATION_BLOCK_LEN: u32 = 8;
+
+/// Writes the magnifications `scales` of a dataset into a new precomputed
+/// volume at `path`. Each entry is a magnification (as passed to
+/// `pyramid::build`) and the dataset holding it, starting with `1-1-1`.
+/// `resolution` is the voxel size (in nm) at magnification `1-1-1`.
+///
+/// Volumes with compressed segmentation chunks are marked as segmentation
+/// layers, all others as image layers. All-zero chunks are not written.
+pub fn export(
+    scales: &[(Vec3, &Dataset)],
+    path: &Path,
+    resolution: [f64; 3],
+    encoding: Encoding,
+) -> Result<()> {
+    let header = match scales.first() {
+        Some(&(_, dataset)) => dataset.header(),
+        None => return Err(Error::invalid("No scales given")),
+    };
+
+    let voxel_type = header.voxel_type;
+    let num_channels = header.num_channels();
+
+    for &(_, dataset) in scales {
+        let other = dataset.header();
+        if other.voxel_type != voxel_type || other.voxel_size != header.voxel_size {
+            return Err(Error::invalid(
+                "All scales must have the same voxel type and size",
+            ));
+        }
+    }
+
+    let (data_type, layer_type) = match (voxel_type, encoding) {
+        (VoxelType::U32, Encoding::CompressedSegmentation) => ("uint32", "segmentation"),
+        (VoxelType::U64, Encoding::CompressedSegmentation) => ("uint64", "segmentation"),
+        (_, Encoding::CompressedSegmentation) => {
+            return Err(Error::invalid(format!(
+                "Compressed segmentation is not supported for {:?}",
+                voxel_type
+            )))
+        }
+        (_, Encoding::Raw) => match data_type(voxel_type) {
+            Some(data_type) => (data_type, "image"),
+            None => {
+                return Err(Error::invalid(format!(
+                    "Neuroglancer does not support {:?}",
+                    voxel_type
+                )))
+            }
+        },
+    };
+
+    let mut scales_json = Vec::with_capacity(scales.len());
+    for &(mag, dataset) in scales {
+        let size = dataset_shape(dataset)?;
+        let chunk_shape = dataset.header().block_shape();
+
+        let mut scale = json!({
+            "key": mag_name(mag),
+            "size": [size.x, size.y, size.z],
+            "resolution": [
+                resolution[0] * mag.x as f64,
+                resolution[1] * mag.y as f64,
+                resolution[2] * mag.z as f64,
+            ],
+            "voxel_offset": [0, 0, 0],
+            "chunk_sizes": [[chunk_shape.x, chunk_shape.y, chunk_shape.z]],
+            "encoding": match encoding {
+                Encoding::Raw => "raw",
+                Encoding::CompressedSegmentation => "compressed_segmentation",
+            },
+        });
+
+        if encoding == Encoding::CompressedSegmentation {
+            scale["compressed_segmentation_block_size"] = json!([
+                SEGMENTATION_BLOCK_LEN,
+                SEGMENTATION_BLOCK_LEN,
+                SEGMENTATION_BLOCK_LEN
+            ]);
+        }
+
+        scales_json.push(scale);
+    }
+
+    let info_path = path.join("info");
+    if info_path.exists() {
+        return Err(Error::AlreadyExists(info_path));
+    }
+
+    super::write_json(
+        &info_path,
+        &json!({
+            "@type": "neuroglancer_multiscale_volume",
+            "type": layer_type,
+            "data_type": data_type,
+            "num_channels": num_channels,
+            "scales": scales_json,
+        }),
+    )?;
+
+    let type_size = voxel_type.size();
+    for &(mag, dataset) in scales {
+        let scale_path = path.join(mag_name(mag));
+        let chunk_shape = dataset.header().block_shape();
+
+        export_chunks(dataset, chunk_shape, |chunk_ids, buf| {
+            let min = chunk_ids * chunk_shape;
+            let max = min + chunk_shape;
+            let name = format!(
+                "{}-{}_{}-{}_{}-{}",
+                min.x, max.x, min.y, max.y, min.z, max.z
+            );
+
+            let encoded = match encoding {
+                Encoding::Raw => split_channels(buf, num_channels, type_size),
+                Encoding::CompressedSegmentation => {
+                    encode_segmentation(buf, chunk_shape, num_channels, type_size)?
+                }
+            };
+
+            super::write_file(&scale_path.join(name), &encoded)
+        })?;
+    }
+
+    Ok(())
+}
+
+fn data_type(voxel_type: VoxelType) -> Option<&'static str> {
+    match voxel_type {
+        VoxelType::U8 => Some("uint8"),
+        VoxelType::U16 => Some("uint16"),
+        VoxelType::U32 => Some("uint32"),
+        VoxelType::U64 => Some("uint64"),
+        VoxelType::I8 => Some("int8"),
+        VoxelType::I16 => Some("int16"),
+        VoxelType::I32 => Some("int32"),
+        VoxelType::F32 => Some("float32"),
+        VoxelType::I64 | VoxelType::F64 => None,
+    }
+}
+
+// Converts interleaved channels (as in WKW) into one plane per channel.
+fn split_channels(buf: &[u8], num_channels: usize, type_size: usize) -> Vec<u8> {
+    if num_channels == 1 {
+        return buf.to_vec();
+    }
+
+    let voxel_size = num_channels * type_size;
+    let mut out = Vec::with_capacity(buf.len());
+    for channel in 0..num_channels {
+        let off = channel * type_size;
+        for voxel in buf.chunks(voxel_size) {
+            out.extend_from_slice(&voxel[off..(off + type_size)]);
+        }
+    }
+
+    out
+}
+
+// Encodes a chunk in the compressed segmentation format. The chunk starts
+// with the offsets (in 32-bit words) of the channels. Each channel consists
+// of a header with two words per block (lookup table offset and number of
+// bits; offset of the encoded values), followed by the lookup tables and
+// bit-packed indices of the blocks.
+fn encode_segmentation(
+    buf: &[u8],
+    shape: Vec3,
+    num_channels: usize,
+    type_size: usize,
+) -> Result<Vec<u8>> {
+    let voxel_size = num_channels * type_size;
+    let mut words: Vec<u32> = vec![0; num_channels];
+
+    for channel in 0..num_channels {
+        let off = channel * type_size;
+        let values: Vec<u64> = buf
+            .chunks(voxel_size)
+            .map(|voxel| {
+                let mut bytes = [0u8; 8];
+                bytes[..type_size].copy_from_slice(&voxel[off..(off + type_size)]);
+                u64::from_le_bytes(bytes)
+            })
+            .collect();
+
+        words[channel] = words.len() as u32;
+        encode_channel(&values, shape, type_size == 8, &mut words)?;
+    }
+
+    Ok(words.iter().flat_map(|word| word.to_le_bytes()).collect())
+}
+
+fn encode_channel(values: &[u64], shape: Vec3, wide: bool, words: &mut Vec<u32>) -> Result<()> {
+    let block_len = SEGMENTATION_BLOCK_LEN;
+    let grid = (shape + block_len - 1) / block_len;
+    let num_blocks = grid.product() as usize;
+    let block_numel = (block_len * block_len * block_len) as usize;
+
+    let base = words.len();
+    words.resize(base + 2 * num_blocks, 0);
+
+    let mut block_values = vec![0u64; block_numel];
+    for block_idx in 0..num_blocks {
+        let block = Vec3 {
+            x: block_idx as u32 % grid.x,
+            y: (block_idx as u32 / grid.x) % grid.y,
+            z: block_idx as u32 / (grid.x * grid.y),
+        };
+        let min = block * block_len;
+        let max = (min + block_len).elem_min(shape);
+
+        // voxels outside the chunk use the first table entry
+        let mut table = Vec::new();
+        for (i, value) in block_values.iter_mut().enumerate() {
+            let i = i as u32;
+            let pos = min
+                + Vec3 {
+                    x: i % block_len,
+                    y: (i / block_len) % block_len,
+                    z: i / (block_len * block_len),
+                };
+
+            *value = match pos.x < max.x && pos.y < max.y && pos.z < max.z {
+                true => values[(pos.x + shape.x * (pos.y + shape.y * pos.z)) as usize],
+                false => values[(min.x + shape.x * (min.y + shape.y * min.z)) as usize],
+            };
+            table.push(*value);
+        }
+
+        table.sort_unstable();
+        table.dedup();
+
+        let num_bits = match table.len() {
+            1 => 0,
+            n => [1, 2, 4, 8, 16, 32]
+                .iter()
+                .cloned()
+                .find(|&bits| n <= 1 << bits)
+                .unwrap(),
+        };
+
+        let table_offset = words.len() - base;
+        if table_offset >= 1 << 24 {
+            return Err(Error::invalid(
+                "Chunk too large for compressed segmentation",
+            ));
+        }
+
+        for &value in &table {
+            words.push(value as u32);
+            if wide {
+                words.push((value >> 32) as u32);
+            }
+        }
+
+        let values_offset = words.len() - base;
+        words.resize(words.len() + (block_numel * num_bits).div_ceil(32), 0);
+
+        if num_bits > 0 {
+            for (i, value) in block_values.iter().enumerate() {
+                let idx = table.binary_search(value).unwrap() as u32;
+                let bit = i * num_bits;
+                words[base + values_offset + bit / 32] |= idx << (bit % 32);
+            }
+        }
+
+        words[base + 2 * block_idx] = table_offset as u32 | (num_bits as u32) << 24;
+        words[base + 2 * block_idx + 1] = values_offset as u32;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_export() {
+    use pyramid::{self, Interpolation};
+    use {BlockType, Header, Mat};
+
+    let root = ::std::env::temp_dir().join(format!("wkwrap-neuroglancer-{}", ::std::process::id()));
+    let _ = ::std::fs::remove_dir_all(&root);
+
+    let header = Header::for_tests(1, 3, 1, BlockType::LZ4, VoxelType::U32);
+    let src = Dataset::create(&root.join("wkw").join("1"), header).unwrap();
+
+    let shape = Vec3 {
+        x: 32,
+        y: 16,
+        z: 16,
+    };
+    let segment = |x: u32, y: u32, z: u32| 1 + x / 5 + y / 7 * 10 + z / 3 * 100;
+    let mut values = Vec::new();
+    for z in 0..shape.z {
+        for y in 0..shape.y {
+            for x in 0..shape.x {
+                values.push(segment(x, y, z));
+            }
+        }
+    }
+
+    let mut data: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+    let mat = Mat::new(&mut data, shape, 4, VoxelType::U32, false).unwrap();
+    src.write_mat(Vec3::from(0u32), &mat).unwrap();
+
+    let mag = Vec3 { x: 2, y: 2, z: 1 };
+    let mags = pyramid::build(&src, &root.join("wkw"), &[mag], Interpolation::Mode).unwrap();
+    let scales = [(Vec3::from(1u32), &src), (mag, &mags[0])];
+
+    let cseg_path = root.join("cseg");
+    let resolution = [11.24, 11.24, 28.0];
+    export(
+        &scales,
+        &cseg_path,
+        resolution,
+        Encoding::CompressedSegmentation,
+    )
+    .unwrap();
+
+    let info = super::read_json(&cseg_path.join("info")).unwrap();
+    assert_eq!(info["type"], "segmentation");
+    assert_eq!(info["data_type"], "uint32");
+    assert_eq!(info["scales"][1]["key"], "2-2-1");
+    assert_eq!(info["scales"][1]["size"], json!([16, 16, 16]));
+    assert_eq!(info["scales"][1]["resolution"], json!([22.48, 22.48, 28.0]));
+
+    // decodes voxel `pos` of the first channel
+    let decode = |words: &[u32], chunk_shape: Vec3, pos: Vec3| {
+        let base = words[0] as usize;
+        let grid = chunk_shape / 8;
+        let block = pos / 8;
+        let block_idx = (block.x + grid.x * (block.y + grid.y * block.z)) as usize;
+
+        let table_offset = (words[base + 2 * block_idx] & 0xffffff) as usize;
+        let num_bits = (words[base + 2 * block_idx] >> 24) as usize;
+        let values_offset = words[base + 2 * block_idx + 1] as usize;
+
+        let local = pos % 8;
+        let bit = (local.x + 8 * (local.y + 8 * local.z)) as usize * num_bits;
+        let idx = match num_bits {
+            0 => 0,
+            _ => (words[base + values_offset + bit / 32] >> (bit % 32)) & ((1 << num_bits) - 1),
+        };
+        words[base + table_offset + idx as usize]
+    };
+
+    let chunk_shape = Vec3::from(8u32);
+    for &(x, y, z) in &[(0, 0, 0), (8, 8, 8), (24, 0, 8)] {
+        let name = format!("{}-{}_{}-{}_{}-{}", x, x + 8, y, y + 8, z, z + 8);
+        let buf = ::std::fs::read(cseg_path.join("1").join(name)).unwrap();
+        let words: Vec<u32> = buf
+            .chunks(4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect();
+        assert_eq!(words[0], 1);
+
+        for i in 0..chunk_shape.product() as u32 {
+            let pos = Vec3 {
+                x: i % 8,
+                y: (i / 8) % 8,
+                z: i / 64,
+            };
+            let value = decode(&words, chunk_shape, pos);
+            assert_eq!(value, segment(x + pos.x, y + pos.y, z + pos.z));
+        }
+    }
+
+    let raw_path = root.join("raw");
+    export(&scales[..1], &raw_path, resolution, Encoding::Raw).unwrap();
+    let chunk = ::std::fs::read(raw_path.join("1").join("8-16_0-8_0-8")).unwrap();
+    assert_eq!(chunk.len(), 8 * 8 * 8 * 4);
+    assert_eq!(&chunk[..4], &segment(8, 0, 0).to_le_bytes());
+
+    assert!(export(&scales, &raw_path, resolution, Encoding::Raw).is_err());
+    ::std::fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,365 @@
+//! Stacks of 2D TIFF images, one per z-slice.
+//!
+//! Grayscale, gray-alpha, RGB, and RGBA images become datasets with one to
+//! four channels.
+
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::{fs, mem};
+use tiff::decoder::{Decoder, DecodingResult, Limits};
+use tiff::encoder::{colortype, TiffEncoder};
+use tiff::{ColorType, TiffError};
+use {Box3, Dataset, Error, Header, Mat, Result, Vec3, VoxelType};
+
+struct Slice {
+    width: u32,
+    height: u32,
+    voxel_type: VoxelType,
+    num_channels: usize,
+    data: Vec<u8>,
+}
+
+/// Creates a WKW dataset at `root` from the TIFF files (`.tif`, `.tiff`) in
+/// `dir`. The files are taken as consecutive z-slices starting at z = 0, in
+/// order of their names, and all must have the same size and pixel type.
+/// Block and file lengths, as well as the block type, are taken from
+/// `template`. Slices are buffered into slabs as thick as a file.
+pub fn import(dir: &Path, root: &Path, template: &Header) -> Result<Dataset> {
+    let paths = list_slices(dir)?;
+    let mut next_slice = match paths.first() {
+        Some(path) => Some(read_slice(path)?),
+        None => return Err(Error::invalid(format!("No TIFF files in {:?}", dir))),
+    };
+
+    let (width, height, voxel_type, num_channels, slice_size) = {
+        let first = next_slice.as_ref().unwrap();
+        let (w, h) = (first.width, first.height);
+        (w, h, first.voxel_type, first.num_channels, first.data.len())
+    };
+
+    let voxel_size = num_channels * voxel_type.size();
+    let dataset = super::create_dataset(root, template, voxel_type, voxel_size)?;
+
+    let slab_depth = dataset.header().file_shape_vx().z as usize;
+    let mut slab = vec![0u8; slab_depth * slice_size];
+    let mut slab_z = 0;
+    let mut num_slices = 0;
+
+    for (z, path) in paths.iter().enumerate() {
+        let slice = match next_slice.take() {
+            Some(slice) => slice,
+            None => read_slice(path)?,
+        };
+
+        if slice.width != width
+            || slice.height != height
+            || slice.voxel_type != voxel_type
+            || slice.num_channels != num_channels
+        {
+            return Err(Error::invalid(format!(
+                "TIFF file {:?} does not match size or pixel type of {:?}",
+                path, paths[0]
+            )));
+        }
+
+        let offset = num_slices * slice_size;
+        slab[offset..(offset + slice_size)].copy_from_slice(&slice.data);
+        num_slices += 1;
+
+        if num_slices == slab_depth || z + 1 == paths.len() {
+            let shape = Vec3 {
+                x: width,
+                y: height,
+                z: num_slices as u32,
+            };
+            let mat = Mat::new(
+                &mut slab[..(num_slices * slice_size)],
+                shape,
+                voxel_size,
+                voxel_type,
+                false,
+            )?;
+            dataset.write_mat(
+                Vec3 {
+                    x: 0,
+                    y: 0,
+                    z: slab_z,
+                },
+                &mat,
+            )?;
+
+            slab_z += num_slices as u32;
+            num_slices = 0;
+        }
+    }
+
+    Ok(dataset)
+}
+
+/// Writes `bbox` of `dataset` into `dir` as one TIFF file per z-slice,
+/// named after the zero-padded z coordinate (e.g. `00042.tif`).
+pub fn export(dataset: &Dataset, bbox: Box3, dir: &Path) -> Result<()> {
+    let header = dataset.header();
+    let voxel_size = header.voxel_size as usize;
+    let voxel_type = header.voxel_type;
+    let num_channels = header.num_channels();
+
+    // fail before reading any data
+    check_color_type(voxel_type, num_channels)?;
+
+    fs::create_dir_all(dir).map_err(|err| Error::io_at("Could not create directory", dir, err))?;
+
+    let width = bbox.width();
+    let slice_size = (width.x as usize) * (width.y as usize) * voxel_size;
+    let file_len_vx = header.file_shape_vx().z;
+
+    // slabs are aligned to files
+    let mut z = bbox.min().z;
+    while z < bbox.max().z {
+        let slab_end = ((z / file_len_vx) + 1) * file_len_vx;
+        let depth = slab_end.min(bbox.max().z) - z;
+
+        let shape = Vec3 {
+            x: width.x,
+            y: width.y,
+            z: depth,
+        };
+        let mut slab = vec![0u8; depth as usize * slice_size];
+        {
+            let mut mat = Mat::new(&mut slab, shape, voxel_size, voxel_type, false)?;
+            dataset.read_mat(
+                Vec3 {
+                    x: bbox.min().x,
+                    y: bbox.min().y,
+                    z,
+                },
+                &mut mat,
+            )?;
+        }
+
+        for (idx, slice) in slab.chunks(slice_size).enumerate() {
+            let path = dir.join(format!("{:05}.tif", z as usize + idx));
+            write_slice(&path, width.x, width.y, voxel_type, num_channels, slice)?;
+        }
+
+        z += depth;
+    }
+
+    Ok(())
+}
+
+fn list_slices(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries =
+        fs::read_dir(dir).map_err(|err| Error::io_at("Could not read directory", dir, err))?;
+
+    let mut paths = Vec::new();
+    for entry in entries {
+        let path = entry
+            .map_err(|err| Error::io_at("Could not read directory", dir, err))?
+            .path();
+
+        let is_tiff = match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) => ext.eq_ignore_ascii_case("tif") || ext.eq_ignore_ascii_case("tiff"),
+            None => false,
+        };
+
+        if is_tiff {
+            paths.push(path);
+        }
+    }
+
+    paths.sort();
+    Ok(paths)
+}
+
+fn tiff_error(context: &'static str, path: &Path, err: TiffError) -> Error {
+    match err {
+        TiffError::IoError(err) => Error::io_at(context, path, err),
+        err => Error::invalid(format!("{} {:?}: {}", context, path, err)),
+    }
+}
+
+macro_rules! le_bytes {
+    ($values:expr) => {
+        $values
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect()
+    };
+}
+
+fn read_slice(path: &Path) -> Result<Slice> {
+    let context = "Could not read TIFF file";
+    let file = fs::File::open(path).map_err(|err| Error::io_at(context, path, err))?;
+
+    let mut decoder = Decoder::new(BufReader::new(file))
+        .map_err(|err| tiff_error(context, path, err))?
+        .with_limits(Limits::unlimited());
+
+    let (width, height) = decoder
+        .dimensions()
+        .map_err(|err| tiff_error(context, path, err))?;
+
+    let num_channels = match decoder
+        .colortype()
+        .map_err(|err| tiff_error(context, path, err))?
+    {
+        ColorType::Gray(_) => 1,
+        ColorType::GrayA(_) => 2,
+        ColorType::RGB(_) => 3,
+        ColorType::RGBA(_) => 4,
+        other => {
+            return Err(Error::invalid(format!(
+                "TIFF file {:?} has unsupported color type {:?}",
+                path, other
+            )))
+        }
+    };
+
+    let (voxel_type, data) = match decoder
+        .read_image()
+        .map_err(|err| tiff_error(context, path, err))?
+    {
+        DecodingResult::U8(values) => (VoxelType::U8, values),
+        DecodingResult::U16(values) => (VoxelType::U16, le_bytes!(values)),
+        DecodingResult::U32(values) => (VoxelType::U32, le_bytes!(values)),
+        DecodingResult::U64(values) => (VoxelType::U64, le_bytes!(values)),
+        DecodingResult::I8(values) => (VoxelType::I8, le_bytes!(values)),
+        DecodingResult::I16(values) => (VoxelType::I16, le_bytes!(values)),
+        DecodingResult::I32(values) => (VoxelType::I32, le_bytes!(values)),
+        DecodingResult::I64(values) => (VoxelType::I64, le_bytes!(values)),
+        DecodingResult::F32(values) => (VoxelType::F32, le_bytes!(values)),
+        DecodingResult::F64(values) => (VoxelType::F64, le_bytes!(values)),
+    };
+
+    let expected_len = width as usize * height as usize * num_channels * voxel_type.size();
+    if data.len() != expected_len {
+        return Err(Error::invalid(format!(
+            "TIFF file {:?} has {} bytes of pixel data, expected {}",
+            path,
+            data.len(),
+            expected_len
+        )));
+    }
+
+    Ok(Slice {
+        width,
+        height,
+        voxel_type,
+        num_channels,
+        data,
+    })
+}
+
+fn check_color_type(voxel_type: VoxelType, num_channels: usize) -> Result<()> {
+    let is_supported = match (voxel_type, num_channels) {
+        (_, 1) => true,
+        (VoxelType::I8, _) | (VoxelType::I16, _) => false,
+        (VoxelType::I32, _) | (VoxelType::I64, _) => false,
+        (_, num_channels) => num_channels == 3 || num_channels == 4,
+    };
+
+    match is_supported {
+        true => Ok(()),
+        false => Err(Error::invalid(format!(
+            "TIFF export of {} channels of {:?} is not supported",
+            num_channels, voxel_type
+        ))),
+    }
+}
+
+macro_rules! write_image {
+    ($encoder:expr, $color_type:ty, $elem:ty, $width:expr, $height:expr, $data:expr) => {{
+        const SIZE: usize = mem::size_of::<$elem>();
+        let values: Vec<$elem> = $data
+            .chunks(SIZE)
+            .map(|bytes| {
+                let mut buf = [0u8; SIZE];
+                buf.copy_from_slice(bytes);
+                <$elem>::from_le_bytes(buf)
+            })
+            .collect();
+
+        $encoder.write_image::<$color_type>($width, $height, &values)
+    }};
+}
+
+fn write_slice(
+    path: &Path,
+    width: u32,
+    height: u32,
+    voxel_type: VoxelType,
+    num_channels: usize,
+    data: &[u8],
+) -> Result<()> {
+    let context = "Could not write TIFF file";
+    let file = fs::File::create(path).map_err(|err| Error::io_at(context, path, err))?;
+    let mut encoder =
+        TiffEncoder::new(BufWriter::new(file)).map_err(|err| tiff_error(context, path, err))?;
+
+    let (w, h) = (width, height);
+    let result = match (voxel_type, num_channels) {
+        (VoxelType::U8, 1) => encoder.write_image::<colortype::Gray8>(w, h, data),
+        (VoxelType::U16, 1) => write_image!(encoder, colortype::Gray16, u16, w, h, data),
+        (VoxelType::U32, 1) => write_image!(encoder, colortype::Gray32, u32, w, h, data),
+        (VoxelType::U64, 1) => write_image!(encoder, colortype::Gray64, u64, w, h, data),
+        (VoxelType::I8, 1) => write_image!(encoder, colortype::GrayI8, i8, w, h, data),
+        (VoxelType::I16, 1) => write_image!(encoder, colortype::GrayI16, i16, w, h, data),
+        (VoxelType::I32, 1) => write_image!(encoder, colortype::GrayI32, i32, w, h, data),
+        (VoxelType::I64, 1) => write_image!(encoder, colortype::GrayI64, i64, w, h, data),
+        (VoxelType::F32, 1) => write_image!(encoder, colortype::Gray32Float, f32, w, h, data),
+        (VoxelType::F64, 1) => write_image!(encoder, colortype::Gray64Float, f64, w, h, data),
+        (VoxelType::U8, 3) => encoder.write_image::<colortype::RGB8>(w, h, data),
+        (VoxelType::U16, 3) => write_image!(encoder, colortype::RGB16, u16, w, h, data),
+        (VoxelType::U32, 3) => write_image!(encoder, colortype::RGB32, u32, w, h, data),
+        (VoxelType::U64, 3) => write_image!(encoder, colortype::RGB64, u64, w, h, data),
+        (VoxelType::F32, 3) => write_image!(encoder, colortype::RGB32Float, f32, w, h, data),
+        (VoxelType::F64, 3) => write_image!(encoder, colortype::RGB64Float, f64, w, h, data),
+        (VoxelType::U8, 4) => encoder.write_image::<colortype::RGBA8>(w, h, data),
+        (VoxelType::U16, 4) => write_image!(encoder, colortype::RGBA16, u16, w, h, data),
+        (VoxelType::U32, 4) => write_image!(encoder, colortype::RGBA32, u32, w, h, data),
+        (VoxelType::U64, 4) => write_image!(encoder, colortype::RGBA64, u64, w, h, data),
+        (VoxelType::F32, 4) => write_image!(encoder, colortype::RGBA32Float, f32, w, h, data),
+        (VoxelType::F64, 4) => write_image!(encoder, colortype::RGBA64Float, f64, w, h, data),
+        _ => return check_color_type(voxel_type, num_channels),
+    };
+
+    result.map_err(|err| tiff_error(context, path, err))
+}
+
+#[test]
+fn test_roundtrip() {
+    use BlockType;
+
+    let root = ::std::env::temp_dir().join(format!("wkwrap-tiff-{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+
+    let header = Header {
+        voxel_size: 6,
+        ..Header::for_tests(1, 1, 1, BlockType::LZ4, VoxelType::U16)
+    };
+    let src = Dataset::create(&root.join("src"), header.clone()).unwrap();
+
+    // RGB, 5 slices spanning two files in z
+    let shape = Vec3 { x: 7, y: 5, z: 5 };
+    let mut data: Vec<u8> = (0..shape.product() * 6).map(|i| (i % 241) as u8).collect();
+    let mat = Mat::new(&mut data, shape, 6, VoxelType::U16, false).unwrap();
+    src.write_mat(Vec3::from(0u32), &mat).unwrap();
+
+    let stack = root.join("stack");
+    export(&src, Box3::from(shape), &stack).unwrap();
+    assert_eq!(list_slices(&stack).unwrap().len(), 5);
+    assert!(stack.join("00004.tif").exists());
+
+    let dst = import(&stack, &root.join("dst"), &header).unwrap();
+    assert_eq!(dst.header().voxel_size, 6);
+
+    let mut out = vec![0u8; data.len()];
+    {
+        let mut out_mat = Mat::new(&mut out, shape, 6, VoxelType::U16, false).unwrap();
+        dst.read_mat(Vec3::from(0u32), &mut out_mat).unwrap();
+    }
+    assert!(out == data);
+
+    fs::remove_dir_all(&root).unwrap();
+}
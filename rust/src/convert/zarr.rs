@@ -0,0 +1,560 @@
+//! Zarr v2 and v3 arrays in local directory stores.
+//!
+//! Arrays have the dimensions `x`, `y`, `z`, preceded by `c` for datasets
+//! with multiple channels, and are stored in Fortran order. Chunks thus have
+//! the same memory layout as WKW blocks.
+//!
+//! Only such arrays can be imported: arrays in C order, as well as arrays
+//! with filters or compressors other than Zstandard (e.g. Blosc), are
+//! rejected.
+
+use super::{dataset_shape, export_chunks, get_str, get_u32s, import_chunks, invalid_field};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use {zstd, Dataset, Error, Header, Result, Vec3, VoxelType};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Version {
+    V2,
+    V3,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Compressor {
+    Raw,
+    /// Zstandard with the given level, or the default level if zero.
+    Zstd(u8),
+}
+
+#[derive(Debug)]
+struct Array {
+    version: Version,
+    shape: Vec3,
+    chunk_shape: Vec3,
+    num_channels: usize,
+    voxel_type: VoxelType,
+    compressor: Compressor,
+    separator: String,
+    // whether v3 chunk keys are prefixed with "c"
+    key_prefix: bool,
+}
+
+/// Writes the bounding box (from the origin) of `dataset` into a new Zarr
+/// array at `path`, with one chunk per WKW block. All-zero chunks are not
+/// written, as zero is the fill value.
+pub fn export(
+    dataset: &Dataset,
+    path: &Path,
+    version: Version,
+    compressor: Compressor,
+) -> Result<()> {
+    let header = dataset.header();
+
+    let array = Array {
+        version,
+        shape: dataset_shape(dataset)?,
+        chunk_shape: header.block_shape(),
+        num_channels: header.num_channels(),
+        voxel_type: header.voxel_type,
+        compressor,
+        separator: String::from(match version {
+            Version::V2 => ".",
+            Version::V3 => "/",
+        }),
+        key_prefix: true,
+    };
+
+    let metadata_path = array.metadata_path(path);
+    if metadata_path.exists() {
+        return Err(Error::AlreadyExists(metadata_path));
+    }
+    super::write_json(&metadata_path, &array.to_json())?;
+
+    export_chunks(dataset, array.chunk_shape, |chunk_ids, buf| {
+        let encoded = array.encode_chunk(buf)?;
+        super::write_file(&array.chunk_path(path, chunk_ids), &encoded)
+    })
+}
+
+/// Creates a WKW dataset at `root` from the Zarr array at `path`. Block and
+/// file lengths, as well as the block type, are taken from `template`; the
+/// voxel type and size from the Zarr metadata. Missing chunks are zero.
+///
+/// The array must be stored in Fortran order, and be uncompressed or
+/// compressed with Zstandard.
+pub fn import(path: &Path, root: &Path, template: &Header) -> Result<Dataset> {
+    let array = Array::open(path)?;
+    let voxel_size = array.num_channels * array.voxel_type.size();
+    let dataset = super::create_dataset(root, template, array.voxel_type, voxel_size)?;
+
+    let chunk_size = array.chunk_shape.product() as usize * voxel_size;
+    import_chunks(&dataset, array.shape, array.chunk_shape, |chunk_ids| {
+        let encoded = match super::read_file(&array.chunk_path(path, chunk_ids))? {
+            Some(encoded) => encoded,
+            None => return Ok(None),
+        };
+
+        let mut buf = vec![0u8; chunk_size];
+        array.decode_chunk(&encoded, &mut buf)?;
+        Ok(Some((array.chunk_shape, buf)))
+    })?;
+
+    Ok(dataset)
+}
+
+impl Array {
+    fn open(path: &Path) -> Result<Array> {
+        let v3_path = path.join("zarr.json");
+        match v3_path.exists() {
+            true => Self::from_json_v3(&super::read_json(&v3_path)?, &v3_path),
+            false => {
+                let v2_path = path.join(".zarray");
+                Self::from_json_v2(&super::read_json(&v2_path)?, &v2_path)
+            }
+        }
+    }
+
+    fn metadata_path(&self, path: &Path) -> PathBuf {
+        match self.version {
+            Version::V2 => path.join(".zarray"),
+            Version::V3 => path.join("zarr.json"),
+        }
+    }
+
+    fn chunk_path(&self, path: &Path, chunk_ids: Vec3) -> PathBuf {
+        let mut parts = Vec::with_capacity(5);
+        if self.version == Version::V3 && self.key_prefix {
+            parts.push(String::from("c"));
+        }
+        if self.num_channels > 1 {
+            parts.push(String::from("0"));
+        }
+        parts.push(chunk_ids.x.to_string());
+        parts.push(chunk_ids.y.to_string());
+        parts.push(chunk_ids.z.to_string());
+
+        // both separators result in valid relative paths
+        path.join(parts.join(&self.separator))
+    }
+
+    fn dims(&self, xyz: Vec3, channels: usize) -> Vec<u32> {
+        let mut dims = Vec::with_capacity(4);
+        if self.num_channels > 1 {
+            dims.push(channels as u32);
+        }
+        dims.extend_from_slice(&[xyz.x, xyz.y, xyz.z]);
+        dims
+    }
+
+    fn to_json(&self) -> Value {
+        let shape = self.dims(self.shape, self.num_channels);
+        let chunks = self.dims(self.chunk_shape, self.num_channels);
+
+        let level = match self.compressor {
+            Compressor::Zstd(0) => zstd::DEFAULT_LEVEL,
+            Compressor::Zstd(level) => level as i32,
+            Compressor::Raw => 0,
+        };
+
+        match self.version {
+            Version::V2 => {
+                let compressor = match self.compressor {
+                    Compressor::Raw => Value::Null,
+                    Compressor::Zstd(_) => json!({ "id": "zstd", "level": level }),
+                };
+
+                json!({
+                    "zarr_format": 2,
+                    "shape": shape,
+                    "chunks": chunks,
+                    "dtype": dtype_v2(self.voxel_type),
+                    "compressor": compressor,
+                    "fill_value": 0,
+                    "order": "F",
+                    "filters": null,
+                    "dimension_separator": self.separator,
+                })
+            }
+            Version::V3 => {
+                let order: Vec<usize> = (0..shape.len()).rev().collect();
+                let mut codecs = vec![
+                    json!({ "name": "transpose", "configuration": { "order": order } }),
+                    json!({ "name": "bytes", "configuration": { "endian": "little" } }),
+                ];
+
+                if let Compressor::Zstd(_) = self.compressor {
+                    codecs.push(json!({
+                        "name": "zstd",
+                        "configuration": { "level": level, "checksum": false },
+                    }));
+                }
+
+                let dimension_names = match self.num_channels > 1 {
+                    true => json!(["c", "x", "y", "z"]),
+                    false => json!(["x", "y", "z"]),
+                };
+
+                json!({
+                    "zarr_format": 3,
+                    "node_type": "array",
+                    "shape": shape,
+                    "data_type": data_type_v3(self.voxel_type),
+                    "chunk_grid": {
+                        "name": "regular",
+                        "configuration": { "chunk_shape": chunks },
+                    },
+                    "chunk_key_encoding": {
+                        "name": "default",
+                        "configuration": { "separator": self.separator },
+                    },
+                    "fill_value": 0,
+                    "codecs": codecs,
+                    "attributes": {},
+                    "dimension_names": dimension_names,
+                })
+            }
+        }
+    }
+
+    fn from_json_v2(value: &Value, path: &Path) -> Result<Array> {
+        if value["zarr_format"].as_u64() != Some(2) {
+            return Err(invalid_field(path, "zarr_format"));
+        }
+
+        if get_str(value, path, "order")? != "F" {
+            return Err(unsupported(path, "arrays in C order"));
+        }
+
+        match value["filters"] {
+            Value::Null => (),
+            Value::Array(ref filters) if filters.is_empty() => (),
+            _ => return Err(unsupported(path, "filters")),
+        }
+
+        let voxel_type = parse_dtype_v2(get_str(value, path, "dtype")?)
+            .ok_or_else(|| invalid_field(path, "dtype"))?;
+
+        let compressor = match value["compressor"] {
+            Value::Null => Compressor::Raw,
+            ref compressor if compressor["id"] == "zstd" => Compressor::Zstd(0),
+            _ => return Err(unsupported(path, "compressors other than zstd")),
+        };
+
+        let separator = match value["dimension_separator"] {
+            Value::Null => String::from("."),
+            ref separator => separator
+                .as_str()
+                .map(String::from)
+                .ok_or_else(|| invalid_field(path, "dimension_separator"))?,
+        };
+
+        check_fill_value(value, path)?;
+
+        let shape = get_u32s(value, path, "shape")?;
+        let chunks = get_u32s(value, path, "chunks")?;
+        Self::new(
+            Version::V2,
+            &shape,
+            &chunks,
+            voxel_type,
+            compressor,
+            separator,
+            path,
+        )
+    }
+
+    fn from_json_v3(value: &Value, path: &Path) -> Result<Array> {
+        if value["zarr_format"].as_u64() != Some(3) {
+            return Err(invalid_field(path, "zarr_format"));
+        }
+
+        if get_str(value, path, "node_type")? != "array" {
+            return Err(invalid_field(path, "node_type"));
+        }
+
+        let voxel_type = parse_data_type_v3(get_str(value, path, "data_type")?)
+            .ok_or_else(|| invalid_field(path, "data_type"))?;
+
+        let chunk_grid = &value["chunk_grid"];
+        if chunk_grid["name"] != "regular" {
+            return Err(unsupported(path, "irregular chunk grids"));
+        }
+        let chunks = get_u32s(&chunk_grid["configuration"], path, "chunk_shape")?;
+
+        let key_encoding = &value["chunk_key_encoding"];
+        let (key_prefix, default_separator) = match key_encoding["name"].as_str() {
+            Some("default") => (true, "/"),
+            Some("v2") => (false, "."),
+            _ => return Err(invalid_field(path, "chunk_key_encoding")),
+        };
+        let separator = key_encoding["configuration"]["separator"]
+            .as_str()
+            .unwrap_or(default_separator);
+
+        check_fill_value(value, path)?;
+
+        let shape = get_u32s(value, path, "shape")?;
+        let codecs = value["codecs"]
+            .as_array()
+            .ok_or_else(|| invalid_field(path, "codecs"))?;
+
+        // only Fortran order is supported, i.e., a reversing transpose
+        let mut is_fortran_order = false;
+        let mut compressor = Compressor::Raw;
+
+        for codec in codecs {
+            let config = &codec["configuration"];
+            match codec["name"].as_str() {
+                Some("transpose") => {
+                    let order: Vec<u32> = get_u32s(config, path, "order")?;
+                    let reversed: Vec<u32> = (0..shape.len() as u32).rev().collect();
+                    is_fortran_order = order == reversed;
+                }
+                Some("bytes") => match config["endian"].as_str() {
+                    Some("little") | None => (),
+                    Some(_) => return Err(unsupported(path, "big-endian data")),
+                },
+                Some("zstd") => compressor = Compressor::Zstd(0),
+                _ => {
+                    return Err(unsupported(
+                        path,
+                        "codecs other than transpose, bytes, zstd",
+                    ))
+                }
+            }
+        }
+
+        if !is_fortran_order {
+            return Err(unsupported(path, "arrays in C order"));
+        }
+
+        let mut array = Self::new(
+            Version::V3,
+            &shape,
+            &chunks,
+            voxel_type,
+            compressor,
+            String::from(separator),
+            path,
+        )?;
+        array.key_prefix = key_prefix;
+        Ok(array)
+    }
+
+    fn new(
+        version: Version,
+        shape: &[u32],
+        chunks: &[u32],
+        voxel_type: VoxelType,
+        compressor: Compressor,
+        separator: String,
+        path: &Path,
+    ) -> Result<Array> {
+        if shape.len() != chunks.len() || chunks.contains(&0) {
+            return Err(invalid_field(path, "chunks"));
+        }
+
+        let (num_channels, shape, chunks) = match shape.len() {
+            3 => (1, shape, chunks),
+            4 if chunks[0] == shape[0] => (shape[0] as usize, &shape[1..], &chunks[1..]),
+            4 => return Err(unsupported(path, "channels split across chunks")),
+            _ => return Err(unsupported(path, "arrays which are not 3D")),
+        };
+
+        Ok(Array {
+            version,
+            shape: Vec3 {
+                x: shape[0],
+                y: shape[1],
+                z: shape[2],
+            },
+            chunk_shape: Vec3 {
+                x: chunks[0],
+                y: chunks[1],
+                z: chunks[2],
+            },
+            num_channels,
+            voxel_type,
+            compressor,
+            separator,
+            key_prefix: true,
+        })
+    }
+
+    fn encode_chunk(&self, buf: &[u8]) -> Result<Vec<u8>> {
+        match self.compressor {
+            Compressor::Raw => Ok(buf.to_vec()),
+            Compressor::Zstd(level) => {
+                let mut encoded = vec![0u8; zstd::compress_bound(buf.len())];
+                let len = zstd::compress(buf, &mut encoded, level)?;
+                encoded.truncate(len);
+                Ok(encoded)
+            }
+        }
+    }
+
+    fn decode_chunk(&self, encoded: &[u8], buf: &mut [u8]) -> Result<()> {
+        let len = match self.compressor {
+            Compressor::Raw if encoded.len() == buf.len() => {
+                buf.copy_from_slice(encoded);
+                encoded.len()
+            }
+            Compressor::Raw => encoded.len(),
+            Compressor::Zstd(_) => zstd::decompress(encoded, buf)?,
+        };
+
+        match len == buf.len() {
+            true => Ok(()),
+            false => Err(Error::Decompression(format!(
+                "Zarr chunk has {} bytes, expected {}",
+                len,
+                buf.len()
+            ))),
+        }
+    }
+}
+
+fn unsupported(path: &Path, what: &str) -> Error {
+    Error::invalid(format!("{:?}: Zarr {} are not supported", path, what))
+}
+
+fn check_fill_value(value: &Value, path: &Path) -> Result<()> {
+    match value["fill_value"] {
+        Value::Null => Ok(()),
+        ref fill_value if fill_value.as_f64() == Some(0.0) => Ok(()),
+        _ => Err(unsupported(path, "non-zero fill values")),
+    }
+}
+
+fn dtype_v2(voxel_type: VoxelType) -> &'static str {
+    match voxel_type {
+        VoxelType::U8 => "|u1",
+        VoxelType::U16 => "<u2",
+        VoxelType::U32 => "<u4",
+        VoxelType::U64 => "<u8",
+        VoxelType::I8 => "|i1",
+        VoxelType::I16 => "<i2",
+        VoxelType::I32 => "<i4",
+        VoxelType::I64 => "<i8",
+        VoxelType::F32 => "<f4",
+        VoxelType::F64 => "<f8",
+    }
+}
+
+fn parse_dtype_v2(dtype: &str) -> Option<VoxelType> {
+    let voxel_type = match dtype.get(1..).unwrap_or("") {
+        "u1" => VoxelType::U8,
+        "u2" => VoxelType::U16,
+        "u4" => VoxelType::U32,
+        "u8" => VoxelType::U64,
+        "i1" => VoxelType::I8,
+        "i2" => VoxelType::I16,
+        "i4" => VoxelType::I32,
+        "i8" => VoxelType::I64,
+        "f4" => VoxelType::F32,
+        "f8" => VoxelType::F64,
+        _ => return None,
+    };
+
+    // big-endian data is only fine for single bytes
+    match dtype.chars().next() {
+        Some('<') | Some('|') => Some(voxel_type),
+        Some('>') if voxel_type.size() == 1 => Some(voxel_type),
+        _ => None,
+    }
+}
+
+fn data_type_v3(voxel_type: VoxelType) -> &'static str {
+    match voxel_type {
+        VoxelType::U8 => "uint8",
+        VoxelType::U16 => "uint16",
+        VoxelType::U32 => "uint32",
+        VoxelType::U64 => "uint64",
+        VoxelType::I8 => "int8",
+        VoxelType::I16 => "int16",
+        VoxelType::I32 => "int32",
+        VoxelType::I64 => "int64",
+        VoxelType::F32 => "float32",
+        VoxelType::F64 => "float64",
+    }
+}
+
+fn parse_data_type_v3(data_type: &str) -> Option<VoxelType> {
+    [
+        VoxelType::U8,
+        VoxelType::U16,
+        VoxelType::U32,
+        VoxelType::U64,
+        VoxelType::I8,
+        VoxelType::I16,
+        VoxelType::I32,
+        VoxelType::I64,
+        VoxelType::F32,
+        VoxelType::F64,
+    ]
+    .iter()
+    .cloned()
+    .find(|&voxel_type| data_type_v3(voxel_type) == data_type)
+}
+
+#[test]
+fn test_roundtrip() {
+    use {BlockType, Mat};
+
+    let root = ::std::env::temp_dir().join(format!("wkwrap-zarr-{}", ::std::process::id()));
+    let _ = ::std::fs::remove_dir_all(&root);
+
+    let header = Header {
+        voxel_size: 4,
+        ..Header::for_tests(1, 2, 1, BlockType::Raw, VoxelType::U16)
+    };
+    let src = Dataset::create(&root.join("src"), header.clone()).unwrap();
+
+    let shape = Vec3 { x: 16, y: 8, z: 9 };
+    let mut data: Vec<u8> = (0..shape.product() * 4).map(|i| (i % 251) as u8).collect();
+    let mat = Mat::new(&mut data, shape, 4, VoxelType::U16, false).unwrap();
+    src.write_mat(Vec3::from(0u32), &mat).unwrap();
+
+    let template = Header {
+        block_type: BlockType::LZ4,
+        ..header
+    };
+
+    for &(version, compressor) in &[
+        (Version::V2, Compressor::Raw),
+        (Version::V3, Compressor::Zstd(0)),
+    ] {
+        let name = format!("{:?}", version);
+        let zarr_path = root.join(format!("{}.zarr", name));
+        export(&src, &zarr_path, version, compressor).unwrap();
+
+        let chunk_path = match version {
+            Version::V2 => zarr_path.join("0.1.1.2"),
+            Version::V3 => zarr_path.join("c/0/1/1/2"),
+        };
+        assert!(chunk_path.exists());
+
+        let dst = import(&zarr_path, &root.join(name), &template).unwrap();
+        assert_eq!(dst.header().voxel_size, 4);
+        assert_eq!(dst.header().block_type, BlockType::LZ4);
+
+        let mut out = vec![0u8; data.len()];
+        {
+            let mut out_mat = Mat::new(&mut out, shape, 4, VoxelType::U16, false).unwrap();
+            dst.read_mat(Vec3::from(0u32), &mut out_mat).unwrap();
+        }
+        assert!(out == data);
+    }
+
+    ::std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_parse_dtype_v2() {
+    assert_eq!(parse_dtype_v2("<u2"), Some(VoxelType::U16));
+    assert_eq!(parse_dtype_v2(">u1"), Some(VoxelType::U8));
+    assert_eq!(parse_dtype_v2(">u2"), None);
+    assert_eq!(parse_dtype_v2(""), None);
+    assert_eq!(parse_dtype_v2("<"), None);
+    assert_eq!(parse_dtype_v2("\u{e4}u2"), None);
+}
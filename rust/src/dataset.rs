@@ -1,11 +1,25 @@
-use std::fs;
+use cache::{BlockCache, FileCache};
+use file;
 use std::path::{Path, PathBuf};
-use {Box3, File, Header, Mat, Result, Vec3};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::{cmp, fs, thread};
+#[cfg(feature = "s3")]
+use storage::S3Storage;
+use storage::{LocalStorage, Storage};
+use {BlockType, Box3, CacheStats, Error, File, Header, Mat, Metadata, Report, Result, Vec3};
 
 #[derive(Debug, Clone)]
 pub struct Dataset {
-    root: PathBuf,
+    storage: Arc<dyn Storage>,
     header: Header,
+    use_mmap: bool,
+    num_threads: usize,
+    // opened files, shared between clones of the dataset
+    file_cache: Arc<Mutex<FileCache>>,
+    block_cache: Arc<BlockCache>,
+    fill_value: Vec<u8>,
+    allow_missing_files: bool,
 }
 
 static HEADER_FILE_NAME: &str = "header.wkw";
@@ -13,235 +27,1369 @@ static HEADER_FILE_NAME: &str = "header.wkw";
 impl Dataset {
     pub fn new(root: &Path) -> Result<Dataset> {
         if !root.is_dir() {
-            return Err(format!("Dataset root {:?} is not a directory", &root));
+            return Err(Error::invalid(format!(
+                "Dataset root {:?} is not a directory",
+                &root
+            )));
         }
 
+        Self::with_storage(Arc::new(LocalStorage::new(root)))
+    }
+
+    /// Opens the dataset at `url`, which is either a local path or, with
+    /// the `s3` feature, an S3 URL of the form `s3://<bucket>/<prefix>`.
+    pub fn open(url: &str) -> Result<Dataset> {
+        if url.starts_with("s3://") {
+            return Self::open_s3(url);
+        }
+
+        Self::new(Path::new(url))
+    }
+
+    #[cfg(feature = "s3")]
+    fn open_s3(url: &str) -> Result<Dataset> {
+        Self::with_storage(Arc::new(S3Storage::new(url)?))
+    }
+
+    #[cfg(not(feature = "s3"))]
+    fn open_s3(url: &str) -> Result<Dataset> {
+        Err(Error::invalid(format!(
+            "Cannot open {:?} without the s3 feature",
+            url
+        )))
+    }
+
+    /// Opens the dataset stored in `storage`.
+    pub fn with_storage(storage: Arc<dyn Storage>) -> Result<Dataset> {
         // read required header file
-        let header = Self::read_header(root)?;
+        let (header, fill_value) = Self::read_header(&*storage)?;
 
         Ok(Dataset {
-            root: root.to_owned(),
+            storage,
             header,
+            use_mmap: false,
+            num_threads: 1,
+            file_cache: Arc::new(Mutex::new(FileCache::new(0))),
+            block_cache: Arc::new(BlockCache::new(0)),
+            fill_value,
+            allow_missing_files: true,
         })
     }
 
-    pub fn create(root: &Path, mut header: Header) -> Result<Dataset> {
+    pub fn create(root: &Path, header: Header) -> Result<Dataset> {
+        header.check_shape()?;
+
         // create directory hierarchy
-        fs::create_dir_all(root).or(Err(format!(
-            "Could not create dataset directory {:?}",
-            &root
-        )))?;
+        fs::create_dir_all(root)
+            .map_err(|err| Error::io_at("Could not create dataset directory", root, err))?;
 
-        // create header file
-        Self::create_header_file(root, &mut header)?;
-        Self::new(root)
+        Self::create_with_storage(Arc::new(LocalStorage::new(root)), header)
+    }
+
+    /// Creates a new dataset in `storage`.
+    pub fn create_with_storage(storage: Arc<dyn Storage>, mut header: Header) -> Result<Dataset> {
+        Self::create_header_file(&*storage, &mut header)?;
+        Self::with_storage(storage)
     }
 
     pub fn compress(&self, path: &Path) -> Result<Dataset> {
-        let header = Header::compress(&self.header);
-        Self::create(path, header)
+        self.compress_with(path, BlockType::LZ4HC, 0)
+    }
+
+    /// Creates an empty dataset at `path` with the header of this dataset,
+    /// but compressed blocks. Fails with an invalid argument error if the
+    /// level is not valid for the block type (see
+    /// `BlockType::compression_levels`), or if a level other than the
+    /// default is given for a version-1 dataset.
+    pub fn compress_with(
+        &self,
+        path: &Path,
+        block_type: BlockType,
+        compression_level: u8,
+    ) -> Result<Dataset> {
+        let header = Header::compress_with(&self.header, block_type, compression_level)?;
+        let mut dataset = Self::create(path, header)?;
+
+        if self.fill_value.iter().any(|&byte| byte != 0) {
+            dataset.set_fill_value(&self.fill_value)?;
+        }
+
+        Ok(dataset)
     }
 
-    fn create_header_file(root: &Path, header: &mut Header) -> Result<()> {
+    fn create_header_file(storage: &dyn Storage, header: &mut Header) -> Result<()> {
         header.data_offset = 0;
         header.jump_table = None;
+        header.checksum_table = None;
 
-        // build path to header file
-        let mut header_path = PathBuf::from(root);
-        header_path.push(HEADER_FILE_NAME);
+        if header.version >= 2 {
+            let metadata = header.metadata.get_or_insert_with(Metadata::default);
+            if metadata.creation_tool.is_none() {
+                let creation_tool = format!("wkwrap {}", env!("CARGO_PKG_VERSION"));
+                metadata.creation_tool = Some(creation_tool);
+            }
+        }
 
-        if header_path.exists() {
-            return Err(format!("Header {:?} file already exists", &header_path));
+        if storage.exists(HEADER_FILE_NAME)? {
+            return Err(Error::AlreadyExists(Self::object_path(
+                storage,
+                HEADER_FILE_NAME,
+            )));
         }
 
         // create header file
-        let mut file = fs::File::create(&header_path).or_else(|err| {
-            Err(format!(
-                "Could not create header file {:?}: {}",
-                &header_path, err
-            ))
-        })?;
-
-        header.write(&mut file)
+        let mut buf = Vec::new();
+        header.write(&mut buf)?;
+        storage.put(HEADER_FILE_NAME, &buf)
     }
 
     pub fn header(&self) -> &Header {
         &self.header
     }
 
+    pub fn storage(&self) -> &Arc<dyn Storage> {
+        &self.storage
+    }
+
+    pub fn fill_value(&self) -> &[u8] {
+        &self.fill_value
+    }
+
+    /// Sets the value which `read_mat` returns for voxels in missing files
+    /// and in unwritten parts of new files, given as the (little endian)
+    /// bytes of a single voxel. The fill value is zero by default. It is
+    /// stored in the metadata of version-2 headers, and after the header in
+    /// the header file otherwise.
+    ///
+    /// Files of version-2 datasets carry the fill value in their metadata,
+    /// and their compressed blocks consisting of the fill value only are not
+    /// stored. Files of version-1 datasets cannot carry it, so only blocks
+    /// of zeros are left out. Files written before keep their content, so
+    /// the fill value should be set before writing any data.
+    pub fn set_fill_value(&mut self, fill_value: &[u8]) -> Result<()> {
+        if fill_value.len() != self.header.voxel_size as usize {
+            return Err(Error::VoxelSizeMismatch {
+                expected: self.header.voxel_size as usize,
+                found: fill_value.len(),
+            });
+        }
+
+        let mut header = self.header.clone();
+        let mut buf = Vec::new();
+        match header.metadata {
+            Some(ref mut metadata) => {
+                metadata.fill_value = Some(fill_value.to_vec());
+                header.write(&mut buf)?;
+            }
+            None => {
+                header.write(&mut buf)?;
+                buf.extend_from_slice(fill_value);
+            }
+        }
+        self.storage.put(HEADER_FILE_NAME, &buf)?;
+
+        self.header = header;
+        self.fill_value = fill_value.to_vec();
+        self.file_cache.lock().unwrap().clear();
+        self.block_cache.clear();
+        Ok(())
+    }
+
+    /// Whether `read_mat` fills regions covered by missing files with the
+    /// fill value (the default) or fails with a not found error.
+    pub fn set_allow_missing_files(&mut self, allow_missing_files: bool) {
+        self.allow_missing_files = allow_missing_files;
+    }
+
+    /// Read raw files through memory mappings. This setting has no effect
+    /// on datasets with compressed blocks.
+    ///
+    /// Files must not be truncated or rewritten in place by other processes
+    /// while they are mapped: reads then return undefined data, or the
+    /// process is killed by a SIGBUS signal. Writes through this library
+    /// replace whole files or write blocks in place, which only leads to
+    /// torn reads of these blocks.
+    pub fn set_use_mmap(&mut self, use_mmap: bool) {
+        self.use_mmap = use_mmap;
+        self.file_cache.lock().unwrap().clear();
+    }
+
+    /// Keep up to `capacity` files (and their headers and jump tables) open
+    /// between calls to `read_mat`, evicting the least recently used file
+    /// once full. Files are dropped from the cache when written through
+    /// this dataset or its clones, but changes by other processes are not
+    /// detected. The cache is disabled (capacity zero) by default.
+    pub fn set_file_cache_capacity(&mut self, capacity: usize) {
+        self.file_cache.lock().unwrap().set_capacity(capacity);
+    }
+
+    pub fn file_cache_capacity(&self) -> usize {
+        self.file_cache.lock().unwrap().capacity()
+    }
+
+    /// Keep up to `capacity` bytes of decompressed blocks in memory, so
+    /// that repeated reads of the same blocks skip their decompression.
+    /// Like the file cache, the block cache is shared between clones of the
+    /// dataset and disabled by default. Blocks of raw datasets are not
+    /// cached.
+    pub fn set_block_cache_capacity(&mut self, capacity: usize) {
+        self.block_cache.set_capacity(capacity);
+
+        // cached files might not use the block cache yet
+        self.file_cache.lock().unwrap().clear();
+    }
+
+    pub fn block_cache_capacity(&self) -> usize {
+        self.block_cache.capacity()
+    }
+
+    /// Number of blocks read from and missing in the block cache, and the
+    /// size of the cached blocks in bytes.
+    pub fn block_cache_stats(&self) -> CacheStats {
+        self.block_cache.stats()
+    }
+
     pub fn read_mat(&self, src_pos: Vec3, mat: &mut Mat) -> Result<usize> {
         let bbox = Box3::from(mat.shape) + src_pos;
-        let file_len_vx_log2 = self.header.file_len_vx_log2() as u32;
+        let file_ids = self.file_ids(bbox)?;
 
-        // find files to load
-        let bbox_files = Box3::new(
-            bbox.min() >> file_len_vx_log2,
-            ((bbox.max() - 1) >> file_len_vx_log2) + 1,
-        )?;
-
-        for cur_z in bbox_files.min().z..bbox_files.max().z {
-            for cur_y in bbox_files.min().y..bbox_files.max().y {
-                for cur_x in bbox_files.min().x..bbox_files.max().x {
-                    // file path to wkw file
-                    let mut cur_path = self.root.clone();
-                    cur_path.push(format!("z{}", cur_z));
-                    cur_path.push(format!("y{}", cur_y));
-                    cur_path.push(format!("x{}.wkw", cur_x));
-
-                    // bounding box
-                    let cur_file_ids = Vec3 {
-                        x: cur_x,
-                        y: cur_y,
-                        z: cur_z,
-                    };
-                    let cur_file_box = Box3::new(
-                        cur_file_ids << file_len_vx_log2,
-                        (cur_file_ids + 1) << file_len_vx_log2,
-                    )?;
-                    let cur_box = cur_file_box.intersect(bbox);
-
-                    // offsets
-                    let cur_src_pos = cur_box.min() - cur_file_box.min();
-                    let cur_dst_pos = cur_box.min() - src_pos;
-
-                    // try to open file
-                    if let Ok(mut file) = File::open(&cur_path) {
-                        match file.read_mat(cur_src_pos, mat, cur_dst_pos) {
-                            Ok(_) => {}
-                            Err(err) => {
-                                return Err(format!(
-                                    "Error while reading from file {:?}: {}",
-                                    &cur_path, err
-                                ));
-                            }
-                        }
-                    }
-                }
+        if self.num_threads < 2 || file_ids.len() < 2 {
+            for &cur_file_ids in &file_ids {
+                self.read_file_mat(cur_file_ids, bbox, mat, src_pos)?;
             }
+
+            return Ok(1);
         }
 
-        Ok(1 as usize)
+        let voxel_size = mat.voxel_size;
+        let voxel_type = mat.voxel_type;
+        let data_in_c_order = mat.data_in_c_order;
+        let dst_mat = Mutex::new(mat);
+
+        self.for_each_file(&file_ids, |cur_file_ids| {
+            let cur_box = self.file_box(cur_file_ids)?.intersect(bbox);
+            let cur_shape = cur_box.width();
+
+            // read into buffer
+            let mut buf = vec![0u8; cur_shape.product() as usize * voxel_size];
+            let mut buf_mat =
+                Mat::new(&mut buf, cur_shape, voxel_size, voxel_type, data_in_c_order)?;
+
+            self.read_file_mat(cur_file_ids, cur_box, &mut buf_mat, cur_box.min())?;
+
+            // copy buffer into the disjoint region of the destination
+            let mut dst_mat = dst_mat.lock().unwrap();
+            dst_mat.copy_from(cur_box.min() - src_pos, &buf_mat, Box3::from(cur_shape))
+        })?;
+
+        Ok(1)
     }
 
+    /// Writes `mat` into the dataset at `dst_pos`.
+    ///
+    /// Writes to local files are serialized per file with advisory locks,
+    /// so that concurrent writers (threads or processes) do not corrupt a
+    /// file. In particular, concurrent writes to disjoint blocks of the same
+    /// file are safe, and overlapping writes are applied one after the other
+    /// within each file. Readers do not take locks.
+    ///
+    /// Files of storage backends without local paths (e.g. S3) are not
+    /// locked. They are read, modified in memory, and put as a whole, so
+    /// that concurrent writes into the same file may overwrite each other.
+    /// Writers of such datasets must not write into the same files at the
+    /// same time.
     pub fn write_mat(&self, dst_pos: Vec3, mat: &Mat) -> Result<usize> {
         // validate input matrix
         if mat.voxel_type != self.header.voxel_type {
-            return Err(format!(
-                "Input matrix has invalid voxel type {:?} != {:?}",
-                mat.voxel_type, self.header.voxel_type
-            ));
+            return Err(Error::VoxelTypeMismatch {
+                expected: self.header.voxel_type,
+                found: mat.voxel_type,
+            });
         }
 
         if mat.voxel_size != self.header.voxel_size as usize {
-            return Err(format!(
-                "Input matrix has invalid voxel size {} != {}",
-                mat.voxel_size, self.header.voxel_size as usize
-            ));
-        }
-
-        let file_len_vx_log2 = self.header.file_len_vx_log2() as u32;
-        if self.header.is_compressed() {
-            let file_len_vec = Vec3::from(1 << file_len_vx_log2);
-            let is_dst_aligned = dst_pos % file_len_vec == Vec3::from(0);
-            let is_shape_aligned = mat.shape % file_len_vec == Vec3::from(0);
-            if !is_dst_aligned || !is_shape_aligned {
-                return Err(String::from(
-                    "When writing compressed files, each file has to be \
-                            written as a whole. Please pad your data so that all cubes \
-                            are complete and the write position is block-aligned.",
-                ));
-            }
-        };
+            return Err(Error::VoxelSizeMismatch {
+                expected: self.header.voxel_size as usize,
+                found: mat.voxel_size,
+            });
+        }
 
         let bbox = Box3::from(mat.shape) + dst_pos;
+        let file_ids = self.file_ids(bbox)?;
+
+        self.for_each_file(&file_ids, |cur_file_ids| {
+            self.uncache_file(cur_file_ids);
+            let result = self.write_file_mat(cur_file_ids, bbox, mat, dst_pos);
+
+            // concurrent reads which opened the file before it was written
+            // must not put it back into the caches
+            self.uncache_file(cur_file_ids);
+            result
+        })?;
+
+        Ok(1)
+    }
+
+    /// Lists the coordinates and paths of all files in the dataset,
+    /// sorted by z, y, and x coordinate. For datasets which are not stored
+    /// in the local filesystem, the paths are the storage keys.
+    pub fn list_files(&self) -> Result<Vec<(Vec3, PathBuf)>> {
+        let mut files = Vec::new();
+
+        for z in self.list_dir("", "z", "")? {
+            for y in self.list_dir(&format!("z{}/", z), "y", "")? {
+                for x in self.list_dir(&format!("z{}/y{}/", z, y), "x", ".wkw")? {
+                    let ids = Vec3 { x, y, z };
+                    files.push((ids, self.file_path(ids)));
+                }
+            }
+        }
+
+        files.sort_by_key(|&(ids, _)| (ids.z, ids.y, ids.x));
+        Ok(files)
+    }
+
+    /// Computes the tight bounding box (in voxels) of all files in the
+    /// dataset. With `refine` set, the bounding box is shrunk to the blocks
+    /// which contain non-zero data. Returns `None` for empty datasets.
+    pub fn bounding_box(&self, refine: bool) -> Result<Option<Box3>> {
+        let files = self.list_files()?;
+        let file_ids: Vec<Vec3> = files.iter().map(|&(ids, _)| ids).collect();
+        let bbox: Mutex<Option<Box3>> = Mutex::new(None);
+
+        self.for_each_file(&file_ids, |cur_file_ids| {
+            let cur_file_box = self.file_box(cur_file_ids)?;
+
+            let cur_box = match refine {
+                true => {
+                    let cur_path = self.file_path(cur_file_ids);
+                    let mut file = self
+                        .open_file(cur_file_ids, false)
+                        .map_err(|err| err.in_file(&cur_path))?;
+                    match file.nonzero_box().map_err(|err| err.in_file(&cur_path))? {
+                        Some(cur_box) => cur_box + cur_file_box.min(),
+                        None => return Ok(()),
+                    }
+                }
+                false => cur_file_box,
+            };
+
+            let mut bbox = bbox.lock().unwrap();
+            *bbox = Some(match *bbox {
+                Some(bbox) => bbox.union(cur_box),
+                None => cur_box,
+            });
+
+            Ok(())
+        })?;
+
+        Ok(bbox.into_inner().unwrap())
+    }
+
+    /// Verifies all files of the dataset. Returns one report per file,
+    /// in the order of `list_files`.
+    pub fn verify(&self) -> Result<Vec<Report>> {
+        let files = self.list_files()?;
+        let file_ids: Vec<Vec3> = files.iter().map(|&(ids, _)| ids).collect();
+        let reports = Mutex::new(Vec::with_capacity(files.len()));
+
+        self.for_each_file(&file_ids, |cur_file_ids| {
+            let key = Self::file_key(cur_file_ids);
+            let report = match self.storage.local_path(&key) {
+                Some(path) => File::verify(&path)?,
+                None => File::verify_storage(self.storage.clone(), &key)?,
+            };
+            reports.lock().unwrap().push((cur_file_ids, report));
+            Ok(())
+        })?;
+
+        let mut reports = reports.into_inner().unwrap();
+        reports.sort_by_key(|&(ids, _)| (ids.z, ids.y, ids.x));
+        Ok(reports.into_iter().map(|(_, report)| report).collect())
+    }
+
+    fn list_dir(&self, key_prefix: &str, prefix: &str, suffix: &str) -> Result<Vec<u32>> {
+        let ids = self
+            .storage
+            .list(key_prefix)?
+            .iter()
+            .filter_map(|name| {
+                name.strip_prefix(prefix)
+                    .and_then(|name| name.strip_suffix(suffix))
+                    .and_then(|id| id.parse::<u32>().ok())
+            })
+            .collect();
+
+        Ok(ids)
+    }
+
+    /// Sets the number of threads used to process the files touched by
+    /// `read_mat` and `write_mat`. With less than two threads, all files
+    /// are processed serially on the calling thread.
+    pub fn set_num_threads(&mut self, num_threads: usize) {
+        self.num_threads = num_threads;
+    }
+
+    pub fn num_threads(&self) -> usize {
+        self.num_threads
+    }
+
+    fn file_ids(&self, bbox: Box3) -> Result<Vec<Vec3>> {
+        let file_shape_vx_log2 = self.header.file_shape_vx_log2();
 
         // find files to load
         let bbox_files = Box3::new(
-            bbox.min() >> file_len_vx_log2,
-            ((bbox.max() - 1) >> file_len_vx_log2) + 1,
+            bbox.min() >> file_shape_vx_log2,
+            ((bbox.max() - 1) >> file_shape_vx_log2) + 1,
         )?;
 
+        let mut file_ids = Vec::with_capacity(bbox_files.width().product() as usize);
         for cur_z in bbox_files.min().z..bbox_files.max().z {
             for cur_y in bbox_files.min().y..bbox_files.max().y {
                 for cur_x in bbox_files.min().x..bbox_files.max().x {
-                    // file path to wkw file
-                    let mut cur_path = self.root.clone();
-                    cur_path.push(format!("z{}", cur_z));
-                    cur_path.push(format!("y{}", cur_y));
-                    cur_path.push(format!("x{}.wkw", cur_x));
-
-                    // writing compressed file into temporary file first
-                    if self.header.is_compressed() {
-                        cur_path.set_extension("wkw_tmp");
-                    }
-
-                    // bounding box
-                    let cur_file_ids = Vec3 {
+                    file_ids.push(Vec3 {
                         x: cur_x,
                         y: cur_y,
                         z: cur_z,
-                    };
-                    let cur_file_box = Box3::new(
-                        cur_file_ids << file_len_vx_log2,
-                        (cur_file_ids + 1) << file_len_vx_log2,
-                    )?;
-                    let cur_box = cur_file_box.intersect(bbox);
-
-                    // offsets
-                    let cur_src_pos = cur_box.min() - dst_pos;
-                    let cur_dst_pos = cur_box.min() - cur_file_box.min();
-
-                    {
-                        let mut file = match File::open_or_create(&cur_path, &self.header) {
-                            Ok(file) => file,
-                            Err(err) => {
-                                return Err(format!(
-                                    "Error while open file {:?} for writing: {}",
-                                    &cur_path, err
-                                ));
-                            }
-                        };
-                        match file.write_mat(cur_dst_pos, mat, cur_src_pos) {
-                            Ok(_) => {}
-                            Err(err) => {
-                                return Err(format!(
-                                    "Error while writing to file {:?}: {}",
-                                    &cur_path, err
-                                ));
-                            }
-                        }
+                    });
+                }
+            }
+        }
+
+        Ok(file_ids)
+    }
+
+    fn file_key(file_ids: Vec3) -> String {
+        format!("z{}/y{}/x{}.wkw", file_ids.z, file_ids.y, file_ids.x)
+    }
+
+    fn file_path(&self, file_ids: Vec3) -> PathBuf {
+        Self::object_path(&*self.storage, &Self::file_key(file_ids))
+    }
+
+    // Path of an object for error messages and listings.
+    fn object_path(storage: &dyn Storage, key: &str) -> PathBuf {
+        storage
+            .local_path(key)
+            .unwrap_or_else(|| PathBuf::from(key))
+    }
+
+    fn open_file(&self, file_ids: Vec3, use_mmap: bool) -> Result<File> {
+        let key = Self::file_key(file_ids);
+        let mut file = match self.storage.local_path(&key) {
+            Some(ref path) if use_mmap => File::open_mmap(path),
+            Some(ref path) => File::open(path),
+            None => File::open_storage(self.storage.clone(), &key),
+        }?;
+
+        file.set_fill_value(&self.fill_value)?;
+        Ok(file)
+    }
+
+    fn file_box(&self, file_ids: Vec3) -> Result<Box3> {
+        let file_shape_vx_log2 = self.header.file_shape_vx_log2();
+        Box3::new(
+            file_ids << file_shape_vx_log2,
+            (file_ids + 1) << file_shape_vx_log2,
+        )
+    }
+
+    // Runs `f` for each file. Errors are reported deterministically: if
+    // multiple files fail, the error of the first one in `file_ids` wins.
+    pub(crate) fn for_each_file<F>(&self, file_ids: &[Vec3], f: F) -> Result<()>
+    where
+        F: Fn(Vec3) -> Result<()> + Sync,
+    {
+        let num_threads = cmp::min(self.num_threads, file_ids.len());
+        if num_threads < 2 {
+            return file_ids
+                .iter()
+                .try_for_each(|&cur_file_ids| f(cur_file_ids));
+        }
+
+        let next_idx = AtomicUsize::new(0);
+        let first_err_idx = AtomicUsize::new(usize::MAX);
+        let errors = Mutex::new(Vec::new());
+
+        thread::scope(|scope| {
+            for _ in 0..num_threads {
+                scope.spawn(|| loop {
+                    // files are handed out in order; once a file has failed,
+                    // all files preceding it have already been handed out
+                    let idx = next_idx.fetch_add(1, Ordering::SeqCst);
+                    if idx >= file_ids.len() || idx > first_err_idx.load(Ordering::SeqCst) {
+                        break;
                     }
-                    // moving compressed file into final file
-                    if self.header.is_compressed() {
-                        let mut new_path = cur_path.clone();
-                        new_path.set_extension("wkw");
-                        match File::rename(&cur_path, &new_path) {
-                            Ok(_) => {}
-                            Err(err) => {
-                                return Err(format!(
-                                    "Error while renaming temporary file {:?} to {:?}: {}",
-                                    &cur_path, &new_path, err
-                                ));
-                            }
-                        }
+
+                    if let Err(err) = f(file_ids[idx]) {
+                        first_err_idx.fetch_min(idx, Ordering::SeqCst);
+                        errors.lock().unwrap().push((idx, err));
                     }
+                });
+            }
+        });
+
+        let errors = errors.into_inner().unwrap();
+        match errors.into_iter().min_by_key(|&(idx, _)| idx) {
+            Some((_, err)) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    // Regions of missing files are set to the fill value, if allowed.
+    fn read_file_mat(
+        &self,
+        file_ids: Vec3,
+        bbox: Box3,
+        mat: &mut Mat,
+        mat_pos: Vec3,
+    ) -> Result<()> {
+        let cur_path = self.file_path(file_ids);
+        let cur_file_box = self.file_box(file_ids)?;
+        let cur_box = cur_file_box.intersect(bbox);
+
+        // offsets
+        let cur_src_pos = cur_box.min() - cur_file_box.min();
+        let cur_dst_pos = cur_box.min() - mat_pos;
+
+        // try to open file, unless it is cached
+        let (cached, generation) = self.file_cache.lock().unwrap().check_out(file_ids);
+        let block_generation = self.block_cache.generation(file_ids);
+        let mut file = match cached {
+            Some(file) => file,
+            None => match self.open_file(file_ids, self.use_mmap && !self.header.is_compressed()) {
+                Ok(mut file) => {
+                    if self.block_cache.capacity() > 0 {
+                        let cache = self.block_cache.clone();
+                        file.set_block_cache(cache, file_ids, block_generation);
+                    }
+                    file
+                }
+                Err(ref err) if err.is_not_found() && self.allow_missing_files => {
+                    return mat.fill(cur_box - mat_pos, &self.fill_value);
+                }
+                Err(err) => return Err(err.in_file(&cur_path)),
+            },
+        };
+
+        file.read_mat(cur_src_pos, mat, cur_dst_pos)
+            .map_err(|err| err.in_file(&cur_path))?;
+        self.file_cache
+            .lock()
+            .unwrap()
+            .check_in(file_ids, file, generation);
+        Ok(())
+    }
+
+    fn uncache_file(&self, file_ids: Vec3) {
+        self.file_cache.lock().unwrap().invalidate(file_ids);
+        self.block_cache.invalidate(file_ids);
+    }
+
+    fn write_file_mat(&self, file_ids: Vec3, bbox: Box3, mat: &Mat, mat_pos: Vec3) -> Result<()> {
+        let cur_key = Self::file_key(file_ids);
+
+        // bounding box
+        let cur_file_box = self.file_box(file_ids)?;
+        let cur_box = cur_file_box.intersect(bbox);
+
+        // offsets
+        let cur_src_pos = cur_box.min() - mat_pos;
+        let cur_dst_pos = cur_box.min() - cur_file_box.min();
+
+        let cur_path = match self.storage.local_path(&cur_key) {
+            Some(cur_path) => cur_path,
+            None => {
+                let is_partial = cur_box != cur_file_box;
+                return self
+                    .put_file_mat(&cur_key, is_partial, cur_dst_pos, mat, cur_src_pos)
+                    .map_err(|err| err.in_file(Path::new(&cur_key)));
+            }
+        };
+
+        // serialize writers of this file, also across processes
+        let _lock = File::lock(&cur_path).map_err(|err| err.in_file(&cur_path))?;
+
+        if !self.header.is_compressed() {
+            let mut file = File::open_or_create(&cur_path, &self.header, &self.fill_value)
+                .map_err(|err| err.in_file(&cur_path))?;
+            file.write_mat(cur_dst_pos, mat, cur_src_pos)
+                .map_err(|err| err.in_file(&cur_path))?;
+            return Ok(());
+        }
+
+        // partial writes are merged with the existing file
+        let mut base = match cur_box == cur_file_box {
+            true => None,
+            false => match self.open_file(file_ids, false) {
+                Ok(file) => Some(file),
+                Err(ref err) if err.is_not_found() => None,
+                Err(err) => return Err(err.in_file(&cur_path)),
+            },
+        };
+
+        // writing compressed file into temporary file first
+        let tmp_path = file::tmp_path(&cur_path);
+
+        let result = File::open_or_create(&tmp_path, &self.header, &self.fill_value)
+            .and_then(|mut file| file.write_mat_onto(base.as_mut(), cur_dst_pos, mat, cur_src_pos))
+            .map_err(|err| err.in_file(&tmp_path))
+            // moving compressed file into final file
+            .and_then(|_| File::rename(&tmp_path, &cur_path));
+
+        // do not leave partial files behind
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+
+        result
+    }
+
+    // Writes into a file of a storage backend without a local path. The
+    // file is assembled in memory (on top of its current content, for raw
+    // files and partial writes) and put as a whole.
+    fn put_file_mat(
+        &self,
+        key: &str,
+        is_partial: bool,
+        dst_pos: Vec3,
+        mat: &Mat,
+        src_pos: Vec3,
+    ) -> Result<()> {
+        let file = match self.header.is_compressed() {
+            false => {
+                let buf = match self.storage.get(key) {
+                    Ok(buf) => buf,
+                    Err(ref err) if err.is_not_found() => Vec::new(),
+                    Err(err) => return Err(err),
+                };
+
+                let mut file = File::open_or_create_buffer(buf, &self.header, &self.fill_value)?;
+                file.write_mat(dst_pos, mat, src_pos)?;
+                file
+            }
+            true => {
+                let mut base = match is_partial {
+                    true => match File::open_storage(self.storage.clone(), key) {
+                        Ok(mut file) => {
+                            file.set_fill_value(&self.fill_value)?;
+                            Some(file)
+                        }
+                        Err(ref err) if err.is_not_found() => None,
+                        Err(err) => return Err(err),
+                    },
+                    false => None,
+                };
+
+                let mut file =
+                    File::open_or_create_buffer(Vec::new(), &self.header, &self.fill_value)?;
+                file.write_mat_onto(base.as_mut(), dst_pos, mat, src_pos)?;
+                file
+            }
+        };
+
+        self.storage.put(key, &file.into_buffer().unwrap())
+    }
+
+    // Reads the header and the fill value, which is either part of the
+    // metadata or follows the header.
+    fn read_header(storage: &dyn Storage) -> Result<(Header, Vec<u8>)> {
+        let header_path = Self::object_path(storage, HEADER_FILE_NAME);
+        let buf = storage.get(HEADER_FILE_NAME)?;
+
+        let mut reader = &buf[..];
+        let header = Header::read(&mut reader).map_err(|err| err.in_file(&header_path))?;
+
+        let voxel_size = header.voxel_size as usize;
+        let fill_value = match header.metadata {
+            Some(Metadata {
+                fill_value: Some(ref fill_value),
+                ..
+            }) => fill_value.clone(),
+            _ if reader.is_empty() => vec![0u8; voxel_size],
+            _ => reader.to_vec(),
+        };
+
+        if fill_value.len() != voxel_size {
+            return Err(Error::InvalidHeader {
+                field: "fill_value",
+                value: format!("{} bytes", fill_value.len()),
+            }
+            .in_file(&header_path));
+        }
+
+        Ok((header, fill_value))
+    }
+}
+
+#[test]
+fn test_zstd_roundtrip() {
+    use {BlockType, VoxelType};
+
+    let root = ::std::env::temp_dir().join(format!("wkwrap-zstd-{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+
+    let header = Header {
+        compression_level: 19,
+        ..Header::for_tests(2, 3, 2, BlockType::Zstd, VoxelType::U16)
+    };
+    let dataset = Dataset::create(&root, header).unwrap();
+    assert_eq!(dataset.header().block_type, BlockType::Zstd);
+    assert_eq!(dataset.header().compression_level, 19);
+
+    let shape = Vec3::from(32u32);
+    let mut data: Vec<u8> = (0..shape.product() * 2).map(|i| (i / 7) as u8).collect();
+    let mat = Mat::new(&mut data, shape, 2, VoxelType::U16, false).unwrap();
+    dataset.write_mat(Vec3::from(0u32), &mat).unwrap();
+
+    let mut out = vec![0u8; data.len()];
+    {
+        let mut out_mat = Mat::new(&mut out, shape, 2, VoxelType::U16, false).unwrap();
+        dataset.read_mat(Vec3::from(0u32), &mut out_mat).unwrap();
+    }
+    assert!(out == data);
+
+    // levels are validated per block type
+    let dst_root = root.join("compressed");
+    for &(block_type, level) in &[
+        (BlockType::Zstd, 0),
+        (BlockType::Zstd, 23),
+        (BlockType::LZ4HC, 13),
+        (BlockType::LZ4, 1),
+    ] {
+        match dataset.compress_with(&dst_root, block_type, level) {
+            Err(Error::InvalidArgument(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+    assert!(!dst_root.exists());
+
+    let compressed = dataset
+        .compress_with(&dst_root, BlockType::LZ4HC, 12)
+        .unwrap();
+    assert_eq!(compressed.header().compression_level, 12);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_mmap_read() {
+    use {BlockType, VoxelType};
+
+    let root = ::std::env::temp_dir().join(format!("wkwrap-mmap-{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+
+    let header = Header::for_tests(1, 2, 2, BlockType::Raw, VoxelType::U8);
+    let mut dataset = Dataset::create(&root, header).unwrap();
+
+    let shape = Vec3 { x: 20, y: 9, z: 5 };
+    let mut data: Vec<u8> = (0..shape.product()).map(|i| (i % 251) as u8).collect();
+    let mat = Mat::new(&mut data, shape, 1, VoxelType::U8, false).unwrap();
+    dataset.write_mat(Vec3::from(3u32), &mat).unwrap();
+
+    dataset.set_use_mmap(true);
+    let mut out = vec![0u8; data.len()];
+    {
+        let mut out_mat = Mat::new(&mut out, shape, 1, VoxelType::U8, false).unwrap();
+        dataset.read_mat(Vec3::from(3u32), &mut out_mat).unwrap();
+    }
+    assert!(out == data);
+
+    let file = File::open_mmap(&root.join("z0").join("y0").join("x0.wkw")).unwrap();
+    assert_eq!(file.block_slice(0).unwrap().len(), 64);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_parallel_read_write() {
+    use {BlockType, VoxelType};
+
+    let root = ::std::env::temp_dir().join(format!("wkwrap-parallel-{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+
+    let header = Header {
+        voxel_size: 4,
+        ..Header::for_tests(1, 2, 1, BlockType::Raw, VoxelType::U16)
+    };
+    let mut dataset = Dataset::create(&root, header).unwrap();
+    dataset.set_num_threads(4);
+
+    // spans 4 x 3 x 2 files of 8^3 voxels
+    let shape = Vec3 { x: 27, y: 17, z: 9 };
+    let mut data: Vec<u8> = (0..shape.product() * 4).map(|i| (i % 253) as u8).collect();
+    let mat = Mat::new(&mut data, shape, 4, VoxelType::U16, false).unwrap();
+    dataset.write_mat(Vec3::from(5u32), &mat).unwrap();
+
+    let mut out = vec![0u8; data.len()];
+    {
+        let mut out_mat = Mat::new(&mut out, shape, 4, VoxelType::U16, false).unwrap();
+        dataset.read_mat(Vec3::from(5u32), &mut out_mat).unwrap();
+    }
+    assert!(out == data);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_list_files() {
+    use {BlockType, VoxelType};
+
+    let root = ::std::env::temp_dir().join(format!("wkwrap-list-{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+
+    let header = Header::for_tests(1, 2, 1, BlockType::Raw, VoxelType::U8);
+    let dataset = Dataset::create(&root, header).unwrap();
+
+    assert!(dataset.list_files().unwrap().is_empty());
+    assert_eq!(dataset.bounding_box(false).unwrap(), None);
+    assert_eq!(dataset.bounding_box(true).unwrap(), None);
+
+    // spans 2 x 1 x 1 files of 8^3 voxels
+    let shape = Vec3 { x: 6, y: 3, z: 3 };
+    let mut data: Vec<u8> = (0..shape.product()).map(|i| (i % 13 + 1) as u8).collect();
+    let mat = Mat::new(&mut data, shape, 1, VoxelType::U8, false).unwrap();
+    dataset.write_mat(Vec3::from(5u32), &mat).unwrap();
+
+    // all-zero file
+    let mut zeros = vec![0u8; 8 * 8 * 8];
+    let mat = Mat::new(&mut zeros, Vec3::from(8u32), 1, VoxelType::U8, false).unwrap();
+    dataset.write_mat(Vec3 { x: 0, y: 0, z: 16 }, &mat).unwrap();
+
+    let files = dataset.list_files().unwrap();
+    let file_ids: Vec<Vec3> = files.iter().map(|&(ids, _)| ids).collect();
+    assert_eq!(
+        file_ids,
+        vec![
+            Vec3 { x: 0, y: 0, z: 0 },
+            Vec3 { x: 1, y: 0, z: 0 },
+            Vec3 { x: 0, y: 0, z: 2 },
+        ]
+    );
+    assert_eq!(files[2].1, root.join("z2").join("y0").join("x0.wkw"));
+
+    let max = Vec3 { x: 16, y: 8, z: 24 };
+    let bbox = dataset.bounding_box(false).unwrap().unwrap();
+    assert_eq!(bbox, Box3::from(max));
+
+    // blocks of 4^3 voxels with non-zero data
+    let max = Vec3 { x: 12, y: 8, z: 8 };
+    let bbox = dataset.bounding_box(true).unwrap().unwrap();
+    assert_eq!(bbox, Box3::new(Vec3::from(4u32), max).unwrap());
+
+    // errors name the file
+    fs::write(&files[1].1, b"WKW").unwrap();
+    match dataset.bounding_box(true).unwrap_err() {
+        Error::InFile { ref path, .. } => assert_eq!(path, &files[1].1),
+        err => panic!("Unexpected error {:?}", err),
+    }
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_partial_compressed_write() {
+    use {BlockType, VoxelType};
+
+    let root = ::std::env::temp_dir().join(format!("wkwrap-partial-{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+
+    let header = Header {
+        checksums: true,
+        ..Header::for_tests(2, 2, 1, BlockType::LZ4HC, VoxelType::U8)
+    };
+    let dataset = Dataset::create(&root, header).unwrap();
+
+    // reference volume covering 3 x 1 x 1 files of 8^3 voxels
+    let full = Vec3 { x: 24, y: 8, z: 8 };
+    let mut expected = vec![0u8; full.product() as usize];
+    let mut write = |off: Vec3, shape: Vec3, seed: u8| {
+        let mut data = vec![0u8; shape.product() as usize];
+        for z in 0..shape.z {
+            for y in 0..shape.y {
+                for x in 0..shape.x {
+                    let value = seed.wrapping_add((x + 3 * y + 7 * z) as u8);
+                    data[(x + shape.x * (y + shape.y * z)) as usize] = value;
+
+                    let (x, y, z) = (x + off.x, y + off.y, z + off.z);
+                    expected[(x + full.x * (y + full.y * z)) as usize] = value;
                 }
             }
         }
 
-        Ok(1 as usize)
+        let mat = Mat::new(&mut data, shape, 1, VoxelType::U8, false).unwrap();
+        dataset.write_mat(off, &mat).unwrap();
+    };
+
+    // whole files, then overlapping partial writes
+    write(Vec3::from(0u32), Vec3 { x: 16, y: 8, z: 8 }, 1);
+    write(Vec3 { x: 6, y: 1, z: 3 }, Vec3 { x: 5, y: 3, z: 2 }, 100);
+    write(Vec3 { x: 4, y: 4, z: 4 }, Vec3::from(4u32), 200);
+    write(Vec3 { x: 17, y: 2, z: 5 }, Vec3::from(2u32), 50);
+
+    let mut out = vec![0u8; expected.len()];
+    {
+        let mut out_mat = Mat::new(&mut out, full, 1, VoxelType::U8, false).unwrap();
+        dataset.read_mat(Vec3::from(0u32), &mut out_mat).unwrap();
+    }
+    assert!(out == expected);
+
+    for report in dataset.verify().unwrap() {
+        assert!(report.is_ok(), "{:?}", report);
+    }
+
+    // failed writes do not leave temporary files behind
+    let dir = root.join("z0").join("y0");
+    let path = dir.join("x0.wkw");
+    let offset = File::open(&path).unwrap().header().block_offset(0).unwrap();
+    let mut buf = fs::read(&path).unwrap();
+    buf[offset as usize] ^= 0xff;
+    fs::write(&path, buf).unwrap();
+
+    let mut data = vec![0u8];
+    let mat = Mat::new(&mut data, Vec3::from(1u32), 1, VoxelType::U8, false).unwrap();
+    assert!(dataset.write_mat(Vec3::from(0u32), &mat).is_err());
+    for entry in fs::read_dir(&dir).unwrap() {
+        let name = entry.unwrap().file_name().into_string().unwrap();
+        assert!(!name.ends_with("_tmp"), "{}", name);
     }
 
-    pub(crate) fn read_header(root: &Path) -> Result<Header> {
-        let mut header_path = PathBuf::from(root);
-        header_path.push(HEADER_FILE_NAME);
+    fs::remove_dir_all(&root).unwrap();
+}
 
-        let mut header_file_opt = fs::File::open(&header_path);
-        let header_file = match header_file_opt.as_mut() {
-            Ok(header_file) => header_file,
-            Err(_) => return Err(format!("Could not open header file {:?}", header_path)),
+#[test]
+fn test_memory_storage() {
+    use storage::MemoryStorage;
+    use VoxelType;
+
+    for &block_type in &[BlockType::Raw, BlockType::LZ4] {
+        let header = Header {
+            checksums: true,
+            ..Header::for_tests(2, 2, 2, block_type, VoxelType::U8)
         };
 
-        Header::read(header_file)
+        let storage = Arc::new(MemoryStorage::new());
+        let mut dataset = Dataset::create_with_storage(storage.clone(), header.clone()).unwrap();
+        dataset.set_num_threads(2);
+        assert!(Dataset::create_with_storage(storage.clone(), header).is_err());
+
+        // two partial writes into the same file, and one into another file
+        let shape = Vec3 { x: 12, y: 8, z: 8 };
+        let mut data: Vec<u8> = (0..shape.product()).map(|i| (i % 251) as u8 + 1).collect();
+        let mat = Mat::new(&mut data, shape, 1, VoxelType::U8, false).unwrap();
+        dataset.write_mat(Vec3::from(2u32), &mat).unwrap();
+        dataset.write_mat(Vec3 { x: 6, y: 0, z: 0 }, &mat).unwrap();
+
+        assert!(storage.exists("z0/y0/x0.wkw").unwrap());
+        let files = dataset.list_files().unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[1].1, PathBuf::from("z0/y0/x1.wkw"));
+
+        let dataset = Dataset::with_storage(storage.clone()).unwrap();
+        let mut out = vec![0u8; data.len()];
+        {
+            let mut out_mat = Mat::new(&mut out, shape, 1, VoxelType::U8, false).unwrap();
+            dataset.read_mat(Vec3::from(2u32), &mut out_mat).unwrap();
+        }
+
+        // voxel (6, 2, 2) was overwritten by the second write
+        assert_eq!(out[0], data[0]);
+        assert_eq!(out[4], data[(2 * 8 + 2) * 12]);
+
+        let bbox = dataset.bounding_box(true).unwrap().unwrap();
+        assert_eq!(
+            bbox,
+            Box3::new(
+                Vec3::from(0u32),
+                Vec3 {
+                    x: 20,
+                    y: 12,
+                    z: 12
+                }
+            )
+            .unwrap()
+        );
+        assert!(dataset
+            .verify()
+            .unwrap()
+            .iter()
+            .all(|report| report.is_ok()));
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn test_file_cache() {
+    use VoxelType;
+
+    let root = ::std::env::temp_dir().join(format!("wkwrap-file-cache-{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+
+    let header = Header::for_tests(1, 2, 2, BlockType::LZ4, VoxelType::U8);
+    let mut dataset = Dataset::create(&root, header).unwrap();
+    dataset.set_file_cache_capacity(1);
+    assert_eq!(dataset.file_cache_capacity(), 1);
+
+    let shape = Vec3::from(16u32);
+    let mut data = vec![3u8; shape.product() as usize];
+    let mat = Mat::new(&mut data, shape, 1, VoxelType::U8, false).unwrap();
+    dataset.write_mat(Vec3::from(0u32), &mat).unwrap();
+
+    let read = |dataset: &Dataset| {
+        let mut out = vec![0u8; 8];
+        {
+            let mut out_mat =
+                Mat::new(&mut out, Vec3::from(2u32), 1, VoxelType::U8, false).unwrap();
+            dataset.read_mat(Vec3::from(1u32), &mut out_mat).unwrap();
+        }
+        out[0]
+    };
+
+    // the cached file remains readable after it has been unlinked
+    assert_eq!(read(&dataset), 3);
+    let file_path = root.join("z0").join("y0").join("x0.wkw");
+    fs::remove_file(&file_path).unwrap();
+    assert_eq!(read(&dataset), 3);
+
+    // writing through a clone invalidates the cached file
+    let mut data = vec![5u8; shape.product() as usize];
+    let mat = Mat::new(&mut data, shape, 1, VoxelType::U8, false).unwrap();
+    dataset.clone().write_mat(Vec3::from(0u32), &mat).unwrap();
+    assert_eq!(read(&dataset), 5);
+
+    // without cache, the file is opened anew
+    dataset.set_file_cache_capacity(0);
+    fs::remove_file(&file_path).unwrap();
+    assert_eq!(read(&dataset), 0);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_block_cache() {
+    use storage::MemoryStorage;
+    use VoxelType;
+
+    let header = Header::for_tests(1, 2, 2, BlockType::LZ4, VoxelType::U8);
+
+    let storage = Arc::new(MemoryStorage::new());
+    let mut dataset = Dataset::create_with_storage(storage, header).unwrap();
+    dataset.set_block_cache_capacity(1 << 20);
+
+    let shape = Vec3::from(16u32);
+    let mut data = vec![3u8; shape.product() as usize];
+    let mat = Mat::new(&mut data, shape, 1, VoxelType::U8, false).unwrap();
+    dataset.write_mat(Vec3::from(0u32), &mat).unwrap();
+
+    // the box covers 2 x 2 x 2 blocks
+    let read = |dataset: &Dataset| {
+        let mut out = vec![0u8; 8];
+        {
+            let mut out_mat =
+                Mat::new(&mut out, Vec3::from(2u32), 1, VoxelType::U8, false).unwrap();
+            dataset.read_mat(Vec3::from(3u32), &mut out_mat).unwrap();
+        }
+        out[0]
+    };
+
+    assert_eq!(read(&dataset), 3);
+    assert_eq!(
+        dataset.block_cache_stats(),
+        CacheStats {
+            hits: 0,
+            misses: 8,
+            size: 512
+        }
+    );
+    assert_eq!(read(&dataset.clone()), 3);
+    assert_eq!(
+        dataset.block_cache_stats(),
+        CacheStats {
+            hits: 8,
+            misses: 8,
+            size: 512
+        }
+    );
+
+    // blocks cached before a write are not read anymore
+    let mut data = vec![5u8; shape.product() as usize];
+    let mat = Mat::new(&mut data, shape, 1, VoxelType::U8, false).unwrap();
+    dataset.write_mat(Vec3::from(0u32), &mat).unwrap();
+    assert_eq!(read(&dataset), 5);
+    assert_eq!(
+        dataset.block_cache_stats(),
+        CacheStats {
+            hits: 8,
+            misses: 16,
+            size: 1024
+        }
+    );
+}
+
+#[test]
+fn test_concurrent_writers() {
+    use VoxelType;
+
+    let root = ::std::env::temp_dir().join(format!("wkwrap-writers-{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+
+    for &block_type in &[BlockType::Raw, BlockType::LZ4] {
+        let path = root.join(format!("{:?}", block_type));
+        let header = Header {
+            checksums: true,
+            ..Header::for_tests(2, 2, 2, block_type, VoxelType::U8)
+        };
+        Dataset::create(&path, header).unwrap();
+
+        // each writer has its own dataset and writes one plane of blocks
+        // of the same file
+        thread::scope(|scope| {
+            for z in 0..4u32 {
+                let path = &path;
+                scope.spawn(move || {
+                    let dataset = Dataset::new(path).unwrap();
+                    let shape = Vec3 { x: 16, y: 16, z: 4 };
+                    let mut data = vec![z as u8 + 1; shape.product() as usize];
+                    let mat = Mat::new(&mut data, shape, 1, VoxelType::U8, false).unwrap();
+                    dataset
+                        .write_mat(
+                            Vec3 {
+                                x: 0,
+                                y: 0,
+                                z: 4 * z,
+                            },
+                            &mat,
+                        )
+                        .unwrap();
+                });
+            }
+        });
+
+        let dataset = Dataset::new(&path).unwrap();
+        let shape = Vec3::from(16u32);
+        let mut out = vec![0u8; shape.product() as usize];
+        {
+            let mut out_mat = Mat::new(&mut out, shape, 1, VoxelType::U8, false).unwrap();
+            dataset.read_mat(Vec3::from(0u32), &mut out_mat).unwrap();
+        }
+
+        for (idx, &value) in out.iter().enumerate() {
+            assert_eq!(value as usize, idx / (16 * 16 * 4) + 1);
+        }
+        assert!(dataset
+            .verify()
+            .unwrap()
+            .iter()
+            .all(|report| report.is_ok()));
+
+        // only the data file and its lock file remain
+        let mut names: Vec<_> = fs::read_dir(path.join("z0").join("y0"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["x0.wkw", "x0.wkw.lock"]);
+    }
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_fill_value() {
+    use storage::MemoryStorage;
+    use VoxelType;
+
+    let cases = [
+        (1, BlockType::Raw),
+        (1, BlockType::LZ4),
+        (2, BlockType::LZ4),
+    ];
+    for &(version, block_type) in &cases {
+        let header = Header::for_tests(version, 2, 1, block_type, VoxelType::U16);
+
+        let storage = Arc::new(MemoryStorage::new());
+        let mut dataset = Dataset::create_with_storage(storage.clone(), header).unwrap();
+        assert_eq!(dataset.fill_value(), &[0, 0]);
+        assert!(dataset.set_fill_value(&[7]).is_err());
+        dataset.set_fill_value(&7u16.to_le_bytes()).unwrap();
+
+        let mut dataset = Dataset::with_storage(storage.clone()).unwrap();
+        assert_eq!(dataset.fill_value(), &7u16.to_le_bytes());
+
+        // partial write into the first block of file (0, 0, 0)
+        let mut data = vec![0u8; 2 * 8];
+        let mat = Mat::new(&mut data, Vec3::from(2u32), 2, VoxelType::U16, false).unwrap();
+        dataset.write_mat(Vec3::from(0u32), &mat).unwrap();
+
+        // version-1 files do not carry the fill value, so that readers of
+        // single files assume zero for blocks which are not stored
+        let mut file = File::open_storage(storage.clone(), "z0/y0/x0.wkw").unwrap();
+        if block_type == BlockType::LZ4 {
+            for block_idx in 1..8 {
+                let len = file.header().block_size_on_disk(block_idx).unwrap();
+                assert_eq!(len > 0, version == 1);
+            }
+        }
+
+        let mut out = vec![0u8; 8 * 8 * 8 * 2];
+        {
+            let mut out_mat =
+                Mat::new(&mut out, Vec3::from(8u32), 2, VoxelType::U16, false).unwrap();
+            file.read_mat(Vec3::from(0u32), &mut out_mat, Vec3::from(0u32))
+                .unwrap();
+        }
+        assert_eq!(&out[..6], &[0, 0, 0, 0, 7, 0]);
+        assert_eq!(&out[(out.len() - 2)..], &[7, 0]);
+
+        // file (1, 0, 0) is missing
+        let shape = Vec3 { x: 16, y: 8, z: 8 };
+        let mut out = vec![0xffu8; shape.product() as usize * 2];
+        {
+            let mut out_mat = Mat::new(&mut out, shape, 2, VoxelType::U16, false).unwrap();
+            dataset.read_mat(Vec3::from(0u32), &mut out_mat).unwrap();
+        }
+
+        let values: Vec<u16> = out
+            .chunks(2)
+            .map(|voxel| u16::from_le_bytes([voxel[0], voxel[1]]))
+            .collect();
+        assert_eq!(values[0], 0);
+        assert_eq!(values[2], 7);
+        assert_eq!(values[8], 7);
+        assert!(values[(7 * 8 + 7) * 16..].iter().all(|&value| value == 7));
+
+        dataset.set_allow_missing_files(false);
+        let mut out_mat = Mat::new(&mut out, shape, 2, VoxelType::U16, false).unwrap();
+        let err = dataset
+            .read_mat(Vec3::from(0u32), &mut out_mat)
+            .unwrap_err();
+        assert!(err.is_not_found());
+    }
+}
+
+#[test]
+fn test_header_v2() {
+    use storage::MemoryStorage;
+    use VoxelType;
+
+    let header = Header {
+        checksums: true,
+        metadata: Some(Metadata {
+            voxel_size_nm: Some([1.0, 1.0, 2.5]),
+            ..Metadata::default()
+        }),
+        ..Header::for_tests(2, 2, 1, BlockType::LZ4, VoxelType::U8)
+    };
+
+    let storage = Arc::new(MemoryStorage::new());
+    let mut dataset = Dataset::create_with_storage(storage.clone(), header).unwrap();
+    dataset.set_fill_value(&[9]).unwrap();
+
+    let dataset = Dataset::with_storage(storage.clone()).unwrap();
+    let metadata = dataset.header().metadata.clone().unwrap();
+    assert_eq!(dataset.header().version, 2);
+    assert_eq!(dataset.fill_value(), &[9]);
+    assert_eq!(metadata.voxel_size_nm, Some([1.0, 1.0, 2.5]));
+    assert!(metadata.creation_tool.unwrap().starts_with("wkwrap "));
+
+    let mut data = vec![1u8; 8];
+    let mat = Mat::new(&mut data, Vec3::from(2u32), 1, VoxelType::U8, false).unwrap();
+    dataset.write_mat(Vec3::from(0u32), &mat).unwrap();
+
+    // files carry the metadata, including the fill value of unstored blocks
+    let mut file = File::open_storage(storage.clone(), "z0/y0/x0.wkw").unwrap();
+    assert_eq!(file.header().metadata, dataset.header().metadata);
+    assert_eq!(file.header().block_size_on_disk(7).unwrap(), 0);
+
+    let mut out = vec![0u8; 2];
+    let mut out_mat =
+        Mat::new(&mut out, Vec3 { x: 2, y: 1, z: 1 }, 1, VoxelType::U8, false).unwrap();
+    file.read_mat(Vec3::from(1u32), &mut out_mat, Vec3::from(0u32))
+        .unwrap();
+    assert_eq!(out, vec![1, 9]);
+
+    assert!(dataset
+        .verify()
+        .unwrap()
+        .iter()
+        .all(|report| report.is_ok()));
+}
+
+#[test]
+fn test_non_cubic() {
+    use storage::MemoryStorage;
+    use VoxelType;
+
+    let mut header = Header {
+        block_shape_log2: Vec3 { x: 2, y: 2, z: 1 },
+        file_shape_log2: Vec3 { x: 1, y: 2, z: 0 },
+        checksums: true,
+        ..Header::for_tests(3, 0, 0, BlockType::Raw, VoxelType::U16)
+    };
+    assert_eq!(header.file_shape_vx(), Vec3 { x: 8, y: 16, z: 2 });
+
+    let pos = Vec3 { x: 3, y: 1, z: 1 };
+    let shape = Vec3 { x: 13, y: 21, z: 5 };
+    let mut data: Vec<u8> = (0..shape.product())
+        .flat_map(|i| ((i * 7 % 65521) as u16 + 1).to_le_bytes())
+        .collect();
+    let expected_data = data.clone();
+    let mat = Mat::new(&mut data, shape, 2, VoxelType::U16, false).unwrap();
+
+    for &block_type in &[BlockType::Raw, BlockType::LZ4] {
+        header.block_type = block_type;
+        let storage = Arc::new(MemoryStorage::new());
+        Dataset::create_with_storage(storage.clone(), header.clone()).unwrap();
+
+        let dataset = Dataset::with_storage(storage.clone()).unwrap();
+        assert_eq!(dataset.header().block_shape_log2, header.block_shape_log2);
+        assert_eq!(dataset.header().file_shape_log2, header.file_shape_log2);
+        dataset.write_mat(pos, &mat).unwrap();
+        assert_eq!(dataset.list_files().unwrap().len(), 2 * 2 * 3);
+
+        let read_shape = shape + pos + 1;
+        let mut out = vec![0u8; read_shape.product() as usize * 2];
+        {
+            let mut out_mat = Mat::new(&mut out, read_shape, 2, VoxelType::U16, false).unwrap();
+            dataset.read_mat(Vec3::from(0u32), &mut out_mat).unwrap();
+        }
+
+        for z in 0..read_shape.z {
+            for y in 0..read_shape.y {
+                for x in 0..read_shape.x {
+                    let cur = Vec3 { x, y, z };
+                    let out_idx = (x + read_shape.x * (y + read_shape.y * z)) as usize;
+                    let expected = match cur.elem_max(pos) == cur && cur < pos + shape {
+                        true => {
+                            let rel = cur - pos;
+                            let idx = (rel.x + shape.x * (rel.y + shape.y * rel.z)) as usize;
+                            &expected_data[(2 * idx)..(2 * idx + 2)]
+                        }
+                        false => &[0, 0][..],
+                    };
+                    assert_eq!(&out[(2 * out_idx)..(2 * out_idx + 2)], expected);
+                }
+            }
+        }
+
+        assert!(dataset
+            .verify()
+            .unwrap()
+            .iter()
+            .all(|report| report.is_ok()));
+    }
+
+    // non-cubic blocks and files need a version-3 header
+    header.version = 2;
+    let storage = Arc::new(MemoryStorage::new());
+    assert!(Dataset::create_with_storage(storage, header.clone()).is_err());
+
+    // lengths which do not fit into the header are rejected up front
+    header.version = 3;
+    header.block_shape_log2.y = 16;
+    let root = ::std::env::temp_dir().join(format!("wkwrap-non-cubic-{}", ::std::process::id()));
+    match Dataset::create(&root, header) {
+        Err(Error::InvalidArgument(_)) => assert!(!root.exists()),
+        other => panic!("Unexpected result {:?}", other.map(|_| ())),
     }
 }
@@ -0,0 +1,237 @@
+use std::path::{Path, PathBuf};
+use std::{error, fmt, io};
+use {Vec3, VoxelType};
+
+#[derive(Debug)]
+pub enum Error {
+    Io {
+        context: &'static str,
+        path: Option<PathBuf>,
+        source: io::Error,
+    },
+    Rename {
+        from: PathBuf,
+        to: PathBuf,
+        source: io::Error,
+    },
+    InvalidHeader {
+        field: &'static str,
+        value: String,
+    },
+    CorruptJumpTable {
+        block_idx: u64,
+    },
+    ChecksumMismatch {
+        block_idx: u64,
+        expected: u32,
+        found: u32,
+    },
+    Compression(&'static str),
+    Decompression(String),
+    OutOfBounds(&'static str),
+    VoxelTypeMismatch {
+        expected: VoxelType,
+        found: VoxelType,
+    },
+    VoxelSizeMismatch {
+        expected: usize,
+        found: usize,
+    },
+    ShapeMismatch {
+        expected: Vec3,
+        found: Vec3,
+    },
+    AlreadyExists(PathBuf),
+    InvalidArgument(String),
+    InFile {
+        path: PathBuf,
+        source: Box<Error>,
+    },
+}
+
+impl Error {
+    pub(crate) fn io(context: &'static str, source: io::Error) -> Error {
+        Error::Io {
+            context,
+            path: None,
+            source,
+        }
+    }
+
+    pub(crate) fn io_at(context: &'static str, path: &Path, source: io::Error) -> Error {
+        Error::Io {
+            context,
+            path: Some(path.to_owned()),
+            source,
+        }
+    }
+
+    pub(crate) fn rename(from: &Path, to: &Path, source: io::Error) -> Error {
+        Error::Rename {
+            from: from.to_owned(),
+            to: to.to_owned(),
+            source,
+        }
+    }
+
+    pub(crate) fn in_file(self, path: &Path) -> Error {
+        Error::InFile {
+            path: path.to_owned(),
+            source: Box::new(self),
+        }
+    }
+
+    pub(crate) fn invalid<S: Into<String>>(msg: S) -> Error {
+        Error::InvalidArgument(msg.into())
+    }
+
+    /// Innermost error, with all `InFile` contexts stripped.
+    pub fn root(&self) -> &Error {
+        match *self {
+            Error::InFile { ref source, .. } => source.root(),
+            ref other => other,
+        }
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        match *self.root() {
+            Error::Io { ref source, .. } | Error::Rename { ref source, .. } => {
+                source.kind() == io::ErrorKind::NotFound
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io {
+                context,
+                path: Some(ref path),
+                ref source,
+            } => write!(f, "{} {:?}: {}", context, path, source),
+            Error::Io {
+                context,
+                path: None,
+                ref source,
+            } => write!(f, "{}: {}", context, source),
+            Error::Rename {
+                ref from,
+                ref to,
+                ref source,
+            } => write!(
+                f,
+                "Could not rename file {:?} to {:?}: {}",
+                from, to, source
+            ),
+            Error::InvalidHeader { field, ref value } => {
+                write!(f, "Header field {} has invalid value {}", field, value)
+            }
+            Error::CorruptJumpTable { block_idx } => {
+                write!(f, "Corrupt jump table at block {}", block_idx)
+            }
+            Error::ChecksumMismatch {
+                block_idx,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Checksum mismatch in block {} ({:08x} != {:08x})",
+                block_idx, found, expected
+            ),
+            Error::Compression(msg) => write!(f, "Compression failed: {}", msg),
+            Error::Decompression(ref msg) => write!(f, "Decompression failed: {}", msg),
+            Error::OutOfBounds(msg) => write!(f, "{} out of bounds", msg),
+            Error::VoxelTypeMismatch { expected, found } => {
+                write!(f, "Invalid voxel type {:?} != {:?}", found, expected)
+            }
+            Error::VoxelSizeMismatch { expected, found } => {
+                write!(f, "Invalid voxel size {} != {}", found, expected)
+            }
+            Error::ShapeMismatch { expected, found } => {
+                write!(f, "Invalid shape {:?} != {:?}", found, expected)
+            }
+            Error::AlreadyExists(ref path) => write!(f, "{:?} already exists", path),
+            Error::InvalidArgument(ref msg) => write!(f, "{}", msg),
+            Error::InFile {
+                ref path,
+                ref source,
+            } => write!(f, "Error in file {:?}: {}", path, source),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io { ref source, .. } | Error::Rename { ref source, .. } => Some(source),
+            Error::InFile { ref source, .. } => Some(&**source),
+            _ => None,
+        }
+    }
+}
+
+// the MATLAB bindings report errors as plain strings
+impl From<Error> for String {
+    fn from(err: Error) -> String {
+        err.to_string()
+    }
+}
+
+#[test]
+fn test_errors() {
+    use std::error::Error as StdError;
+
+    let not_found = || io::Error::new(io::ErrorKind::NotFound, "gone");
+
+    let err = Error::io_at("Could not open WKW file", Path::new("x0.wkw"), not_found());
+    assert_eq!(err.to_string(), "Could not open WKW file \"x0.wkw\": gone");
+    assert!(err.is_not_found());
+    assert!(err.source().is_some());
+
+    // renames report both paths
+    let err = Error::rename(Path::new("a_tmp"), Path::new("a"), not_found());
+    assert_eq!(
+        err.to_string(),
+        "Could not rename file \"a_tmp\" to \"a\": gone"
+    );
+    assert!(err.is_not_found());
+
+    // file contexts are stripped by `root`
+    let err = Error::CorruptJumpTable { block_idx: 3 }
+        .in_file(Path::new("x0.wkw"))
+        .in_file(Path::new("ds"));
+    assert_eq!(
+        err.to_string(),
+        "Error in file \"ds\": Error in file \"x0.wkw\": Corrupt jump table at block 3"
+    );
+    match *err.root() {
+        Error::CorruptJumpTable { block_idx } => assert_eq!(block_idx, 3),
+        ref other => panic!("Unexpected error {:?}", other),
+    }
+    assert!(!err.is_not_found());
+    assert!(err.source().is_some());
+
+    let err = Error::ChecksumMismatch {
+        block_idx: 1,
+        expected: 0xabcd,
+        found: 0x1234,
+    };
+    assert_eq!(
+        err.to_string(),
+        "Checksum mismatch in block 1 (00001234 != 0000abcd)"
+    );
+    assert!(err.source().is_none());
+
+    let err = Error::VoxelTypeMismatch {
+        expected: VoxelType::U8,
+        found: VoxelType::F32,
+    };
+    assert_eq!(err.to_string(), "Invalid voxel type F32 != U8");
+    assert_eq!(
+        Error::OutOfBounds("Block index").to_string(),
+        "Block index out of bounds"
+    );
+    assert_eq!(String::from(Error::invalid("Bad")), "Bad");
+}
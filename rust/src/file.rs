@@ -1,20 +1,172 @@
+use cache::BlockCache;
+use crc32c;
 use lz4;
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::{fs, path};
-use {BlockType, Box3, Header, Iter, Mat, Morton, Result, Vec3};
+use mat::MatView;
+use memmap2::{Mmap, MmapOptions};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::{cmp, fs, mem, path};
+use storage::Storage;
+use zstd;
+use {BlockType, Box3, Error, Header, Iter, Mat, Metadata, Morton, Problem, Report, Result, Vec3};
+
+// Underlying bytes of a WKW file. Objects of a storage backend are read
+// through range requests and cannot be written; files to be put into a
+// backend are assembled in a buffer.
+#[derive(Debug)]
+enum Handle {
+    Disk(fs::File),
+    Buffer(Cursor<Vec<u8>>),
+    Storage {
+        storage: Arc<dyn Storage>,
+        key: String,
+        pos: u64,
+        // byte ranges fetched ahead of time, as (offset, data)
+        prefetched: Vec<(u64, Vec<u8>)>,
+    },
+}
+
+impl Handle {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        match *self {
+            Handle::Disk(ref file) => file.set_len(len),
+            Handle::Buffer(ref mut cursor) => {
+                cursor.get_mut().resize(len as usize, 0);
+                Ok(())
+            }
+            Handle::Storage { .. } => Err(read_only()),
+        }
+    }
+}
+
+fn read_only() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "Object is read-only")
+}
+
+fn storage_error(err: Error) -> io::Error {
+    match err {
+        Error::Io { source, .. } | Error::Rename { source, .. } => source,
+        err => io::Error::other(err.to_string()),
+    }
+}
+
+impl Read for Handle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Handle::Disk(ref mut file) => file.read(buf),
+            Handle::Buffer(ref mut cursor) => cursor.read(buf),
+            Handle::Storage {
+                ref storage,
+                ref key,
+                ref mut pos,
+                ref prefetched,
+            } => {
+                let cached = prefetched
+                    .iter()
+                    .find(|&&(off, ref data)| off <= *pos && *pos < off + data.len() as u64);
+
+                if let Some(&(off, ref data)) = cached {
+                    let data = &data[((*pos - off) as usize)..];
+                    let len = cmp::min(buf.len(), data.len());
+                    buf[..len].copy_from_slice(&data[..len]);
+                    *pos += len as u64;
+                    return Ok(len);
+                }
+
+                let range = *pos..(*pos + buf.len() as u64);
+                let data = storage.get_range(key, range).map_err(storage_error)?;
+                // do not trust backends to stick to the range
+                let len = cmp::min(buf.len(), data.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                *pos += len as u64;
+                Ok(len)
+            }
+        }
+    }
+}
+
+impl Write for Handle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Handle::Disk(ref mut file) => file.write(buf),
+            Handle::Buffer(ref mut cursor) => cursor.write(buf),
+            Handle::Storage { .. } => Err(read_only()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Handle::Disk(ref mut file) => file.flush(),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Seek for Handle {
+    fn seek(&mut self, from: SeekFrom) -> io::Result<u64> {
+        match *self {
+            Handle::Disk(ref mut file) => file.seek(from),
+            Handle::Buffer(ref mut cursor) => cursor.seek(from),
+            Handle::Storage {
+                ref storage,
+                ref key,
+                ref mut pos,
+                ..
+            } => {
+                let new_pos = match from {
+                    SeekFrom::Start(off) => Some(off),
+                    SeekFrom::Current(off) => pos.checked_add_signed(off),
+                    SeekFrom::End(off) => storage
+                        .size(key)
+                        .map_err(storage_error)?
+                        .checked_add_signed(off),
+                };
+
+                *pos = new_pos.ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "Invalid seek position")
+                })?;
+                Ok(*pos)
+            }
+        }
+    }
+}
+
+fn with_suffix(path: &path::Path, suffix: &str) -> path::PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path::PathBuf::from(path)
+}
+
+/// Path for a temporary file next to `path`, which is unique across
+/// processes and threads, e.g. `x0.wkw.<pid>-<n>_tmp`.
+pub(crate) fn tmp_path(path: &path::Path) -> path::PathBuf {
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    with_suffix(path, &format!(".{}-{}_tmp", ::std::process::id(), id))
+}
 
 #[derive(Debug)]
 pub struct File {
-    file: fs::File,
+    file: Handle,
     header: Header,
     block_idx: Option<u64>,
     disk_block_buf: Option<Box<[u8]>>,
+    mmap: Option<Mmap>,
+    // cache of decompressed blocks, the coordinates of this file, and the
+    // generation of its blocks in the cache when the file was opened
+    block_cache: Option<(Arc<BlockCache>, Vec3, u64)>,
+    // voxel value of new files outside of the written region
+    fill_value: Vec<u8>,
+    // voxel value of compressed blocks which are not stored
+    empty_block_value: Vec<u8>,
 }
 
 impl File {
-    fn new(file: fs::File, header: Header) -> File {
+    fn new(file: Handle, header: Header) -> File {
         let block_buf = match header.block_type {
-            BlockType::LZ4 | BlockType::LZ4HC => {
+            BlockType::LZ4 | BlockType::LZ4HC | BlockType::Zstd => {
                 let buf_size = header.max_block_size_on_disk();
                 let buf_vec = vec![0u8; buf_size];
                 Some(buf_vec.into_boxed_slice())
@@ -22,37 +174,147 @@ impl File {
             _ => None,
         };
 
+        // readers without the dataset header, e.g. of version-1 files,
+        // assume that blocks which are not stored are zero
+        let voxel_size = header.voxel_size as usize;
+        let empty_block_value = match header.metadata {
+            Some(Metadata {
+                fill_value: Some(ref fill_value),
+                ..
+            }) if fill_value.len() == voxel_size => fill_value.clone(),
+            _ => vec![0u8; voxel_size],
+        };
+
         File {
             file,
             header,
             block_idx: None,
             disk_block_buf: block_buf,
+            mmap: None,
+            block_cache: None,
+            fill_value: empty_block_value.clone(),
+            empty_block_value,
         }
     }
 
     pub fn open(path: &path::Path) -> Result<File> {
-        let mut file =
-            fs::File::open(path).or(Err(format!("Could not open WKW file {:?}", path)))?;
+        let mut file = fs::File::open(path)
+            .map_err(|err| Error::io_at("Could not open WKW file", path, err))?;
+        let header = Header::read(&mut file)?;
+        Ok(Self::new(Handle::Disk(file), header))
+    }
+
+    /// Opens object `key` of a storage backend for reading. Data is
+    /// fetched block by block, as it is needed.
+    pub fn open_storage(storage: Arc<dyn Storage>, key: &str) -> Result<File> {
+        let mut file = Handle::Storage {
+            storage,
+            key: String::from(key),
+            pos: 0,
+            prefetched: Vec::new(),
+        };
+
         let header = Header::read(&mut file)?;
         Ok(Self::new(file, header))
     }
 
-    pub(crate) fn open_or_create(path: &path::Path, header: &Header) -> Result<File> {
+    /// Opens a raw WKW file for reading through a memory mapping.
+    ///
+    /// Blocks are copied straight from the mapping into the destination
+    /// matrix, without going through an intermediate buffer.
+    pub fn open_mmap(path: &path::Path) -> Result<File> {
+        let mut file = Self::open(path)?;
+
+        if file.header.block_type != BlockType::Raw {
+            return Err(Error::invalid(format!(
+                "Memory mapping is not supported for block type {:?}",
+                file.header.block_type
+            )));
+        }
+
+        // The mapping is read-only. Its contents are undefined, though, and
+        // accesses may fail with SIGBUS, if another process truncates or
+        // rewrites the file while it is mapped (see `Dataset::set_use_mmap`).
+        let mmap = match file.file {
+            Handle::Disk(ref disk_file) => unsafe { MmapOptions::new().map(disk_file) },
+            _ => return Err(Error::invalid("Memory mapping requires a file on disk")),
+        }
+        .map_err(|err| Error::io_at("Could not memory map WKW file", path, err))?;
+
+        let expected_len = file.header.size_on_disk() + file.header.file_size();
+        if mmap.len() < expected_len {
+            return Err(Error::invalid(format!(
+                "Raw file {:?} is truncated ({} < {} bytes)",
+                path,
+                mmap.len(),
+                expected_len
+            )));
+        }
+
+        file.mmap = Some(mmap);
+        Ok(file)
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Returns the on-disk bytes of a block of a memory mapped file.
+    pub fn block_slice(&self, block_idx: u64) -> Result<&[u8]> {
+        let mmap = match self.mmap {
+            Some(ref mmap) => mmap,
+            None => return Err(Error::invalid("File is not memory mapped")),
+        };
+
+        let offset = self.header.block_offset(block_idx)? as usize;
+        let block = &mmap[offset..(offset + self.header.block_size())];
+        Self::check_block(&self.header, block_idx, block)?;
+
+        Ok(block)
+    }
+
+    /// Opens the file at `path`, or creates a new one from `header`. Blocks
+    /// of new raw files are initialized with `fill_value`.
+    pub(crate) fn open_or_create(
+        path: &path::Path,
+        header: &Header,
+        fill_value: &[u8],
+    ) -> Result<File> {
         // create parent directory, if needed
         if let Some(parent) = path.parent() {
-            fs::create_dir_all(parent).or(Err(format!(
-                "Could not create parent directory {:?}",
-                parent
-            )))?;
+            fs::create_dir_all(parent)
+                .map_err(|err| Error::io_at("Could not create parent directory", parent, err))?;
         }
 
         let mut open_opts = fs::OpenOptions::new();
         open_opts.read(true).write(true).create(true);
 
-        let mut file = open_opts
+        let file = open_opts
             .open(path)
-            .or(Err(format!("Could not open file {:?}", &path)))?;
+            .map_err(|err| Error::io_at("Could not open file", path, err))?;
+
+        Self::open_or_create_handle(Handle::Disk(file), header, fill_value)
+    }
+
+    /// Opens the file in `buf`, or creates a new one from `header` if `buf`
+    /// is empty. The file can be written and its bytes retrieved with
+    /// `into_buffer`.
+    pub(crate) fn open_or_create_buffer(
+        buf: Vec<u8>,
+        header: &Header,
+        fill_value: &[u8],
+    ) -> Result<File> {
+        Self::open_or_create_handle(Handle::Buffer(Cursor::new(buf)), header, fill_value)
+    }
 
+    pub(crate) fn into_buffer(self) -> Option<Vec<u8>> {
+        match self.file {
+            Handle::Buffer(cursor) => Some(cursor.into_inner()),
+            _ => None,
+        }
+    }
+
+    fn open_or_create_handle(mut file: Handle, header: &Header, fill_value: &[u8]) -> Result<File> {
         // check if file was created
         let (header, created) = match Header::read(&mut file) {
             Ok(header) => (header, false),
@@ -61,9 +323,27 @@ impl File {
 
         // create structure
         let mut file = Self::new(file, header);
+        file.set_fill_value(fill_value)?;
 
         if created {
             file.truncate()?;
+
+            // truncated raw files are zero-filled
+            if !file.header.is_compressed() && file.fill_value.iter().any(|&byte| byte != 0) {
+                let block: Vec<u8> = file
+                    .fill_value
+                    .iter()
+                    .cycle()
+                    .take(file.header.block_size())
+                    .cloned()
+                    .collect();
+
+                file.seek_block(0)?;
+                for _idx in 0..file.header.file_vol() {
+                    file.write_block(&block)?;
+                }
+            }
+
             file.write_header()?;
         }
 
@@ -71,7 +351,35 @@ impl File {
     }
 
     pub(crate) fn rename(old_path: &path::Path, new_path: &path::Path) -> Result<()> {
-        fs::rename(old_path, new_path).or_else(|err| Err(err.to_string()))
+        fs::rename(old_path, new_path).map_err(|err| Error::rename(old_path, new_path, err))
+    }
+
+    /// Takes an advisory, exclusive lock for writing the file at `path`.
+    /// Blocks until the lock is available and holds it until the returned
+    /// handle is dropped.
+    ///
+    /// The lock is taken on `<path>.lock` rather than on the file itself,
+    /// because compressed files are replaced by renaming. Lock files are
+    /// left in place; removing them would allow two writers to hold locks
+    /// on different inodes.
+    pub(crate) fn lock(path: &path::Path) -> Result<fs::File> {
+        let lock_path = with_suffix(path, ".lock");
+        if let Some(parent) = lock_path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|err| Error::io_at("Could not create parent directory", parent, err))?;
+        }
+
+        let lock_file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_path)
+            .map_err(|err| Error::io_at("Could not open lock file", &lock_path, err))?;
+        lock_file
+            .lock()
+            .map_err(|err| Error::io_at("Could not lock file", &lock_path, err))?;
+
+        Ok(lock_file)
     }
 
     pub(crate) fn read_mat(
@@ -80,21 +388,20 @@ impl File {
         dst_mat: &mut Mat,
         dst_pos: Vec3,
     ) -> Result<usize> {
-        let file_len_vx = self.header.file_len_vx();
-        let file_len_log2 = self.header.file_len_log2 as u32;
-        let block_len_log2 = self.header.block_len_log2 as u32;
+        let file_shape_vx = self.header.file_shape_vx();
+        let file_shape_log2 = self.header.file_shape_log2;
+        let block_shape_log2 = self.header.block_shape_log2;
 
-        let file_len_vx_vec = Vec3::from(file_len_vx);
-        assert!(src_pos < file_len_vx_vec);
+        assert!(src_pos < file_shape_vx);
 
         let dst_len = dst_mat.shape;
-        let src_end = file_len_vx_vec.elem_min(src_pos + dst_len - dst_pos);
+        let src_end = file_shape_vx.elem_min(src_pos + dst_len - dst_pos);
 
         // bounding boxes
         let src_box = Box3::new(src_pos, src_end)?;
         let src_box_boxes = Box3::new(
-            src_box.min() >> block_len_log2,
-            ((src_box.max() - 1) >> block_len_log2) + 1,
+            src_box.min() >> block_shape_log2,
+            ((src_box.max() - 1) >> block_shape_log2) + 1,
         )?;
 
         // allocate buffer
@@ -102,18 +409,25 @@ impl File {
         let voxel_size = self.header.voxel_size as usize;
         let voxel_type = self.header.voxel_type;
 
-        let buf_shape = Vec3::from(1u32 << block_len_log2);
+        let buf_shape = self.header.block_shape();
         let mut buf_vec = vec![0u8; block_size];
         let buf = buf_vec.as_mut_slice();
 
-        let iter = Iter::new(file_len_log2, src_box_boxes)?;
+        let iter = Iter::with_shape(file_shape_log2, src_box_boxes)?;
+        if let Handle::Storage { .. } = self.file {
+            let block_idxs: Vec<u64> = Iter::with_shape(file_shape_log2, src_box_boxes)?
+                .filter(|&block_idx| !self.is_block_cached(block_idx))
+                .collect();
+            self.prefetch(&block_idxs)?;
+        }
+
         for cur_block_idx in iter {
             // box for current block
-            let cur_block_ids = Vec3::from(Morton::from(cur_block_idx));
+            let cur_block_ids = Morton::from(cur_block_idx).to_vec(file_shape_log2);
 
             let cur_block_box = Box3::new(
-                cur_block_ids << block_len_log2,
-                (cur_block_ids + 1) << block_len_log2,
+                cur_block_ids << block_shape_log2,
+                (cur_block_ids + 1) << block_shape_log2,
             )?;
             let cur_box = cur_block_box.intersect(src_box);
 
@@ -121,16 +435,101 @@ impl File {
             let cur_dst_pos = cur_box.min() - src_pos + dst_pos;
             let cur_src_box = cur_box - cur_block_box.min();
 
+            if let Some(ref mmap) = self.mmap {
+                // copy directly from mapped file
+                let offset = self.header.block_offset(cur_block_idx)? as usize;
+                let block = &mmap[offset..(offset + block_size)];
+                Self::check_block(&self.header, cur_block_idx, block)?;
+                let src_mat = MatView::new(block, buf_shape, voxel_size, voxel_type, false)?;
+                dst_mat.copy_from_view(cur_dst_pos, src_mat, cur_src_box)?;
+                continue;
+            }
+
             // read data
-            self.seek_block(cur_block_idx)?;
-            self.read_block(buf)?;
+            if !self.read_cached_block(cur_block_idx, buf) {
+                self.seek_block(cur_block_idx)?;
+                self.read_block(buf)?;
+                self.cache_block(cur_block_idx, buf);
+            }
 
             // copy data
             let src_mat = Mat::new(buf, buf_shape, voxel_size, voxel_type, false)?;
             dst_mat.copy_from(cur_dst_pos, &src_mat, cur_src_box)?;
         }
 
-        Ok(1 as usize)
+        // don't hold on to prefetched data, e.g., while the file is cached
+        if let Handle::Storage {
+            ref mut prefetched, ..
+        } = self.file
+        {
+            prefetched.clear();
+        }
+
+        Ok(1)
+    }
+
+    /// Keep decompressed blocks read by `read_mat` in `cache`, where they
+    /// are identified by `file_ids`, `generation`, and their block index. Blocks of raw
+    /// files are not cached. `generation` must have been taken from the
+    /// cache before the file was opened (see `BlockCache::generation`).
+    pub(crate) fn set_block_cache(
+        &mut self,
+        cache: Arc<BlockCache>,
+        file_ids: Vec3,
+        generation: u64,
+    ) {
+        self.block_cache = match self.header.is_compressed() {
+            true => Some((cache, file_ids, generation)),
+            false => None,
+        };
+    }
+
+    /// Sets the value of the voxels of new files outside of the written
+    /// region. Compressed blocks which are not stored always consist of the
+    /// fill value in the metadata of the file (or zero without one), which
+    /// is also the value of the blocks that are not stored when writing.
+    pub(crate) fn set_fill_value(&mut self, fill_value: &[u8]) -> Result<()> {
+        if fill_value.len() != self.header.voxel_size as usize {
+            return Err(Error::VoxelSizeMismatch {
+                expected: self.header.voxel_size as usize,
+                found: fill_value.len(),
+            });
+        }
+
+        self.fill_value = fill_value.to_vec();
+        Ok(())
+    }
+
+    fn is_empty_block(&self, buf: &[u8]) -> bool {
+        let value = &self.empty_block_value;
+        match value.iter().all(|&byte| byte == 0) {
+            true => buf.iter().all(|&byte| byte == 0),
+            false => buf.chunks(value.len()).all(|voxel| voxel == &value[..]),
+        }
+    }
+
+    fn is_block_cached(&self, block_idx: u64) -> bool {
+        match self.block_cache {
+            Some((ref cache, file_ids, generation)) => {
+                cache.contains(file_ids, generation, block_idx)
+            }
+            None => false,
+        }
+    }
+
+    fn read_cached_block(&self, block_idx: u64, buf: &mut [u8]) -> bool {
+        match self.block_cache {
+            Some((ref cache, file_ids, generation)) => {
+                cache.read(file_ids, generation, block_idx, buf)
+            }
+            None => false,
+        }
+    }
+
+    fn cache_block(&self, block_idx: u64, buf: &[u8]) {
+        if let Some((ref cache, file_ids, generation)) = self.block_cache {
+            cache.insert(file_ids, generation, block_idx, buf);
+        }
     }
 
     pub(crate) fn write_mat(
@@ -139,23 +538,26 @@ impl File {
         src_mat: &Mat,
         src_pos: Vec3,
     ) -> Result<usize> {
-        let block_len_log2 = self.header.block_len_log2 as u32;
+        let file_shape_log2 = self.header.file_shape_log2;
+        let block_shape_log2 = self.header.block_shape_log2;
 
-        let dst_end =
-            Vec3::from(self.header.file_len_vx()).elem_min(src_mat.shape - src_pos + dst_pos);
+        let dst_end = self
+            .header
+            .file_shape_vx()
+            .elem_min(src_mat.shape - src_pos + dst_pos);
         let dst_box = Box3::new(dst_pos, dst_end)?;
 
         // bounding boxes
         let dst_box_boxes = Box3::new(
-            dst_box.min() >> block_len_log2,
-            ((dst_box.max() - 1) >> block_len_log2) + 1,
+            dst_box.min() >> block_shape_log2,
+            ((dst_box.max() - 1) >> block_shape_log2) + 1,
         )?;
 
         // build buffer matrix
         let mut src_block_buf = vec![0u8; self.header.block_size()];
         let mut src_block_buf_mat = Mat::new(
             src_block_buf.as_mut_slice(),
-            Vec3::from(1u32 << block_len_log2),
+            self.header.block_shape(),
             self.header.voxel_size as usize,
             self.header.voxel_type,
             false,
@@ -165,22 +567,23 @@ impl File {
         let mut c_to_fortran_buf = vec![0u8; self.header.block_size()];
         let mut c_to_fortran_buf_mat = Mat::new(
             c_to_fortran_buf.as_mut_slice(),
-            Vec3::from(1u32 << block_len_log2),
+            self.header.block_shape(),
             self.header.voxel_size as usize,
             self.header.voxel_type,
             true,
         )?;
 
         // build Morton-order iterator
-        let iter = Iter::new(self.header.file_len_log2 as u32, dst_box_boxes)?;
+        let iter = Iter::with_shape(file_shape_log2, dst_box_boxes)?;
+        let mut written_block_ids = Vec::new();
 
         for cur_block_idx in iter {
             // box for current block
-            let cur_block_ids = Vec3::from(Morton::from(cur_block_idx));
+            let cur_block_ids = Morton::from(cur_block_idx).to_vec(file_shape_log2);
 
             let cur_block_box = Box3::new(
-                cur_block_ids << block_len_log2,
-                (cur_block_ids + 1) << block_len_log2,
+                cur_block_ids << block_shape_log2,
+                (cur_block_ids + 1) << block_shape_log2,
             )?;
             let cur_box = cur_block_box.intersect(dst_box);
 
@@ -205,25 +608,269 @@ impl File {
 
             // write in fortran order
             self.write_block(src_block_buf_mat.as_slice())?;
+            written_block_ids.push(cur_block_idx);
         }
 
-        if self.header.block_type == BlockType::LZ4 || self.header.block_type == BlockType::LZ4HC {
+        if self.header.is_compressed() {
             // Update jump table
             self.write_header()?;
             self.truncate()?;
+        } else if self.header.checksums {
+            // Update checksums of written blocks
+            self.write_checksums(&mut written_block_ids)?;
+        }
+
+        Ok(1)
+    }
+
+    /// Writes `src_mat` into a compressed file by rewriting it from the
+    /// first block on. Blocks and parts of blocks outside of the written
+    /// region are taken from `base` (or the fill value if there is none); untouched
+    /// blocks are copied over without being recompressed.
+    pub(crate) fn write_mat_onto(
+        &mut self,
+        mut base: Option<&mut File>,
+        dst_pos: Vec3,
+        src_mat: &Mat,
+        src_pos: Vec3,
+    ) -> Result<usize> {
+        if !self.header.is_compressed() {
+            return Err(Error::invalid("File is not compressed"));
+        }
+
+        let file_shape_log2 = self.header.file_shape_log2;
+        let block_shape_log2 = self.header.block_shape_log2;
+
+        let dst_end = self
+            .header
+            .file_shape_vx()
+            .elem_min(src_mat.shape - src_pos + dst_pos);
+        let dst_box = Box3::new(dst_pos, dst_end)?;
+
+        // compressed blocks can only be copied between files of equal type,
+        // and blocks which are not stored need to mean the same in both
+        let copy_compressed = match base {
+            Some(ref base) => {
+                base.header.block_type == self.header.block_type
+                    && base.empty_block_value == self.empty_block_value
+            }
+            None => false,
+        };
+
+        let mut block_buf = vec![0u8; self.header.block_size()];
+        let mut block_buf_mat = Mat::new(
+            block_buf.as_mut_slice(),
+            self.header.block_shape(),
+            self.header.voxel_size as usize,
+            self.header.voxel_type,
+            false,
+        )?;
+
+        let mut c_to_fortran_buf = vec![0u8; self.header.block_size()];
+        let mut c_to_fortran_buf_mat = Mat::new(
+            c_to_fortran_buf.as_mut_slice(),
+            self.header.block_shape(),
+            self.header.voxel_size as usize,
+            self.header.voxel_type,
+            true,
+        )?;
+
+        self.seek_block(0)?;
+
+        for cur_block_idx in 0..self.header.file_vol() {
+            let cur_block_ids = Morton::from(cur_block_idx).to_vec(file_shape_log2);
+            let cur_block_box = Box3::new(
+                cur_block_ids << block_shape_log2,
+                (cur_block_ids + 1) << block_shape_log2,
+            )?;
+            let cur_box = cur_block_box.intersect(dst_box);
+
+            if cur_box.is_empty() && copy_compressed {
+                let base = base.as_mut().unwrap();
+                base.seek_block(cur_block_idx)?;
+                self.copy_block_compressed(base)?;
+                continue;
+            }
+
+            if cur_box != cur_block_box {
+                // reuse existing data
+                match base {
+                    Some(ref mut base) => {
+                        base.seek_block(cur_block_idx)?;
+                        base.read_block(block_buf_mat.as_mut_slice())?;
+                    }
+                    None => {
+                        let fill_value = &self.fill_value;
+                        for voxel in block_buf_mat.as_mut_slice().chunks_mut(fill_value.len()) {
+                            voxel.copy_from_slice(fill_value);
+                        }
+                    }
+                }
+            }
+
+            if !cur_box.is_empty() {
+                let cur_src_box = cur_box - dst_pos + src_pos;
+                let cur_dst_pos = cur_box.min() - cur_block_box.min();
+
+                block_buf_mat.copy_from_order_agnostic(
+                    cur_dst_pos,
+                    src_mat,
+                    cur_src_box,
+                    &mut c_to_fortran_buf_mat,
+                )?;
+            }
+
+            self.write_block(block_buf_mat.as_slice())?;
+        }
+
+        // update jump table
+        self.write_header()?;
+        self.truncate()?;
+
+        Ok(1)
+    }
+
+    /// Bounding box (in voxels, relative to the file) of all blocks which
+    /// contain at least one non-zero byte. Returns `None` for empty files.
+    pub fn nonzero_box(&mut self) -> Result<Option<Box3>> {
+        let file_shape_log2 = self.header.file_shape_log2;
+        let block_shape_log2 = self.header.block_shape_log2;
+
+        let mut buf_vec = vec![0u8; self.header.block_size()];
+        let buf = buf_vec.as_mut_slice();
+
+        let mut bbox: Option<Box3> = None;
+        self.seek_block(0)?;
+
+        for block_idx in 0..self.header.file_vol() {
+            self.read_block(buf)?;
+
+            if buf.iter().all(|&byte| byte == 0) {
+                continue;
+            }
+
+            let block_ids = Morton::from(block_idx).to_vec(file_shape_log2);
+            let block_box = Box3::new(
+                block_ids << block_shape_log2,
+                (block_ids + 1) << block_shape_log2,
+            )?;
+
+            bbox = Some(match bbox {
+                Some(bbox) => bbox.union(block_box),
+                None => block_box,
+            });
         }
 
-        Ok(1 as usize)
+        Ok(bbox)
+    }
+
+    /// Checks the header, jump table, file length, and the blocks of a
+    /// WKW file. All problems found are collected in the report; only
+    /// failing to open the file at all results in an error.
+    pub fn verify(path: &path::Path) -> Result<Report> {
+        let file = fs::File::open(path)
+            .map_err(|err| Error::io_at("Could not open WKW file", path, err))?;
+        let file_len = file
+            .metadata()
+            .map_err(|err| Error::io_at("Could not stat WKW file", path, err))?
+            .len();
+
+        Ok(Self::verify_handle(Handle::Disk(file), file_len, path))
+    }
+
+    /// Verifies object `key` of a storage backend (see `verify`).
+    pub fn verify_storage(storage: Arc<dyn Storage>, key: &str) -> Result<Report> {
+        let file_len = storage.size(key)?;
+        let file = Handle::Storage {
+            storage,
+            key: String::from(key),
+            pos: 0,
+            prefetched: Vec::new(),
+        };
+
+        Ok(Self::verify_handle(file, file_len, path::Path::new(key)))
+    }
+
+    fn verify_handle(mut file: Handle, file_len: u64, path: &path::Path) -> Report {
+        let mut report = Report {
+            path: path.to_owned(),
+            problems: Vec::new(),
+        };
+
+        let header = match Header::read(&mut file) {
+            Ok(header) => header,
+            Err(err) => {
+                report.problems.push(Problem::Header(err));
+                return report;
+            }
+        };
+
+        // blocks whose jump table entries are broken are not decoded
+        let mut skip_blocks = Vec::new();
+
+        let expected_len = match header.jump_table {
+            Some(ref jump_table) => {
+                let mut prev_entry = header.data_offset;
+                for (block_idx, &entry) in jump_table.iter().enumerate() {
+                    if entry < prev_entry {
+                        let block_idx = block_idx as u64;
+                        report.problems.push(Problem::JumpTable { block_idx });
+                        skip_blocks.push(block_idx);
+                    } else {
+                        prev_entry = entry;
+                    }
+                }
+
+                jump_table[jump_table.len() - 1]
+            }
+            None => (header.size_on_disk() + header.file_size()) as u64,
+        };
+
+        if file_len != expected_len {
+            report.problems.push(Problem::FileLength {
+                expected: expected_len,
+                found: file_len,
+            });
+        }
+
+        // decode all blocks
+        let mut file = Self::new(file, header);
+        let mut buf = vec![0u8; file.header.block_size()];
+
+        for block_idx in 0..file.header.file_vol() {
+            if skip_blocks.contains(&block_idx) {
+                continue;
+            }
+
+            let result = file
+                .seek_block(block_idx)
+                .and_then(|_| file.read_block(&mut buf));
+
+            if let Err(error) = result {
+                report.problems.push(Problem::Block { block_idx, error });
+            }
+        }
+
+        report
     }
 
     pub fn compress(&mut self, path: &path::Path) -> Result<()> {
+        self.compress_with(path, BlockType::LZ4HC, 0)
+    }
+
+    pub fn compress_with(
+        &mut self,
+        path: &path::Path,
+        block_type: BlockType,
+        compression_level: u8,
+    ) -> Result<()> {
         // prepare header
-        let header = Header::compress(&self.header);
+        let header = Header::compress_with(&self.header, block_type, compression_level)?;
 
         // make sure that output path does not exist yet
         let mut file = match path.exists() {
-            true => return Err(format!("Output file {:?} already exists", path)),
-            false => Self::open_or_create(path, &header)?,
+            true => return Err(Error::AlreadyExists(path.to_owned())),
+            false => Self::open_or_create(path, &header, &self.fill_value)?,
         };
 
         // prepare buffers and jump table
@@ -243,7 +890,7 @@ impl File {
         file.write_header()
     }
 
-    fn truncate(&self) -> Result<()> {
+    fn truncate(&mut self) -> Result<()> {
         let truncated_size = match self.header.block_type {
             BlockType::Raw => {
                 let header_size = self.header.size_on_disk();
@@ -251,7 +898,7 @@ impl File {
                 let size = header_size + body_size;
                 size as u64
             }
-            BlockType::LZ4 | BlockType::LZ4HC => {
+            BlockType::LZ4 | BlockType::LZ4HC | BlockType::Zstd => {
                 let last_block_idx = self.header.file_vol() - 1;
                 let jump_table = self.header.jump_table.as_ref().unwrap();
                 jump_table[last_block_idx as usize]
@@ -260,13 +907,14 @@ impl File {
 
         self.file
             .set_len(truncated_size)
-            .map_err(|_| String::from("Could not truncate file"))
+            .map_err(|err| Error::io("Could not truncate file", err))
     }
 
     fn seek_header(&mut self) -> Result<()> {
         match self.file.seek(SeekFrom::Start(0)) {
             Ok(0) => Ok(()),
-            _ => Err(String::from("Could not seek header")),
+            Ok(_) => Err(Error::invalid("Could not seek header")),
+            Err(err) => Err(Error::io("Could not seek header", err)),
         }
     }
 
@@ -275,19 +923,49 @@ impl File {
         self.header.write(&mut self.file)
     }
 
+    // Writes the checksum table entries of the given blocks in place, in
+    // runs of consecutive blocks. The checksum table directly precedes the
+    // data of raw files.
+    fn write_checksums(&mut self, block_ids: &mut [u64]) -> Result<()> {
+        let file = &mut self.file;
+        let checksum_table = match self.header.checksum_table {
+            Some(ref checksum_table) => checksum_table,
+            None => return Ok(()),
+        };
+
+        let entry_size = mem::size_of::<u32>() as u64;
+        let table_offset = self.header.data_offset - checksum_table.len() as u64 * entry_size;
+
+        block_ids.sort_unstable();
+        for run in block_ids.chunk_by(|a, b| a + 1 == *b) {
+            let mut buf = Vec::with_capacity(run.len() * entry_size as usize);
+            for &block_idx in run {
+                buf.extend_from_slice(&checksum_table[block_idx as usize].to_le_bytes());
+            }
+
+            file.seek(SeekFrom::Start(table_offset + run[0] * entry_size))
+                .and_then(|_| file.write_all(&buf))
+                .map_err(|err| Error::io("Could not write checksum table", err))?;
+        }
+
+        // the file position no longer matches a block
+        self.block_idx = None;
+        Ok(())
+    }
+
     fn read_block(&mut self, buf: &mut [u8]) -> Result<usize> {
         if buf.len() != self.header.block_size() {
-            return Err(String::from("Buffer has invalid size"));
+            return Err(Error::invalid("Buffer has invalid size"));
         }
 
         let block_idx = match self.block_idx {
             Some(block_idx) => block_idx,
-            None => return Err(String::from("File is not block aligned")),
+            None => return Err(Error::invalid("File is not block aligned")),
         };
 
         let result = match self.header.block_type {
             BlockType::Raw => self.read_block_raw(buf),
-            BlockType::LZ4 | BlockType::LZ4HC => self.read_block_lz4(buf),
+            BlockType::LZ4 | BlockType::LZ4HC | BlockType::Zstd => self.read_block_compressed(buf),
         };
 
         match result {
@@ -301,13 +979,12 @@ impl File {
     fn write_block(&mut self, buf: &[u8]) -> Result<usize> {
         let block_idx = match self.block_idx {
             Some(block_idx) => block_idx,
-            None => return Err(String::from("File is not block aligned")),
+            None => return Err(Error::invalid("File is not block aligned")),
         };
 
         let result = match self.header.block_type {
             BlockType::Raw => self.write_block_raw(buf),
-            BlockType::LZ4 => self.write_block_lz4(buf, BlockType::LZ4),
-            BlockType::LZ4HC => self.write_block_lz4(buf, BlockType::LZ4HC),
+            BlockType::LZ4 | BlockType::LZ4HC | BlockType::Zstd => self.write_block_compressed(buf),
         };
 
         // advance
@@ -320,79 +997,215 @@ impl File {
     }
 
     fn read_block_raw(&mut self, buf: &mut [u8]) -> Result<usize> {
-        match self.file.read_exact(buf) {
-            Ok(_) => Ok(buf.len()),
-            Err(_) => Err(String::from("Could not read raw block")),
-        }
+        self.file
+            .read_exact(buf)
+            .map_err(|err| Error::io("Could not read raw block", err))?;
+
+        Self::check_block(&self.header, self.block_idx.unwrap(), buf)?;
+        Ok(buf.len())
     }
 
     fn write_block_raw(&mut self, buf: &[u8]) -> Result<usize> {
-        match self.file.write_all(buf) {
-            Ok(_) => Ok(buf.len()),
-            Err(_) => Err(String::from("Could not write raw block")),
+        self.file
+            .write_all(buf)
+            .map_err(|err| Error::io("Could not write raw block", err))?;
+
+        self.update_checksum(buf);
+        Ok(buf.len())
+    }
+
+    fn check_block(header: &Header, block_idx: u64, buf_disk: &[u8]) -> Result<()> {
+        let expected = match header.block_checksum(block_idx) {
+            Some(expected) => expected,
+            None => return Ok(()),
+        };
+
+        let found = crc32c::crc32c(buf_disk);
+        match found == expected {
+            true => Ok(()),
+            false => Err(Error::ChecksumMismatch {
+                block_idx,
+                expected,
+                found,
+            }),
+        }
+    }
+
+    fn update_checksum(&mut self, buf_disk: &[u8]) {
+        let block_idx = self.block_idx.unwrap();
+        if let Some(ref mut checksum_table) = self.header.checksum_table {
+            checksum_table[block_idx as usize] = crc32c::crc32c(buf_disk);
         }
     }
 
-    fn write_block_lz4(&mut self, buf: &[u8], block_type: BlockType) -> Result<usize> {
-        // compress data
-        let mut buf_lz4 = &mut *self.disk_block_buf.as_mut().unwrap();
-        let len_lz4 = match block_type {
-            BlockType::LZ4 => lz4::compress(buf, &mut buf_lz4)?,
-            BlockType::LZ4HC => lz4::compress_hc(buf, &mut buf_lz4)?,
-            _ => {
-                return Err(format!(
+    fn write_block_compressed(&mut self, buf: &[u8]) -> Result<usize> {
+        // compress data, unless the block consists of the empty block value only
+        let level = self.header.compression_level;
+        let is_empty_block = self.is_empty_block(buf);
+        let buf_disk = &mut *self.disk_block_buf.as_mut().unwrap();
+        let len_disk = match self.header.block_type {
+            _ if is_empty_block => 0,
+            BlockType::LZ4 => lz4::compress(buf, buf_disk)?,
+            BlockType::LZ4HC => lz4::compress_hc(buf, buf_disk, level)?,
+            BlockType::Zstd => zstd::compress(buf, buf_disk, level)?,
+            block_type => {
+                return Err(Error::invalid(format!(
                     "Invalid block_type {:?} for compression.",
                     block_type
-                ));
+                )));
             }
         };
 
+        let block_idx = self.block_idx.unwrap();
+        Self::append_block_disk(
+            &mut self.file,
+            &mut self.header,
+            block_idx,
+            &buf_disk[..len_disk],
+        )?;
+
+        Ok(len_disk)
+    }
+
+    fn append_block_disk(
+        file: &mut Handle,
+        header: &mut Header,
+        block_idx: u64,
+        buf_disk: &[u8],
+    ) -> Result<()> {
         // write data
-        self.file
-            .write_all(&buf_lz4[..len_lz4])
-            .or(Err("Could not write LZ4 block"))?;
+        file.write_all(buf_disk)
+            .map_err(|err| Error::io("Could not write compressed block", err))?;
+
+        if let Some(ref mut checksum_table) = header.checksum_table {
+            checksum_table[block_idx as usize] = crc32c::crc32c(buf_disk);
+        }
 
         // update jump table
-        let jump_entry = self
-            .file
-            .seek(SeekFrom::Current(0))
-            .or(Err("Could not determine jump entry"))?;
+        let jump_entry = file
+            .stream_position()
+            .map_err(|err| Error::io("Could not determine jump entry", err))?;
 
-        let block_idx = self.block_idx.unwrap();
-        let jump_table = &mut *self.header.jump_table.as_mut().unwrap();
+        let jump_table = &mut *header.jump_table.as_mut().unwrap();
         jump_table[block_idx as usize] = jump_entry;
 
-        Ok(len_lz4)
+        Ok(())
+    }
+
+    fn copy_block_compressed(&mut self, src: &mut File) -> Result<usize> {
+        let (src_block_idx, block_idx) = match (src.block_idx, self.block_idx) {
+            (Some(src_block_idx), Some(block_idx)) => (src_block_idx, block_idx),
+            _ => return Err(Error::invalid("File is not block aligned")),
+        };
+
+        let result = src.read_block_disk().and_then(|buf_disk| {
+            Self::append_block_disk(&mut self.file, &mut self.header, block_idx, buf_disk)
+                .map(|_| buf_disk.len())
+        });
+
+        match result {
+            Ok(_) => {
+                src.block_idx = Some(src_block_idx + 1);
+                self.block_idx = Some(block_idx + 1);
+            }
+            Err(_) => {
+                src.block_idx = None;
+                self.block_idx = None;
+            }
+        };
+
+        result
     }
 
-    fn read_block_lz4(&mut self, buf: &mut [u8]) -> Result<usize> {
+    fn read_block_disk(&mut self) -> Result<&[u8]> {
         let block_idx = self.block_idx.unwrap();
-        let block_size_lz4 = self.header.block_size_on_disk(block_idx)?;
-        let block_size_raw = self.header.block_size();
+        let block_size_disk = self.header.block_size_on_disk(block_idx)?;
 
-        let buf_lz4_orig = &mut *self.disk_block_buf.as_mut().unwrap();
-        let buf_lz4 = match buf_lz4_orig.get_mut(..block_size_lz4) {
-            Some(buf_lz4) => buf_lz4,
+        let buf_disk_orig = &mut *self.disk_block_buf.as_mut().unwrap();
+        let buf_disk = match buf_disk_orig.get_mut(..block_size_disk) {
+            Some(buf_disk) => buf_disk,
             None => {
-                return Err(format!(
+                return Err(Error::Decompression(format!(
                     "Unexpected compressed block length {}",
-                    block_size_lz4
-                ))
+                    block_size_disk
+                )))
             }
         };
 
         // read compressed block
         self.file
-            .read_exact(buf_lz4)
-            .or(Err("Error while reading LZ4 block"))?;
+            .read_exact(buf_disk)
+            .map_err(|err| Error::io("Error while reading compressed block", err))?;
+        Self::check_block(&self.header, block_idx, buf_disk)?;
+
+        Ok(buf_disk)
+    }
+
+    fn read_block_compressed(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let block_size_raw = self.header.block_size();
+        let block_type = self.header.block_type;
+        let buf_disk = self.read_block_disk()?;
+
+        // blocks without data on disk consist of the file's fill value
+        if buf_disk.is_empty() {
+            let value = &self.empty_block_value;
+            for voxel in buf.chunks_mut(value.len()) {
+                voxel.copy_from_slice(value);
+            }
+            return Ok(block_size_raw);
+        }
 
         // decompress block
-        let byte_written = lz4::decompress_safe(buf_lz4, buf)?;
+        let byte_written = match block_type {
+            BlockType::Zstd => zstd::decompress(buf_disk, buf)?,
+            _ => lz4::decompress_safe(buf_disk, buf)?,
+        };
 
         match byte_written == block_size_raw {
             true => Ok(byte_written),
-            false => Err(String::from("Unexpected length after decompression")),
+            false => Err(Error::Decompression(String::from(
+                "Unexpected length after decompression",
+            ))),
+        }
+    }
+
+    // Fetches the blocks of a file in a storage backend, with one range
+    // request per run of adjacent blocks. Replaces previously fetched data.
+    fn prefetch(&mut self, block_idxs: &[u64]) -> Result<()> {
+        let mut ranges: Vec<Range<u64>> = Vec::with_capacity(block_idxs.len());
+        for &block_idx in block_idxs {
+            let offset = self.header.block_offset(block_idx)?;
+            let len = self.header.block_size_on_disk(block_idx)? as u64;
+            if len > 0 {
+                ranges.push(offset..(offset + len));
+            }
+        }
+
+        ranges.sort_by_key(|range| range.start);
+
+        let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = cmp::max(last.end, range.end),
+                _ => merged.push(range),
+            }
         }
+
+        if let Handle::Storage {
+            ref storage,
+            ref key,
+            ref mut prefetched,
+            ..
+        } = self.file
+        {
+            prefetched.clear();
+            for range in merged {
+                let start = range.start;
+                prefetched.push((start, storage.get_range(key, range)?));
+            }
+        }
+
+        Ok(())
     }
 
     fn seek_block(&mut self, block_idx: u64) -> Result<u64> {
@@ -405,7 +1218,7 @@ impl File {
 
         // seek to byte offset
         match self.file.seek(SeekFrom::Start(offset)) {
-            Err(_) => Err(String::from("Could not seek block")),
+            Err(err) => Err(Error::io("Could not seek block", err)),
             Ok(_) => {
                 self.block_idx = Some(block_idx);
                 Ok(block_idx)
@@ -413,3 +1226,469 @@ impl File {
         }
     }
 }
+
+#[test]
+fn test_verify() {
+    use std::io::{Seek, SeekFrom, Write};
+    use {Dataset, VoxelType};
+
+    let root = ::std::env::temp_dir().join(format!("wkwrap-verify-{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+
+    let header = Header::for_tests(1, 3, 1, BlockType::LZ4, VoxelType::U8);
+    let dataset = Dataset::create(&root, header).unwrap();
+
+    let shape = Vec3::from(16u32);
+    let mut data: Vec<u8> = (0..shape.product()).map(|i| (i % 13) as u8).collect();
+    let mat = Mat::new(&mut data, shape, 1, VoxelType::U8, false).unwrap();
+    dataset.write_mat(Vec3::from(0u32), &mat).unwrap();
+
+    let path = root.join("z0").join("y0").join("x0.wkw");
+    assert!(File::verify(&path).unwrap().is_ok());
+
+    // swap two jump table entries and append garbage
+    let (entry_2, entry_3) = {
+        let file = File::open(&path).unwrap();
+        let jump_table = file.header().jump_table.as_ref().unwrap();
+        (jump_table[2], jump_table[3])
+    };
+
+    {
+        let mut file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(16 + 2 * 8)).unwrap();
+        file.write_all(&entry_3.to_le_bytes()).unwrap();
+        file.write_all(&entry_2.to_le_bytes()).unwrap();
+        file.seek(SeekFrom::End(0)).unwrap();
+        file.write_all(&[0u8; 5]).unwrap();
+    }
+
+    let reports = dataset.verify().unwrap();
+    assert_eq!(reports.len(), 1);
+
+    let problems = &reports[0].problems;
+    assert!(problems.iter().any(|problem| match *problem {
+        Problem::JumpTable { block_idx } => block_idx == 3,
+        _ => false,
+    }));
+    assert!(problems.iter().any(|problem| match *problem {
+        Problem::FileLength { expected, found } => found == expected + 5,
+        _ => false,
+    }));
+    assert!(problems.iter().any(|problem| match *problem {
+        Problem::Block { block_idx, .. } => block_idx == 2,
+        _ => false,
+    }));
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_checksums() {
+    use std::io::{Seek, SeekFrom, Write};
+    use {Dataset, VoxelType};
+
+    let root = ::std::env::temp_dir().join(format!("wkwrap-checksums-{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+
+    let header = Header {
+        checksums: true,
+        ..Header::for_tests(2, 2, 1, BlockType::Raw, VoxelType::U8)
+    };
+    let dataset = Dataset::create(&root, header).unwrap();
+    assert!(Dataset::new(&root).unwrap().header().checksums);
+
+    // partial write, so that untouched blocks keep their zero checksum
+    let shape = Vec3::from(5u32);
+    let mut data: Vec<u8> = (0..shape.product()).map(|i| (i % 7) as u8).collect();
+    let mat = Mat::new(&mut data, shape, 1, VoxelType::U8, false).unwrap();
+    dataset.write_mat(Vec3::from(0u32), &mat).unwrap();
+
+    let mut read_data = vec![0u8; data.len()];
+    {
+        let mut read_mat = Mat::new(&mut read_data, shape, 1, VoxelType::U8, false).unwrap();
+        dataset.read_mat(Vec3::from(0u32), &mut read_mat).unwrap();
+    }
+    assert_eq!(read_data, data);
+
+    let raw_path = root.join("z0").join("y0").join("x0.wkw");
+    let lz4_path = root.join("x0-lz4.wkw");
+    assert!(File::verify(&raw_path).unwrap().is_ok());
+
+    File::open(&raw_path)
+        .unwrap()
+        .compress_with(&lz4_path, BlockType::LZ4, 0)
+        .unwrap();
+    assert!(File::open(&lz4_path).unwrap().header().checksums);
+    assert!(File::verify(&lz4_path).unwrap().is_ok());
+
+    // flip a byte in the last block of each file
+    for path in &[&raw_path, &lz4_path] {
+        let mut file = fs::OpenOptions::new().write(true).open(path).unwrap();
+        file.seek(SeekFrom::End(-1)).unwrap();
+        file.write_all(&[0xff]).unwrap();
+
+        let report = File::verify(path).unwrap();
+        assert_eq!(report.problems.len(), 1);
+        match report.problems[0] {
+            Problem::Block {
+                block_idx,
+                error: Error::ChecksumMismatch { .. },
+            } => assert_eq!(block_idx, 7),
+            ref other => panic!("Unexpected problem {:?}", other),
+        }
+    }
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_coalesced_reads() {
+    use std::ops::Range;
+    use std::sync::Mutex;
+    use storage::MemoryStorage;
+    use {Dataset, VoxelType};
+
+    // records the range requests to WKW files
+    #[derive(Debug, Default)]
+    struct RecordingStorage {
+        inner: MemoryStorage,
+        ranges: Mutex<Vec<Range<u64>>>,
+    }
+
+    impl Storage for RecordingStorage {
+        fn get_range(&self, key: &str, range: Range<u64>) -> Result<Vec<u8>> {
+            if key.ends_with(".wkw") && key != "header.wkw" {
+                self.ranges.lock().unwrap().push(range.clone());
+            }
+            self.inner.get_range(key, range)
+        }
+
+        fn size(&self, key: &str) -> Result<u64> {
+            self.inner.size(key)
+        }
+
+        fn exists(&self, key: &str) -> Result<bool> {
+            self.inner.exists(key)
+        }
+
+        fn put(&self, key: &str, buf: &[u8]) -> Result<()> {
+            self.inner.put(key, buf)
+        }
+
+        fn list(&self, prefix: &str) -> Result<Vec<String>> {
+            self.inner.list(prefix)
+        }
+
+        fn rename(&self, old_key: &str, new_key: &str) -> Result<()> {
+            self.inner.rename(old_key, new_key)
+        }
+    }
+
+    let header = Header::for_tests(1, 2, 2, BlockType::LZ4, VoxelType::U8);
+
+    let storage = Arc::new(RecordingStorage::default());
+    let dataset = Dataset::create_with_storage(storage.clone(), header).unwrap();
+
+    let shape = Vec3::from(16u32);
+    let mut data: Vec<u8> = (0..shape.product()).map(|i| (i % 13) as u8).collect();
+    let mat = Mat::new(&mut data, shape, 1, VoxelType::U8, false).unwrap();
+    dataset.write_mat(Vec3::from(0u32), &mat).unwrap();
+
+    // blocks 0 to 7 and 32 to 35 are adjacent
+    let shape = Vec3 { x: 8, y: 8, z: 12 };
+    let mut out = vec![0u8; shape.product() as usize];
+    {
+        let mut out_mat = Mat::new(&mut out, shape, 1, VoxelType::U8, false).unwrap();
+        dataset.read_mat(Vec3::from(0u32), &mut out_mat).unwrap();
+    }
+    assert_eq!(out[0..8], data[0..8]);
+
+    let file = File::open_storage(storage.clone(), "z0/y0/x0.wkw").unwrap();
+    let jump_table = file.header().jump_table.as_ref().unwrap();
+
+    // header, jump table, and two runs of blocks
+    let ranges = storage.ranges.lock().unwrap();
+    assert_eq!(ranges.len(), 6);
+    assert_eq!(ranges[0], 0..16);
+    assert_eq!(ranges[2], file.header().data_offset..jump_table[7]);
+    assert_eq!(ranges[3], jump_table[31]..jump_table[35]);
+}
+
+#[test]
+fn test_overlong_range_reads() {
+    use std::ops::Range;
+    use storage::MemoryStorage;
+    use {Dataset, VoxelType};
+
+    // returns everything from the start of the range on
+    #[derive(Debug, Default)]
+    struct OverlongStorage {
+        inner: MemoryStorage,
+    }
+
+    impl Storage for OverlongStorage {
+        fn get_range(&self, key: &str, range: Range<u64>) -> Result<Vec<u8>> {
+            self.inner.get_range(key, range.start..u64::MAX)
+        }
+
+        fn size(&self, key: &str) -> Result<u64> {
+            self.inner.size(key)
+        }
+
+        fn exists(&self, key: &str) -> Result<bool> {
+            self.inner.exists(key)
+        }
+
+        fn put(&self, key: &str, buf: &[u8]) -> Result<()> {
+            self.inner.put(key, buf)
+        }
+
+        fn list(&self, prefix: &str) -> Result<Vec<String>> {
+            self.inner.list(prefix)
+        }
+
+        fn rename(&self, old_key: &str, new_key: &str) -> Result<()> {
+            self.inner.rename(old_key, new_key)
+        }
+    }
+
+    let header = Header::for_tests(1, 2, 2, BlockType::LZ4, VoxelType::U8);
+
+    let storage = Arc::new(OverlongStorage::default());
+    let dataset = Dataset::create_with_storage(storage.clone(), header).unwrap();
+
+    let shape = Vec3::from(16u32);
+    let mut data: Vec<u8> = (0..shape.product()).map(|i| (i % 13) as u8).collect();
+    let mat = Mat::new(&mut data, shape, 1, VoxelType::U8, false).unwrap();
+    dataset.write_mat(Vec3::from(0u32), &mat).unwrap();
+
+    let mut out = vec![0u8; data.len()];
+    {
+        let mut out_mat = Mat::new(&mut out, shape, 1, VoxelType::U8, false).unwrap();
+        let mut file = File::open_storage(storage.clone(), "z0/y0/x0.wkw").unwrap();
+        file.read_mat(Vec3::from(0u32), &mut out_mat, Vec3::from(0u32))
+            .unwrap();
+    }
+    assert_eq!(out, data);
+}
+
+#[test]
+fn test_sparse_blocks() {
+    use {Dataset, VoxelType};
+
+    let root = ::std::env::temp_dir().join(format!("wkwrap-sparse-{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+
+    let header = Header {
+        checksums: true,
+        ..Header::for_tests(2, 2, 1, BlockType::LZ4, VoxelType::U8)
+    };
+    let dataset = Dataset::create(&root, header.clone()).unwrap();
+
+    // only the first block contains non-zero voxels
+    let shape = Vec3::from(8u32);
+    let mut data = vec![0u8; shape.product() as usize];
+    data[0] = 1;
+    let mat = Mat::new(&mut data, shape, 1, VoxelType::U8, false).unwrap();
+    dataset.write_mat(Vec3::from(0u32), &mat).unwrap();
+
+    let path = root.join("z0").join("y0").join("x0.wkw");
+    let file = File::open(&path).unwrap();
+    assert!(file.header.block_size_on_disk(0).unwrap() > 0);
+    for block_idx in 1..8 {
+        assert_eq!(file.header.block_size_on_disk(block_idx).unwrap(), 0);
+    }
+    assert!(File::verify(&path).unwrap().is_ok());
+
+    let mut out = vec![1u8; data.len()];
+    {
+        let mut out_mat = Mat::new(&mut out, shape, 1, VoxelType::U8, false).unwrap();
+        dataset.read_mat(Vec3::from(0u32), &mut out_mat).unwrap();
+    }
+    assert_eq!(out, data);
+
+    // files whose zero blocks are stored compressed remain readable
+    let mut header = Header::from_template(&header);
+    header.checksums = false;
+    header.checksum_table = None;
+    header.data_offset = header.size_on_disk() as u64;
+
+    let mut body = Vec::new();
+    let mut buf_disk = vec![0u8; header.max_block_size_on_disk()];
+    for block_idx in 0..8 {
+        let len = lz4::compress(&[0u8; 64], &mut buf_disk).unwrap();
+        body.extend_from_slice(&buf_disk[..len]);
+        header.jump_table.as_mut().unwrap()[block_idx] = header.data_offset + body.len() as u64;
+    }
+
+    let mut buf = Vec::new();
+    header.write(&mut buf).unwrap();
+    buf.extend_from_slice(&body);
+
+    let dense_path = root.join("dense.wkw");
+    fs::write(&dense_path, buf).unwrap();
+    assert!(File::verify(&dense_path).unwrap().is_ok());
+    assert_eq!(
+        File::open(&dense_path).unwrap().nonzero_box().unwrap(),
+        None
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+// Writes the files of the golden tests into `root`, as `<name>.wkw`.
+#[cfg(test)]
+fn write_golden_files(root: &path::Path) -> Vec<(&'static str, Header)> {
+    use {Dataset, VoxelType};
+
+    let template = Header::for_tests(1, 2, 1, BlockType::Raw, VoxelType::U16);
+
+    let mut headers = vec![("raw_v1", template.clone())];
+
+    let mut header = template.clone();
+    header.block_type = BlockType::LZ4HC;
+    headers.push(("lz4hc_v1", header.clone()));
+
+    // checksums are signalled by the metadata of version-2 headers
+    let mut header = template.clone();
+    header.version = 2;
+    header.checksums = true;
+    header.metadata = Some(Metadata {
+        creation_tool: Some(String::from("golden")),
+        ..Metadata::default()
+    });
+    headers.push(("raw_v2", header.clone()));
+
+    header.block_type = BlockType::LZ4;
+    header.metadata = Some(Metadata {
+        voxel_size_nm: Some([11.24, 11.24, 25.0]),
+        axis_order: Some(String::from("xyz")),
+        fill_value: None,
+        creation_tool: Some(String::from("golden")),
+        unknown: vec![(0x100, vec![1, 2, 3])],
+    });
+    headers.push(("lz4_v2", header));
+
+    // partial write, so that compressed files contain unstored blocks
+    let shape = Vec3 { x: 8, y: 5, z: 3 };
+    let mut data: Vec<u8> = (0..shape.product())
+        .flat_map(|i| ((i * 31 % 1000) as u16).to_le_bytes())
+        .collect();
+
+    for &(name, ref header) in &headers {
+        let dataset_root = root.join(name);
+        let _ = fs::remove_dir_all(&dataset_root);
+
+        let dataset = Dataset::create(&dataset_root, header.clone()).unwrap();
+        let mat = Mat::new(&mut data, shape, 2, VoxelType::U16, false).unwrap();
+        dataset.write_mat(Vec3::from(0u32), &mat).unwrap();
+
+        let file_path = dataset_root.join("z0").join("y0").join("x0.wkw");
+        fs::rename(file_path, root.join(format!("{}.wkw", name))).unwrap();
+        fs::remove_dir_all(&dataset_root).unwrap();
+    }
+
+    headers
+}
+
+#[test]
+fn test_golden_files() {
+    let data_root = path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("data");
+
+    let root = ::std::env::temp_dir().join(format!("wkwrap-golden-{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+
+    for (name, header) in write_golden_files(&root) {
+        let golden_path = data_root.join(format!("{}.wkw", name));
+        let golden = fs::read(&golden_path).unwrap();
+
+        // files are written byte by byte as before
+        assert_eq!(
+            fs::read(root.join(format!("{}.wkw", name))).unwrap(),
+            golden
+        );
+        assert!(File::verify(&golden_path).unwrap().is_ok());
+
+        // headers, including the jump and checksum tables, round-trip
+        let golden_header = Header::read(&mut &golden[..]).unwrap();
+        assert_eq!(golden_header.version, header.version);
+        assert_eq!(golden_header.block_type, header.block_type);
+        assert_eq!(golden_header.compression_level, header.compression_level);
+        assert_eq!(golden_header.checksums, header.checksums);
+        assert_eq!(golden_header.metadata, header.metadata);
+
+        let mut buf = Vec::new();
+        golden_header.write(&mut buf).unwrap();
+        assert_eq!(&buf[..], &golden[..golden_header.data_offset as usize]);
+    }
+
+    // magic, version, lengths, block type, voxel type, voxel size, and data
+    // offset
+    let golden = fs::read(data_root.join("lz4hc_v1.wkw")).unwrap();
+    assert_eq!(&golden[..8], b"WKW\x01\x12\x03\x02\x02");
+    assert_eq!(&golden[8..16], &(16u64 + 8 * 8).to_le_bytes());
+
+    // the voxel type of files with checksums is unchanged as well
+    let golden = fs::read(data_root.join("lz4_v2.wkw")).unwrap();
+    assert_eq!(&golden[..8], b"WKW\x02\x12\x02\x02\x02");
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_baseline_files() {
+    use {Dataset, VoxelType};
+
+    let data_root = path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("data");
+
+    let root = ::std::env::temp_dir().join(format!("wkwrap-baseline-{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+
+    // files written by version 1.6.0 of the library, before the format
+    // gained levels, checksums, and metadata
+    let shape = Vec3::from(8u32);
+    let mut data: Vec<u8> = (0..shape.product())
+        .flat_map(|i| ((i * 31 % 1000) as u16).to_le_bytes())
+        .collect();
+
+    for &(name, block_type) in &[
+        ("lz4_v1_baseline", BlockType::LZ4),
+        ("lz4hc_v1_baseline", BlockType::LZ4HC),
+    ] {
+        let golden_path = data_root.join(format!("{}.wkw", name));
+        let golden = fs::read(&golden_path).unwrap();
+
+        let header = Header::for_tests(1, 2, 1, block_type, VoxelType::U16);
+
+        let dataset_root = root.join(name);
+        let dataset = Dataset::create(&dataset_root, header.clone()).unwrap();
+        let mat = Mat::new(&mut data, shape, 2, VoxelType::U16, false).unwrap();
+        dataset.write_mat(Vec3::from(0u32), &mat).unwrap();
+
+        // the current writer produces the same bytes
+        let file_path = dataset_root.join("z0").join("y0").join("x0.wkw");
+        assert_eq!(fs::read(&file_path).unwrap(), golden);
+
+        // and the current reader the same voxels
+        let mut file = File::open(&golden_path).unwrap();
+        assert_eq!(file.header().block_type, block_type);
+        assert_eq!(file.header().compression_level, 0);
+        assert!(!file.header().checksums);
+        assert!(File::verify(&golden_path).unwrap().is_ok());
+
+        let mut out = vec![0u8; data.len()];
+        {
+            let mut out_mat = Mat::new(&mut out, shape, 2, VoxelType::U16, false).unwrap();
+            file.read_mat(Vec3::from(0u32), &mut out_mat, Vec3::from(0u32))
+                .unwrap();
+        }
+        assert_eq!(out, data);
+    }
+
+    fs::remove_dir_all(&root).unwrap();
+}
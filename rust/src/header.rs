@@ -1,9 +1,14 @@
+use crc32c;
 use lz4;
 use result::Result;
 use std::io::{Read, Write};
-use std::{fs, mem, slice};
+use std::mem;
+use std::ops::RangeInclusive;
+use zstd;
+use {Error, Vec3};
 
-#[repr(C)]
+// Fixed 16-byte header as stored on disk, with the data offset in little
+// endian order.
 #[derive(Debug)]
 struct HeaderRaw {
     magic: [u8; 3],
@@ -15,11 +20,75 @@ struct HeaderRaw {
     data_offset: u64,
 }
 
+impl HeaderRaw {
+    fn from_bytes(buf: [u8; 16]) -> HeaderRaw {
+        let mut magic = [0u8; 3];
+        magic.copy_from_slice(&buf[0..3]);
+
+        let mut data_offset = [0u8; 8];
+        data_offset.copy_from_slice(&buf[8..16]);
+
+        HeaderRaw {
+            magic,
+            version: buf[3],
+            per_dim_log2: buf[4],
+            block_type: buf[5],
+            voxel_type: buf[6],
+            voxel_size: buf[7],
+            data_offset: u64::from_le_bytes(data_offset),
+        }
+    }
+
+    fn to_bytes(&self) -> [u8; 16] {
+        let mut buf = [0u8; 16];
+        buf[0..3].copy_from_slice(&self.magic);
+        buf[3] = self.version;
+        buf[4] = self.per_dim_log2;
+        buf[5] = self.block_type;
+        buf[6] = self.voxel_type;
+        buf[7] = self.voxel_size;
+        buf[8..16].copy_from_slice(&self.data_offset.to_le_bytes());
+        buf
+    }
+}
+
+// tags of the entries in the metadata section of version-2 headers
+const TAG_VOXEL_SIZE_NM: u16 = 1;
+const TAG_AXIS_ORDER: u16 = 2;
+const TAG_FILL_VALUE: u16 = 3;
+const TAG_COMPRESSION_LEVEL: u16 = 4;
+const TAG_CHECKSUM_ALGORITHM: u16 = 5;
+const TAG_CREATION_TOOL: u16 = 6;
+
+const CHECKSUM_ALGORITHM: &str = "crc32c";
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum BlockType {
     Raw,
     LZ4,
     LZ4HC,
+    Zstd,
+}
+
+impl BlockType {
+    /// Valid compression levels. For LZ4HC, zero selects the default level
+    /// of the library.
+    pub fn compression_levels(&self) -> RangeInclusive<u8> {
+        match *self {
+            BlockType::Raw | BlockType::LZ4 => 0..=0,
+            BlockType::LZ4HC => 0..=12,
+            BlockType::Zstd => 1..=22,
+        }
+    }
+
+    /// Compression level used if none is given. Version-1 headers do not
+    /// store the level, so files read from them report this level.
+    pub fn default_compression_level(&self) -> u8 {
+        match *self {
+            BlockType::Zstd => zstd::DEFAULT_LEVEL as u8,
+            _ => 0,
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -49,16 +118,148 @@ impl VoxelType {
     }
 }
 
+/// Metadata section of version-2 headers. The compression level and the
+/// checksum algorithm are stored in this section as well, but are taken
+/// from the corresponding header fields.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metadata {
+    /// Physical size of a voxel along x, y, and z, in nanometers.
+    pub voxel_size_nm: Option<[f64; 3]>,
+    /// Informational order of the axes, e.g. `"xyz"`.
+    pub axis_order: Option<String>,
+    /// Bytes of a single voxel (see `Dataset::set_fill_value`).
+    pub fill_value: Option<Vec<u8>>,
+    /// Name and version of the tool which created the file.
+    pub creation_tool: Option<String>,
+    /// Entries with tags unknown to this version of the library, as (tag,
+    /// value). These are written back unchanged.
+    pub unknown: Vec<(u16, Vec<u8>)>,
+}
+
+impl Metadata {
+    // Entries are stored as tag (u16), length (u32), and value, all in
+    // little endian order.
+    fn to_bytes(&self, header: &Header) -> Vec<u8> {
+        let mut entries: Vec<(u16, Vec<u8>)> = Vec::new();
+
+        if let Some(voxel_size_nm) = self.voxel_size_nm {
+            let value = voxel_size_nm.iter().flat_map(|v| v.to_le_bytes()).collect();
+            entries.push((TAG_VOXEL_SIZE_NM, value));
+        }
+        if let Some(ref axis_order) = self.axis_order {
+            entries.push((TAG_AXIS_ORDER, axis_order.as_bytes().to_vec()));
+        }
+        if let Some(ref fill_value) = self.fill_value {
+            entries.push((TAG_FILL_VALUE, fill_value.clone()));
+        }
+        if header.is_compressed() {
+            entries.push((TAG_COMPRESSION_LEVEL, vec![header.compression_level]));
+        }
+        if header.checksums {
+            entries.push((
+                TAG_CHECKSUM_ALGORITHM,
+                CHECKSUM_ALGORITHM.as_bytes().to_vec(),
+            ));
+        }
+        if let Some(ref creation_tool) = self.creation_tool {
+            entries.push((TAG_CREATION_TOOL, creation_tool.as_bytes().to_vec()));
+        }
+        entries.extend(self.unknown.iter().cloned());
+
+        let mut buf = Vec::new();
+        for (tag, value) in entries {
+            buf.extend_from_slice(&tag.to_le_bytes());
+            buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            buf.extend_from_slice(&value);
+        }
+
+        buf
+    }
+
+    // Parses the metadata section into `header`.
+    fn read_into(mut buf: &[u8], header: &mut Header) -> Result<()> {
+        let mut metadata = Metadata::default();
+
+        while !buf.is_empty() {
+            if buf.len() < 6 {
+                return Err(Self::invalid("truncated entry"));
+            }
+
+            let tag = u16::from_le_bytes([buf[0], buf[1]]);
+            let len = u32::from_le_bytes([buf[2], buf[3], buf[4], buf[5]]) as usize;
+            let value = match buf.get(6..(6 + len)) {
+                Some(value) => value,
+                None => return Err(Self::invalid("truncated entry")),
+            };
+            buf = &buf[(6 + len)..];
+
+            match tag {
+                TAG_VOXEL_SIZE_NM if len == 24 => {
+                    let mut voxel_size_nm = [0f64; 3];
+                    for (dim, chunk) in value.chunks(8).enumerate() {
+                        let mut bytes = [0u8; 8];
+                        bytes.copy_from_slice(chunk);
+                        voxel_size_nm[dim] = f64::from_le_bytes(bytes);
+                    }
+                    metadata.voxel_size_nm = Some(voxel_size_nm);
+                }
+                TAG_VOXEL_SIZE_NM => return Err(Self::invalid("voxel size")),
+                TAG_AXIS_ORDER => metadata.axis_order = Some(Self::string(value)?),
+                TAG_FILL_VALUE => metadata.fill_value = Some(value.to_vec()),
+                TAG_COMPRESSION_LEVEL if len == 1 => header.compression_level = value[0],
+                TAG_COMPRESSION_LEVEL => return Err(Self::invalid("compression level")),
+                TAG_CHECKSUM_ALGORITHM => {
+                    let algorithm = Self::string(value)?;
+                    if algorithm != CHECKSUM_ALGORITHM {
+                        return Err(Error::InvalidHeader {
+                            field: "checksum_algorithm",
+                            value: algorithm,
+                        });
+                    }
+                    header.checksums = true;
+                }
+                TAG_CREATION_TOOL => metadata.creation_tool = Some(Self::string(value)?),
+                tag => metadata.unknown.push((tag, value.to_vec())),
+            }
+        }
+
+        header.metadata = Some(metadata);
+        Ok(())
+    }
+
+    fn string(value: &[u8]) -> Result<String> {
+        String::from_utf8(value.to_vec()).map_err(|_| Self::invalid("string"))
+    }
+
+    fn invalid(what: &str) -> Error {
+        Error::InvalidHeader {
+            field: "metadata",
+            value: String::from(what),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Header {
     pub version: u8,
-    pub block_len_log2: u8,
-    pub file_len_log2: u8,
+    /// Per-axis log2 of the block length, in voxels. Blocks which are not
+    /// cubes require header version 3 or later.
+    pub block_shape_log2: Vec3,
+    /// Per-axis log2 of the file length, in blocks. Files which are not
+    /// cubes require header version 3 or later.
+    pub file_shape_log2: Vec3,
     pub block_type: BlockType,
     pub voxel_type: VoxelType,
     pub voxel_size: u8,
+    pub compression_level: u8,
+    /// Whether files hold a CRC32C checksum per block. Requires header
+    /// version 2 or later.
+    pub checksums: bool,
     pub data_offset: u64,
     pub jump_table: Option<Box<[u64]>>,
+    pub checksum_table: Option<Box<[u32]>>,
+    /// Metadata section of version-2 headers. Must be `None` for version 1.
+    pub metadata: Option<Metadata>,
 }
 
 impl Header {
@@ -72,45 +273,174 @@ impl Header {
     pub fn compress(template: &Header) -> Header {
         let mut header = template.clone();
         header.block_type = BlockType::LZ4HC;
+        header.compression_level = 0;
         header.init();
 
         header
     }
 
+    /// Header of a compressed copy of `template`. Fails if the level is not
+    /// valid for the block type, or if a level other than the default is
+    /// given for a version-1 header.
+    pub fn compress_with(
+        template: &Header,
+        block_type: BlockType,
+        compression_level: u8,
+    ) -> Result<Header> {
+        let mut header = template.clone();
+        header.block_type = block_type;
+        header.compression_level = compression_level;
+        header.check_compression_level()?;
+        header.init();
+
+        Ok(header)
+    }
+
+    pub(crate) fn check_compression_level(&self) -> Result<()> {
+        let levels = self.block_type.compression_levels();
+        if !levels.contains(&self.compression_level) {
+            return Err(Error::invalid(format!(
+                "Compression level {} of block type {:?} is not in {}..={}",
+                self.compression_level,
+                self.block_type,
+                levels.start(),
+                levels.end()
+            )));
+        }
+
+        // the level is part of the metadata section
+        if self.version < 2 && self.compression_level != self.block_type.default_compression_level()
+        {
+            return Err(Error::invalid(
+                "Compression levels require header version 2 or later",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the per-axis lengths fit into the four bits of the
+    /// header, and that the voxels of a file can be counted.
+    pub(crate) fn check_shape(&self) -> Result<()> {
+        let (block, file) = (self.block_shape_log2, self.file_shape_log2);
+        let axes = [(block.x, file.x), (block.y, file.y), (block.z, file.z)];
+
+        for &(block_len_log2, file_len_log2) in &axes {
+            if block_len_log2 > 15 || file_len_log2 > 15 {
+                return Err(Error::invalid(format!(
+                    "Log2 of block length {:?} and file length {:?} must not exceed 15",
+                    block, file
+                )));
+            }
+        }
+
+        let file_vol_vx_log2: u32 = axes.iter().map(|&(block, file)| block + file).sum();
+        if file_vol_vx_log2 > 63 {
+            return Err(Error::invalid("Files must not exceed 2^63 voxels"));
+        }
+
+        Ok(())
+    }
+
     fn init(&mut self) {
+        // version-2 headers always have a metadata section
+        if self.version >= 2 && self.metadata.is_none() {
+            self.metadata = Some(Metadata::default());
+        }
+
         // initialize data offset
         self.data_offset = self.size_on_disk() as u64;
 
         // initialize jump table
         self.jump_table = match self.block_type {
-            BlockType::LZ4 | BlockType::LZ4HC => {
+            BlockType::LZ4 | BlockType::LZ4HC | BlockType::Zstd => {
                 let file_vol = self.file_vol() as usize;
                 let jump_table = vec![0u64; file_vol];
                 Some(jump_table.into_boxed_slice())
             }
             _ => None,
         };
+
+        // initialize checksum table
+        self.checksum_table = match self.checksums {
+            true => {
+                // newly created raw files are zero-filled
+                let checksum = match self.block_type {
+                    BlockType::Raw => crc32c::crc32c(&vec![0u8; self.block_size()]),
+                    _ => crc32c::crc32c(&[]),
+                };
+
+                let file_vol = self.file_vol() as usize;
+                let checksum_table = vec![checksum; file_vol];
+                Some(checksum_table.into_boxed_slice())
+            }
+            false => None,
+        };
     }
 
     pub fn size_on_disk(&self) -> usize {
-        let header_len = 16;
+        let shape_len = match self.version >= 3 {
+            true => 4,
+            false => 0,
+        };
+        let metadata_len = self.metadata_bytes().map_or(0, |buf| 4 + buf.len());
+        let header_len = 16 + shape_len + metadata_len;
 
         let jump_table_len = match self.block_type {
             BlockType::Raw => 0,
-            BlockType::LZ4 | BlockType::LZ4HC => self.file_vol() as usize * mem::size_of::<u64>(),
+            BlockType::LZ4 | BlockType::LZ4HC | BlockType::Zstd => {
+                self.file_vol() as usize * mem::size_of::<u64>()
+            }
         } as usize;
 
-        header_len + jump_table_len
+        let checksum_table_len = match self.checksums {
+            true => self.file_vol() as usize * mem::size_of::<u32>(),
+            false => 0,
+        };
+
+        header_len + jump_table_len + checksum_table_len
     }
 
-    pub fn read(file: &mut fs::File) -> Result<Header> {
+    pub fn read<R: Read>(file: &mut R) -> Result<Header> {
         let mut buf = [0u8; 16];
 
         let mut header = match file.read_exact(&mut buf) {
-            Err(_) => return Err(String::from("Could not read raw header")),
+            Err(err) => return Err(Error::io("Could not read raw header", err)),
             Ok(_) => Self::from_bytes(buf)?,
         };
 
+        // read per-axis lengths
+        if header.version >= 3 {
+            let mut shape_buf = [0u8; 4];
+            file.read_exact(&mut shape_buf)
+                .map_err(|err| Error::io("Could not read block and file shape", err))?;
+            header.read_shape(shape_buf);
+        }
+
+        // read metadata section
+        if header.version >= 2 {
+            let mut len_buf = [0u8; 4];
+            file.read_exact(&mut len_buf)
+                .map_err(|err| Error::io("Could not read metadata length", err))?;
+
+            // do not trust the length before the data has arrived
+            let len = u32::from_le_bytes(len_buf);
+            let mut metadata_buf = Vec::new();
+            file.by_ref()
+                .take(len as u64)
+                .read_to_end(&mut metadata_buf)
+                .map_err(|err| Error::io("Could not read metadata", err))?;
+
+            if metadata_buf.len() != len as usize {
+                return Err(Error::InvalidHeader {
+                    field: "metadata_length",
+                    value: len.to_string(),
+                });
+            }
+
+            Metadata::read_into(&metadata_buf, &mut header)?;
+        }
+
         // in case of the header file, we're done
         if header.data_offset == 0 {
             return Ok(header);
@@ -118,69 +448,178 @@ impl Header {
 
         // read jump table
         header.jump_table = match header.block_type {
-            BlockType::LZ4 | BlockType::LZ4HC => Some(header.read_jump_table(file)?),
+            BlockType::LZ4 | BlockType::LZ4HC | BlockType::Zstd => {
+                Some(header.read_jump_table(file)?)
+            }
             _ => None,
         };
 
+        // read checksum table
+        if header.checksums {
+            header.checksum_table = Some(header.read_checksum_table(file)?);
+        }
+
         Ok(header)
     }
 
-    pub fn write(&self, file: &mut fs::File) -> Result<()> {
-        if file.write_all(&self.to_bytes()).is_err() {
-            return Err(String::from("Could not write header"));
+    pub fn write<W: Write>(&self, file: &mut W) -> Result<()> {
+        if self.version < 2 && self.metadata.is_some() {
+            return Err(Error::invalid(
+                "Metadata requires header version 2 or later",
+            ));
+        }
+
+        self.check_compression_level()?;
+
+        // the checksum algorithm is part of the metadata section
+        if self.version < 2 && self.checksums {
+            return Err(Error::invalid(
+                "Checksums require header version 2 or later",
+            ));
+        }
+
+        self.check_shape()?;
+
+        if self.version < 3 && !self.is_cubic() {
+            return Err(Error::invalid(
+                "Non-cubic blocks or files require header version 3 or later",
+            ));
+        }
+
+        file.write_all(&self.to_bytes())
+            .map_err(|err| Error::io("Could not write header", err))?;
+
+        if self.version >= 3 {
+            file.write_all(&self.shape_bytes())
+                .map_err(|err| Error::io("Could not write block and file shape", err))?;
+        }
+
+        if let Some(metadata_buf) = self.metadata_bytes() {
+            let mut buf = (metadata_buf.len() as u32).to_le_bytes().to_vec();
+            buf.extend_from_slice(&metadata_buf);
+            file.write_all(&buf)
+                .map_err(|err| Error::io("Could not write metadata", err))?;
         }
 
-        match self.jump_table {
-            Some(_) => self.write_jump_table(file),
+        if self.jump_table.is_some() {
+            self.write_jump_table(file)?;
+        }
+
+        match self.checksum_table {
+            Some(_) => self.write_checksum_table(file),
             None => Ok(()),
         }
     }
 
-    fn read_jump_table(&mut self, file: &mut fs::File) -> Result<Box<[u64]>> {
-        // allocate jump table
+    // Per-axis lengths of version-3 headers. As in the fixed header, each
+    // byte holds the file length in its upper and the block length in its
+    // lower four bits, for x, y, and z. The last byte is reserved.
+    fn shape_bytes(&self) -> [u8; 4] {
+        let per_dim = |file_len_log2: u32, block_len_log2: u32| {
+            ((file_len_log2 as u8) << 4) | (block_len_log2 as u8 & 0x0f)
+        };
+
+        let (file, block) = (self.file_shape_log2, self.block_shape_log2);
+        [
+            per_dim(file.x, block.x),
+            per_dim(file.y, block.y),
+            per_dim(file.z, block.z),
+            0,
+        ]
+    }
+
+    fn read_shape(&mut self, buf: [u8; 4]) {
+        self.block_shape_log2 = Vec3 {
+            x: (buf[0] & 0x0f) as u32,
+            y: (buf[1] & 0x0f) as u32,
+            z: (buf[2] & 0x0f) as u32,
+        };
+        self.file_shape_log2 = Vec3 {
+            x: (buf[0] >> 4) as u32,
+            y: (buf[1] >> 4) as u32,
+            z: (buf[2] >> 4) as u32,
+        };
+    }
+
+    // Encoded metadata section, for version-2 headers.
+    fn metadata_bytes(&self) -> Option<Vec<u8>> {
+        match self.version >= 2 {
+            true => {
+                let default = Metadata::default();
+                let metadata = self.metadata.as_ref().unwrap_or(&default);
+                Some(metadata.to_bytes(self))
+            }
+            false => None,
+        }
+    }
+
+    fn read_jump_table<R: Read>(&mut self, file: &mut R) -> Result<Box<[u64]>> {
         let block_count = self.file_vol() as usize;
-        let mut jump_table = Vec::with_capacity(block_count);
+        let mut buf = vec![0u8; block_count * mem::size_of::<u64>()];
 
-        let result = unsafe {
-            // slice of unsigned 64-bit integers
-            jump_table.set_len(block_count);
+        file.read_exact(&mut buf)
+            .map_err(|err| Error::io("Could not read jump table", err))?;
 
-            // slice of unsigned 8-bit integers
-            let buf_u8_len = jump_table.len() * mem::size_of::<u64>();
-            let buf_u8_ptr = jump_table.as_mut_ptr() as *mut u8;
-            let buf_u8 = slice::from_raw_parts_mut(buf_u8_ptr, buf_u8_len);
+        let jump_table: Vec<u64> = buf
+            .chunks(mem::size_of::<u64>())
+            .map(|chunk| {
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(chunk);
+                u64::from_le_bytes(bytes)
+            })
+            .collect();
 
-            // read jump table
-            file.read_exact(buf_u8)
-        };
+        Ok(jump_table.into_boxed_slice())
+    }
+
+    fn write_jump_table<W: Write>(&self, file: &mut W) -> Result<()> {
+        let jump_table = self.jump_table.as_ref().unwrap();
 
-        match result {
-            Ok(_) => Ok(jump_table.into_boxed_slice()),
-            Err(_) => Err(String::from("Could not read jump table")),
+        let mut buf = Vec::with_capacity(jump_table.len() * mem::size_of::<u64>());
+        for entry in jump_table.iter() {
+            buf.extend_from_slice(&entry.to_le_bytes());
         }
+
+        file.write_all(&buf)
+            .map_err(|err| Error::io("Could not write jump table", err))
     }
 
-    fn write_jump_table(&self, file: &mut fs::File) -> Result<()> {
-        let jump_table = &*self.jump_table.as_ref().unwrap();
+    fn read_checksum_table<R: Read>(&self, file: &mut R) -> Result<Box<[u32]>> {
+        let block_count = self.file_vol() as usize;
+        let mut buf = vec![0u8; block_count * mem::size_of::<u32>()];
 
-        let result = unsafe {
-            let buf_u8_len = jump_table.len() * mem::size_of::<u64>();
-            let buf_u8_ptr = jump_table.as_ptr() as *const u8;
-            let buf_u8 = slice::from_raw_parts(buf_u8_ptr, buf_u8_len);
+        file.read_exact(&mut buf)
+            .map_err(|err| Error::io("Could not read checksum table", err))?;
 
-            // write jump table
-            file.write_all(buf_u8)
-        };
+        let checksum_table: Vec<u32> = buf
+            .chunks(mem::size_of::<u32>())
+            .map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+            .collect();
 
-        match result {
-            Ok(_) => Ok(()),
-            Err(_) => Err(String::from("Could not write jump table")),
+        Ok(checksum_table.into_boxed_slice())
+    }
+
+    fn write_checksum_table<W: Write>(&self, file: &mut W) -> Result<()> {
+        let checksum_table = self.checksum_table.as_ref().unwrap();
+
+        let mut buf = Vec::with_capacity(checksum_table.len() * mem::size_of::<u32>());
+        for checksum in checksum_table.iter() {
+            buf.extend_from_slice(&checksum.to_le_bytes());
         }
+
+        file.write_all(&buf)
+            .map_err(|err| Error::io("Could not write checksum table", err))
+    }
+
+    pub fn block_checksum(&self, block_idx: u64) -> Option<u32> {
+        self.checksum_table
+            .as_ref()
+            .and_then(|checksum_table| checksum_table.get(block_idx as usize).cloned())
     }
 
     pub fn block_offset(&self, block_idx: u64) -> Result<u64> {
         if block_idx >= self.file_vol() {
-            return Err(String::from("Block index out of bounds"));
+            return Err(Error::OutOfBounds("Block index"));
         }
 
         let offset = match self.block_type {
@@ -188,11 +627,11 @@ impl Header {
                 let block_size = self.block_size() as u64;
                 self.data_offset + block_idx * block_size
             }
-            BlockType::LZ4 | BlockType::LZ4HC => {
+            BlockType::LZ4 | BlockType::LZ4HC | BlockType::Zstd => {
                 if block_idx == 0 {
                     self.data_offset
                 } else {
-                    let jump_table = &*self.jump_table.as_ref().unwrap();
+                    let jump_table = self.jump_table.as_ref().unwrap();
                     jump_table[block_idx as usize - 1]
                 }
             }
@@ -201,27 +640,31 @@ impl Header {
         Ok(offset)
     }
 
+    /// Number of bytes a block takes up on disk. In compressed files, zero
+    /// marks a block which is not stored and consists of the fill value.
     pub fn block_size_on_disk(&self, block_idx: u64) -> Result<usize> {
         match self.block_type {
-            BlockType::Raw => Ok(self.block_size() as usize),
-            BlockType::LZ4 | BlockType::LZ4HC => {
-                let jump_table = &*self.jump_table.as_ref().unwrap();
+            BlockType::Raw => Ok(self.block_size()),
+            BlockType::LZ4 | BlockType::LZ4HC | BlockType::Zstd => {
+                let jump_table = self.jump_table.as_ref().unwrap();
 
                 if block_idx == 0 {
                     let block_size = jump_table[0].checked_sub(self.data_offset);
                     match block_size {
                         Some(block_size) => Ok(block_size as usize),
-                        None => Err(String::from("Corrupt jump table")),
+                        None => Err(Error::CorruptJumpTable { block_idx }),
                     }
                 } else if block_idx < self.file_vol() {
                     let block_idx = block_idx as usize;
                     let block_size = jump_table[block_idx].checked_sub(jump_table[block_idx - 1]);
                     match block_size {
                         Some(block_size) => Ok(block_size as usize),
-                        None => Err(String::from("Corrupt jump table")),
+                        None => Err(Error::CorruptJumpTable {
+                            block_idx: block_idx as u64,
+                        }),
                     }
                 } else {
-                    Err(String::from("Block index out of bounds"))
+                    Err(Error::OutOfBounds("Block index"))
                 }
             }
         }
@@ -233,6 +676,7 @@ impl Header {
         match self.block_type {
             BlockType::Raw => block_size,
             BlockType::LZ4 | BlockType::LZ4HC => lz4::compress_bound(block_size),
+            BlockType::Zstd => zstd::compress_bound(block_size),
         }
     }
 
@@ -242,7 +686,7 @@ impl Header {
 
     pub fn num_channels(&self) -> usize {
         let voxel_size = self.voxel_size as usize;
-        assert!(voxel_size % self.voxel_type_size() == 0);
+        assert!(voxel_size.is_multiple_of(self.voxel_type_size()));
         voxel_size / self.voxel_type_size()
     }
 
@@ -251,31 +695,41 @@ impl Header {
     }
 
     pub fn is_compressed(&self) -> bool {
-        return self.block_type == BlockType::LZ4 || self.block_type == BlockType::LZ4HC;
+        self.block_type != BlockType::Raw
     }
 
     fn from_bytes(buf: [u8; 16]) -> Result<Header> {
-        let raw: HeaderRaw = unsafe { mem::transmute(buf) };
+        let raw = HeaderRaw::from_bytes(buf);
 
         if &raw.magic != b"WKW" {
-            return Err(format!(
-                "Sequence of magic bytes {:?} is invalid",
-                &raw.magic
-            ));
+            return Err(Error::InvalidHeader {
+                field: "magic",
+                value: format!("{:?}", &raw.magic),
+            });
         }
 
-        if raw.version != 1 {
-            return Err(format!("Version number '{}' is invalid", raw.version));
+        if raw.version < 1 || raw.version > 3 {
+            return Err(Error::InvalidHeader {
+                field: "version",
+                value: raw.version.to_string(),
+            });
         }
 
-        let block_len_log2 = raw.per_dim_log2 & 0x0f;
-        let file_len_log2 = raw.per_dim_log2 >> 4;
+        // version-3 headers store per-axis lengths after the fixed header
+        let block_len_log2 = (raw.per_dim_log2 & 0x0f) as u32;
+        let file_len_log2 = (raw.per_dim_log2 >> 4) as u32;
 
         let block_type = match raw.block_type {
             1 => BlockType::Raw,
             2 => BlockType::LZ4,
             3 => BlockType::LZ4HC,
-            other => return Err(format!("Block type '{}' is invalid", other)),
+            4 => BlockType::Zstd,
+            other => {
+                return Err(Error::InvalidHeader {
+                    field: "block_type",
+                    value: other.to_string(),
+                })
+            }
         };
 
         let voxel_type = match raw.voxel_type {
@@ -289,68 +743,242 @@ impl Header {
             8 => VoxelType::I16,
             9 => VoxelType::I32,
             10 => VoxelType::I64,
-            other => return Err(format!("Voxel type '{}' is invalid", other)),
+            other => {
+                return Err(Error::InvalidHeader {
+                    field: "voxel_type",
+                    value: other.to_string(),
+                })
+            }
         };
 
         Ok(Header {
             version: raw.version,
-            block_len_log2,
-            file_len_log2,
+            block_shape_log2: Vec3::from(block_len_log2),
+            file_shape_log2: Vec3::from(file_len_log2),
             block_type,
             voxel_type,
             voxel_size: raw.voxel_size,
+            // both overridden by the metadata of version-2 headers
+            compression_level: block_type.default_compression_level(),
+            checksums: false,
             data_offset: raw.data_offset,
             jump_table: None,
+            checksum_table: None,
+            metadata: None,
         })
     }
 
     pub fn to_bytes(&self) -> [u8; 16] {
-        let per_dim_log2 = (self.file_len_log2 << 4) | (self.block_len_log2 & 0x0f);
+        // unused in version-3 headers
+        let per_dim_log2 = match self.version >= 3 {
+            true => 0,
+            false => {
+                let block_len_log2 = self.block_shape_log2.x as u8;
+                let file_len_log2 = self.file_shape_log2.x as u8;
+                (file_len_log2 << 4) | (block_len_log2 & 0x0f)
+            }
+        };
+        let block_type = 1u8 + self.block_type as u8;
+        let voxel_type = 1u8 + self.voxel_type as u8;
 
-        let mut raw = HeaderRaw {
-            magic: [0u8; 3],
+        let raw = HeaderRaw {
+            magic: *b"WKW",
             version: self.version,
             per_dim_log2,
-            block_type: 1u8 + self.block_type as u8,
-            voxel_type: 1u8 + self.voxel_type as u8,
+            block_type,
+            voxel_type,
             voxel_size: self.voxel_size,
             data_offset: self.data_offset,
         };
 
-        // set magic bytes
-        raw.magic.copy_from_slice(b"WKW");
-
-        // convert to bytes
-        unsafe { mem::transmute::<HeaderRaw, [u8; 16]>(raw) }
+        raw.to_bytes()
     }
 
+    /// Log2 of the block length of cubic blocks.
+    #[deprecated(note = "use `block_shape_log2`")]
+    pub fn block_len_log2(&self) -> u8 {
+        assert!(self.is_cubic(), "Blocks and files are not cubic");
+        self.block_shape_log2.x as u8
+    }
+    /// Log2 of the file length of cubic files, in blocks.
+    #[deprecated(note = "use `file_shape_log2`")]
+    pub fn file_len_log2(&self) -> u8 {
+        assert!(self.is_cubic(), "Blocks and files are not cubic");
+        self.file_shape_log2.x as u8
+    }
+    #[deprecated(note = "use `block_shape`")]
+    #[allow(deprecated)]
     pub fn block_len(&self) -> u16 {
-        1u16 << self.block_len_log2
+        1u16 << self.block_len_log2()
+    }
+    #[deprecated(note = "use `file_shape`")]
+    #[allow(deprecated)]
+    pub fn file_len(&self) -> u16 {
+        1u16 << self.file_len_log2()
+    }
+
+    /// Whether blocks and files are cubes.
+    pub fn is_cubic(&self) -> bool {
+        let (block, file) = (self.block_shape_log2, self.file_shape_log2);
+        block == Vec3::from(block.x) && file == Vec3::from(file.x)
+    }
+
+    pub fn block_shape(&self) -> Vec3 {
+        Vec3::from(1u32) << self.block_shape_log2
     }
     pub fn block_vol(&self) -> u64 {
-        1u64 << (3 * self.block_len_log2)
+        self.block_shape().product()
     }
     pub fn block_size(&self) -> usize {
         self.voxel_size as usize * self.block_vol() as usize
     }
 
-    pub fn file_len(&self) -> u16 {
-        1u16 << self.file_len_log2
+    /// Shape of a file, in blocks.
+    pub fn file_shape(&self) -> Vec3 {
+        Vec3::from(1u32) << self.file_shape_log2
     }
     pub fn file_vol(&self) -> u64 {
-        1u64 << (3 * self.file_len_log2)
+        self.file_shape().product()
     }
 
-    pub fn file_len_vx_log2(&self) -> u8 {
-        self.file_len_log2 + self.block_len_log2
+    pub fn file_shape_vx_log2(&self) -> Vec3 {
+        self.file_shape_log2 + self.block_shape_log2
     }
-    pub fn file_len_vx(&self) -> u32 {
-        1u32 << self.file_len_vx_log2() as u32
+    pub fn file_shape_vx(&self) -> Vec3 {
+        Vec3::from(1u32) << self.file_shape_vx_log2()
     }
     pub fn file_vol_vx(&self) -> u64 {
-        1u64 << (3 * self.file_len_vx_log2() as u64)
+        self.file_shape_vx().product()
     }
     pub fn file_size(&self) -> usize {
         self.voxel_size as usize * self.file_vol_vx() as usize
     }
+
+    // Single-channel header with cubic blocks and files, the default
+    // compression level, and neither checksums nor metadata.
+    #[cfg(test)]
+    pub(crate) fn for_tests(
+        version: u8,
+        block_len_log2: u32,
+        file_len_log2: u32,
+        block_type: BlockType,
+        voxel_type: VoxelType,
+    ) -> Header {
+        Header {
+            version,
+            block_shape_log2: Vec3::from(block_len_log2),
+            file_shape_log2: Vec3::from(file_len_log2),
+            block_type,
+            voxel_type,
+            voxel_size: voxel_type.size() as u8,
+            compression_level: block_type.default_compression_level(),
+            checksums: false,
+            data_offset: 0,
+            jump_table: None,
+            checksum_table: None,
+            metadata: None,
+        }
+    }
+}
+
+#[test]
+fn test_metadata() {
+    let mut header = Header {
+        compression_level: 9,
+        checksums: true,
+        metadata: Some(Metadata {
+            voxel_size_nm: Some([11.24, 11.24, 28.0]),
+            axis_order: Some(String::from("xyz")),
+            fill_value: Some(vec![1, 2]),
+            creation_tool: Some(String::from("test")),
+            unknown: vec![(0x7fff, vec![42])],
+        }),
+        ..Header::for_tests(2, 2, 1, BlockType::LZ4HC, VoxelType::U16)
+    };
+    header.init();
+
+    let mut buf = Vec::new();
+    header.write(&mut buf).unwrap();
+    assert_eq!(buf.len(), header.size_on_disk());
+    assert_eq!(header.data_offset, buf.len() as u64);
+    assert_eq!(Header::read(&mut &buf[..]).unwrap(), header);
+    assert_eq!((buf[5], buf[6]), (3, 2));
+
+    // version-1 headers are unchanged and have no metadata
+    header.version = 1;
+    assert!(header.write(&mut Vec::new()).is_err());
+
+    header.metadata = None;
+    header.init();
+    assert!(header.write(&mut Vec::new()).is_err());
+
+    // neither the level nor checksums are part of version-1 headers
+    header.compression_level = 0;
+    assert!(header.write(&mut Vec::new()).is_err());
+
+    header.checksums = false;
+    header.init();
+    assert_eq!(header.size_on_disk(), 16 + 8 * 8);
+    let mut buf = Vec::new();
+    header.write(&mut buf).unwrap();
+    assert_eq!(buf[3], 1);
+    assert_eq!(buf[6], 2);
+    assert_eq!(Header::read(&mut &buf[..]).unwrap(), header);
+
+    // unsupported checksum algorithm
+    let mut buf = header.to_bytes().to_vec();
+    buf[3] = 2;
+    buf.extend_from_slice(&12u32.to_le_bytes());
+    buf.extend_from_slice(&TAG_CHECKSUM_ALGORITHM.to_le_bytes());
+    buf.extend_from_slice(&6u32.to_le_bytes());
+    buf.extend_from_slice(b"xxh64!");
+    assert!(Header::read(&mut &buf[..]).is_err());
+
+    // metadata section longer than the file
+    let mut buf = header.to_bytes().to_vec();
+    buf[3] = 2;
+    buf.extend_from_slice(&u32::MAX.to_le_bytes());
+    buf.extend_from_slice(&[0u8; 6]);
+    match Header::read(&mut &buf[..]) {
+        Err(Error::InvalidHeader { field, .. }) => assert_eq!(field, "metadata_length"),
+        other => panic!("Unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn test_non_cubic() {
+    let mut header = Header {
+        block_shape_log2: Vec3 { x: 5, y: 5, z: 3 },
+        file_shape_log2: Vec3 { x: 1, y: 2, z: 0 },
+        ..Header::for_tests(3, 0, 0, BlockType::Zstd, VoxelType::U8)
+    };
+    header.init();
+    assert!(!header.is_cubic());
+    assert_eq!(header.block_vol(), 32 * 32 * 8);
+    assert_eq!(header.file_vol(), 8);
+
+    let mut buf = Vec::new();
+    header.write(&mut buf).unwrap();
+    assert_eq!(buf.len(), header.size_on_disk());
+    assert_eq!(&buf[16..20], &[0x15, 0x25, 0x03, 0x00]);
+    assert_eq!(Header::read(&mut &buf[..]).unwrap(), header);
+
+    // earlier versions can only describe cubes
+    header.version = 2;
+    assert!(header.write(&mut Vec::new()).is_err());
+
+    // lengths must fit into four bits
+    header.version = 3;
+    header.file_shape_log2.z = 16;
+    assert!(header.write(&mut Vec::new()).is_err());
+
+    let cubic = Header::for_tests(1, 5, 3, BlockType::Raw, VoxelType::U8);
+    #[allow(deprecated)]
+    let lens = (
+        cubic.block_len_log2(),
+        cubic.file_len_log2(),
+        cubic.block_len(),
+        cubic.file_len(),
+    );
+    assert_eq!(lens, (5, 3, 32, 8));
 }
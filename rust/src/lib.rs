@@ -1,20 +1,45 @@
+extern crate crc32c;
+extern crate flate2;
+#[cfg(feature = "s3")]
+extern crate hmac;
+extern crate memmap2;
+#[macro_use]
+extern crate serde_json;
+#[cfg(feature = "s3")]
+extern crate sha2;
+extern crate tiff;
+#[cfg(feature = "s3")]
+extern crate ureq;
+extern crate xxhash_rust;
+
 // public modules
+pub mod convert;
 pub mod dataset;
+pub mod error;
 pub mod file;
 pub mod header;
 pub mod mat;
 pub mod morton;
+pub mod pyramid;
 pub mod result;
+pub mod storage;
 pub mod vec;
+pub mod verify;
 
 // private modules
+mod cache;
 mod lz4;
+mod zstd;
 
 // convenience
+pub use cache::CacheStats;
 pub use dataset::Dataset;
+pub use error::Error;
 pub use file::File;
-pub use header::{BlockType, Header, VoxelType};
+pub use header::{BlockType, Header, Metadata, VoxelType};
 pub use mat::Mat;
 pub use morton::{Iter, Morton};
 pub use result::Result;
+pub use storage::{LocalStorage, MemoryStorage, Storage};
 pub use vec::{Box3, Vec3};
+pub use verify::{Problem, Report};
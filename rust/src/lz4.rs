@@ -1,6 +1,6 @@
 extern crate lz4 as lz4_crate;
 use self::lz4_crate::liblz4;
-use Result;
+use {Error, Result};
 
 pub fn compress_bound(input_size: usize) -> usize {
     unsafe { liblz4::LZ4_compressBound(input_size as i32) as usize }
@@ -20,15 +20,18 @@ pub fn compress(src_buf: &[u8], dst_buf: &mut [u8]) -> Result<usize> {
     };
 
     match dst_len == 0 {
-        true => Err(String::from("Error in LZ4_compress_default")),
+        true => Err(Error::Compression("Error in LZ4_compress_default")),
         false => Ok(dst_len as usize),
     }
 }
 
-pub fn compress_hc(src_buf: &[u8], dst_buf: &mut [u8]) -> Result<usize> {
+pub fn compress_hc(src_buf: &[u8], dst_buf: &mut [u8], level: u8) -> Result<usize> {
     let src_size = src_buf.len() as i32;
     let dst_capacity = dst_buf.len() as i32;
-    let compression_level = 9;
+    let compression_level = match level {
+        0 => 9,
+        level => level as i32,
+    };
 
     let dst_len = unsafe {
         liblz4::LZ4_compress_HC(
@@ -41,7 +44,7 @@ pub fn compress_hc(src_buf: &[u8], dst_buf: &mut [u8]) -> Result<usize> {
     };
 
     match dst_len == 0 {
-        true => Err(String::from("Error in LZ4_compress_HC")),
+        true => Err(Error::Compression("Error in LZ4_compress_HC")),
         false => Ok(dst_len as usize),
     }
 }
@@ -60,7 +63,9 @@ pub fn decompress_safe(src_buf: &[u8], dst_buf: &mut [u8]) -> Result<usize> {
     };
 
     match dst_len < 0 {
-        true => Err(String::from("Error in LZ4_decompress_safe")),
+        true => Err(Error::Decompression(String::from(
+            "Error in LZ4_decompress_safe",
+        ))),
         false => Ok(dst_len as usize),
     }
 }
@@ -1,6 +1,6 @@
 use std::ptr;
 
-use {Box3, Result, Vec3, VoxelType};
+use {Box3, Error, Result, Vec3, VoxelType};
 
 #[derive(Debug)]
 pub struct Mat<'a> {
@@ -15,6 +15,70 @@ pub fn linearize(channel: usize, x: usize, y: usize, z: usize, stride: &[usize])
     (channel * stride[0] + x * stride[1] + y * stride[2] + z * stride[3]) as isize
 }
 
+/// Read-only counterpart of `Mat`, e.g. for blocks of memory mapped files.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct MatView<'a> {
+    data: &'a [u8],
+    pub shape: Vec3,
+    pub voxel_size: usize,
+    pub voxel_type: VoxelType,
+    pub data_in_c_order: bool,
+}
+
+impl<'a> MatView<'a> {
+    pub fn new(
+        data: &[u8],
+        shape: Vec3,
+        voxel_size: usize,
+        voxel_type: VoxelType,
+        data_in_c_order: bool,
+    ) -> Result<MatView<'_>> {
+        check_layout(data.len(), shape, voxel_size, voxel_type)?;
+
+        Ok(MatView {
+            data,
+            shape,
+            voxel_size,
+            voxel_type,
+            data_in_c_order,
+        })
+    }
+
+    fn offset(&self, pos: Vec3) -> usize {
+        // Early usize cast is necessary as overflows happen
+        let offset_vx = if self.data_in_c_order {
+            pos.z as usize
+                + self.shape.z as usize * (pos.y as usize + self.shape.y as usize * pos.x as usize)
+        } else {
+            pos.x as usize
+                + self.shape.x as usize * (pos.y as usize + self.shape.y as usize * pos.z as usize)
+        };
+        offset_vx * self.voxel_size
+    }
+}
+
+fn check_layout(len: usize, shape: Vec3, voxel_size: usize, voxel_type: VoxelType) -> Result<()> {
+    // make sure that slice is large enough
+    let numel = shape.x as usize * shape.y as usize * shape.z as usize;
+    let expected_len = numel * voxel_size;
+    if len != expected_len {
+        return Err(Error::invalid(format!(
+            "Length of slice does not match expected size {} != {}",
+            len, expected_len
+        )));
+    }
+
+    if !voxel_size.is_multiple_of(voxel_type.size()) {
+        return Err(Error::invalid(format!(
+            "Voxel size must be a multiple of voxel type size {} % {} != 0",
+            voxel_size,
+            voxel_type.size()
+        )));
+    }
+
+    Ok(())
+}
+
 impl<'a> Mat<'a> {
     pub fn new(
         data: &mut [u8],
@@ -22,25 +86,8 @@ impl<'a> Mat<'a> {
         voxel_size: usize,
         voxel_type: VoxelType,
         data_in_c_order: bool,
-    ) -> Result<Mat> {
-        // make sure that slice is large enough
-        let numel = shape.x as usize * shape.y as usize * shape.z as usize;
-        let expected_len = numel * voxel_size;
-        if data.len() != expected_len {
-            return Err(format!(
-                "Length of slice does not match expected size {} != {}",
-                data.len(),
-                expected_len
-            ));
-        }
-
-        if voxel_size % voxel_type.size() != 0 {
-            return Err(format!(
-                "Voxel size must be a multiple of voxel type size {} % {} != 0",
-                voxel_size,
-                voxel_type.size()
-            ));
-        }
+    ) -> Result<Mat<'_>> {
+        check_layout(data.len(), shape, voxel_size, voxel_type)?;
 
         Ok(Mat {
             data,
@@ -51,6 +98,16 @@ impl<'a> Mat<'a> {
         })
     }
 
+    pub(crate) fn view(&self) -> MatView<'_> {
+        MatView {
+            data: self.data,
+            shape: self.shape,
+            voxel_size: self.voxel_size,
+            voxel_type: self.voxel_type,
+            data_in_c_order: self.data_in_c_order,
+        }
+    }
+
     pub fn as_slice(&self) -> &[u8] {
         self.data
     }
@@ -62,38 +119,30 @@ impl<'a> Mat<'a> {
     }
 
     fn offset(&self, pos: Vec3) -> usize {
-        // Early usize cast is necessary as overflows happen
-        let offset_vx = if self.data_in_c_order {
-            pos.z as usize
-                + self.shape.z as usize * (pos.y as usize + self.shape.y as usize * pos.x as usize)
-        } else {
-            pos.x as usize
-                + self.shape.x as usize * (pos.y as usize + self.shape.y as usize * pos.z as usize)
-        };
-        offset_vx * self.voxel_size
+        self.view().offset(pos)
     }
 
     pub fn copy_as_fortran_order(&self, buffer: &mut Mat, src_bbox: Box3) -> Result<()> {
         if !self.data_in_c_order {
-            return Err(String::from("Mat is already in fortran order"));
+            return Err(Error::invalid("Mat is already in fortran order"));
         }
         if self.voxel_size != buffer.voxel_size {
-            return Err(format!(
-                "Matrices mismatch in voxel size {} != {}",
-                self.voxel_size, buffer.voxel_size
-            ));
+            return Err(Error::VoxelSizeMismatch {
+                expected: self.voxel_size,
+                found: buffer.voxel_size,
+            });
         }
         if self.voxel_type != buffer.voxel_type {
-            return Err(format!(
-                "Matrices mismatch in voxel type {:?} != {:?}",
-                self.voxel_type, buffer.voxel_type
-            ));
+            return Err(Error::VoxelTypeMismatch {
+                expected: self.voxel_type,
+                found: buffer.voxel_type,
+            });
         }
         if self.shape != buffer.shape {
-            return Err(format!(
-                "Matrices mismatch in shape {:?} != {:?}",
-                self.shape, buffer.shape
-            ));
+            return Err(Error::ShapeMismatch {
+                expected: self.shape,
+                found: buffer.shape,
+            });
         }
 
         let buffer_data = buffer.as_mut_slice();
@@ -151,7 +200,7 @@ impl<'a> Mat<'a> {
         intermediate_buffer: &mut Mat,
     ) -> Result<()> {
         if self.data_in_c_order {
-            return Err(String::from(
+            return Err(Error::invalid(
                 "copy_from_order_agnostic has to be called on a fortran order buffer.",
             ));
         }
@@ -172,28 +221,81 @@ impl<'a> Mat<'a> {
         }
     }
 
+    /// Sets all voxels in `dst_box` to `value`, which holds the bytes of a
+    /// single voxel.
+    // `Vec3` is only partially ordered, so `!(a < b)` is not `a >= b`
+    #[allow(clippy::neg_cmp_op_on_partial_ord)]
+    pub fn fill(&mut self, dst_box: Box3, value: &[u8]) -> Result<()> {
+        if value.len() != self.voxel_size {
+            return Err(Error::VoxelSizeMismatch {
+                expected: self.voxel_size,
+                found: value.len(),
+            });
+        }
+        if !(dst_box.max() < (self.shape + 1)) {
+            return Err(Error::OutOfBounds("Writing"));
+        }
+
+        // unified has fast to slow moving indices
+        let (length, shape) = match self.data_in_c_order {
+            true => (dst_box.width().flip(), self.shape.flip()),
+            false => (dst_box.width(), self.shape),
+        };
+
+        let stripe: Vec<u8> = value
+            .iter()
+            .cycle()
+            .take(value.len() * length.x as usize)
+            .cloned()
+            .collect();
+
+        let inner_offset = shape.x as usize * self.voxel_size;
+        let outer_offset = shape.y as usize * inner_offset;
+        let offset = self.offset(dst_box.min());
+
+        for z in 0..length.z as usize {
+            for y in 0..length.y as usize {
+                let start = offset + z * outer_offset + y * inner_offset;
+                self.data[start..(start + stripe.len())].copy_from_slice(&stripe);
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn copy_from(&mut self, dst_pos: Vec3, src: &Mat, src_box: Box3) -> Result<()> {
+        self.copy_from_view(dst_pos, src.view(), src_box)
+    }
+
+    // `Vec3` is only partially ordered, so `!(a < b)` is not `a >= b`
+    #[allow(clippy::neg_cmp_op_on_partial_ord)]
+    pub(crate) fn copy_from_view(
+        &mut self,
+        dst_pos: Vec3,
+        src: MatView,
+        src_box: Box3,
+    ) -> Result<()> {
         // make sure that matrices are matching
         if self.voxel_size != src.voxel_size {
-            return Err(format!(
-                "Matrices mismatch in voxel size {} != {}",
-                self.voxel_size, src.voxel_size
-            ));
+            return Err(Error::VoxelSizeMismatch {
+                expected: self.voxel_size,
+                found: src.voxel_size,
+            });
         }
         if self.voxel_type != src.voxel_type {
-            return Err(format!(
-                "Matrices mismatch in voxel type {:?} != {:?}",
-                self.voxel_type, src.voxel_type
-            ));
+            return Err(Error::VoxelTypeMismatch {
+                expected: self.voxel_type,
+                found: src.voxel_type,
+            });
         }
         if !(src_box.max() < (src.shape + 1)) {
-            return Err(String::from("Reading out of bounds"));
+            return Err(Error::OutOfBounds("Reading"));
         }
         if !(dst_pos + src_box.width() < (self.shape + 1)) {
-            return Err(String::from("Writing out of bounds"));
+            return Err(Error::OutOfBounds("Writing"));
         }
         if self.data_in_c_order != src.data_in_c_order {
-            return Err(String::from(
+            return Err(Error::invalid(
                 "Source and destination has to be the same order",
             ));
         }
@@ -252,6 +354,8 @@ impl<'a> Mat<'a> {
         Ok(())
     }
 
+    // `Vec3` is only partially ordered, so `!(a < b)` is not `a >= b`
+    #[allow(clippy::neg_cmp_op_on_partial_ord)]
     pub fn copy_from_and_put_channels_last(
         &mut self,
         dst_pos: Vec3,
@@ -260,25 +364,27 @@ impl<'a> Mat<'a> {
     ) -> Result<()> {
         // make sure that matrices are matching
         if self.voxel_size != src.voxel_size {
-            return Err(format!(
-                "Matrices mismatch in voxel size {} != {}",
-                self.voxel_size, src.voxel_size
-            ));
+            return Err(Error::VoxelSizeMismatch {
+                expected: self.voxel_size,
+                found: src.voxel_size,
+            });
         }
         if self.voxel_type != src.voxel_type {
-            return Err(format!(
-                "Matrices mismatch in voxel type {:?} != {:?}",
-                self.voxel_type, src.voxel_type
-            ));
+            return Err(Error::VoxelTypeMismatch {
+                expected: self.voxel_type,
+                found: src.voxel_type,
+            });
         }
         if !(src_box.max() < (src.shape + 1)) {
-            return Err(String::from("Reading out of bounds"));
+            return Err(Error::OutOfBounds("Reading"));
         }
         if !(dst_pos + src_box.width() < (self.shape + 1)) {
-            return Err(String::from("Writing out of bounds"));
+            return Err(Error::OutOfBounds("Writing"));
         }
         if !(self.data_in_c_order & src.data_in_c_order) {
-            return Err(String::from("Source and destination have to be in c-order"));
+            return Err(Error::invalid(
+                "Source and destination have to be in c-order",
+            ));
         }
 
         let length = src_box.width();
@@ -28,6 +28,78 @@ fn unshuffle(z: u64) -> u64 {
     v
 }
 
+impl Morton {
+    /// Index of `vec` in a grid of shape `1 << log2`. Bits are interleaved
+    /// as in the cubic case, skipping axes which have run out of bits.
+    pub fn from_vec(vec: &Vec3, log2: Vec3) -> Morton {
+        if log2.x == log2.y && log2.y == log2.z {
+            return Morton::from(vec);
+        }
+
+        let mut idx = 0u64;
+        let mut bit = 0;
+        for level in 0..max_log2(log2) {
+            for &(len_log2, v) in &[(log2.x, vec.x), (log2.y, vec.y), (log2.z, vec.z)] {
+                if level < len_log2 {
+                    idx |= (((v >> level) & 1) as u64) << bit;
+                    bit += 1;
+                }
+            }
+        }
+
+        Morton(idx)
+    }
+
+    /// Inverse of `Morton::from_vec`.
+    pub fn to_vec(&self, log2: Vec3) -> Vec3 {
+        if log2.x == log2.y && log2.y == log2.z {
+            return Vec3::from(Morton(self.0));
+        }
+
+        let mut vec = [0u32; 3];
+        let mut bit = 0;
+        for level in 0..max_log2(log2) {
+            for (dim, &len_log2) in [log2.x, log2.y, log2.z].iter().enumerate() {
+                if level < len_log2 {
+                    vec[dim] |= (((self.0 >> bit) & 1) as u32) << level;
+                    bit += 1;
+                }
+            }
+        }
+
+        Vec3 {
+            x: vec[0],
+            y: vec[1],
+            z: vec[2],
+        }
+    }
+}
+
+fn max_log2(log2: Vec3) -> u32 {
+    cmp::max(log2.x, cmp::max(log2.y, log2.z))
+}
+
+// Shape (log2) of the box covered by `bits` consecutive Morton indices,
+// starting at a multiple of `1 << bits`.
+fn box_log2(bits: u32, log2: Vec3) -> Vec3 {
+    let mut shape = [0u32; 3];
+    let mut bit = 0;
+    for level in 0..max_log2(log2) {
+        for (dim, &len_log2) in [log2.x, log2.y, log2.z].iter().enumerate() {
+            if level < len_log2 && bit < bits {
+                shape[dim] += 1;
+                bit += 1;
+            }
+        }
+    }
+
+    Vec3 {
+        x: shape[0],
+        y: shape[1],
+        z: shape[2],
+    }
+}
+
 impl<'a> From<&'a Vec3> for Morton {
     fn from(vec: &'a Vec3) -> Morton {
         Morton(
@@ -61,12 +133,18 @@ impl From<u64> for Morton {
 pub struct Iter {
     idx: u64,
     end: u64,
-    log2: u32,
+    log2: Vec3,
     bbox: Box3,
 }
 
 impl Iter {
     pub fn new(log2: u32, bbox: Box3) -> Result<Iter> {
+        Self::with_shape(Vec3::from(log2), bbox)
+    }
+
+    /// Iterates over the Morton indices (see `Morton::from_vec`) of a grid
+    /// of shape `1 << log2` which lie within `bbox`.
+    pub fn with_shape(log2: Vec3, bbox: Box3) -> Result<Iter> {
         Ok(Iter {
             idx: 0,
             end: 0,
@@ -76,17 +154,18 @@ impl Iter {
     }
 
     fn find_range(&self) -> Option<(u64, u64)> {
-        let max_idx = 1 << (3 * self.log2 as u64);
+        let max_bits = self.log2.x + self.log2.y + self.log2.z;
+        let max_idx = 1 << max_bits as u64;
 
         // initialize state
         let mut cur_idx = self.idx;
-        let mut cur_log2 = cmp::min(self.log2, cur_idx.trailing_zeros() / 3);
+        let mut cur_bits = cmp::min(max_bits, cur_idx.trailing_zeros());
 
         while cur_idx < max_idx {
-            let numel = 1 << (3 * cur_log2);
-            let off = Vec3::from(Morton(cur_idx));
+            let numel = 1 << cur_bits;
+            let off = Morton(cur_idx).to_vec(self.log2);
 
-            let bbox = Box3::from(Vec3::from(1 << cur_log2)) + off;
+            let bbox = Box3::from(Vec3::from(1u32) << box_log2(cur_bits, self.log2)) + off;
             let bbox_inter = bbox.intersect(self.bbox);
 
             if bbox == bbox_inter {
@@ -95,12 +174,12 @@ impl Iter {
                 return Some((cur_idx, end_idx));
             } else if !bbox_inter.is_empty() {
                 // need to refine
-                debug_assert!(cur_log2 > 0);
-                cur_log2 -= 1;
+                debug_assert!(cur_bits > 0);
+                cur_bits -= 1;
             } else {
-                // skip cube
+                // skip box
                 cur_idx += numel;
-                cur_log2 = cur_idx.trailing_zeros() / 3;
+                cur_bits = cmp::min(max_bits, cur_idx.trailing_zeros());
             }
         }
 
@@ -125,118 +204,101 @@ impl Iterator for Iter {
 
         // advance
         self.idx += 1;
-        Some(self.idx as u64 - 1)
+        Some(self.idx - 1)
     }
 }
 
 #[test]
 fn test_encoding() {
-    assert_eq!(
-        Morton::from(&Vec3 { x: 0, y: 0, z: 0 }),
-        Morton::from(0 as u64)
-    );
-    assert_eq!(
-        Morton::from(&Vec3 { x: 1, y: 0, z: 0 }),
-        Morton::from(1 as u64)
-    );
-    assert_eq!(
-        Morton::from(&Vec3 { x: 0, y: 1, z: 0 }),
-        Morton::from(2 as u64)
-    );
-    assert_eq!(
-        Morton::from(&Vec3 { x: 1, y: 1, z: 0 }),
-        Morton::from(3 as u64)
-    );
-    assert_eq!(
-        Morton::from(&Vec3 { x: 0, y: 0, z: 1 }),
-        Morton::from(4 as u64)
-    );
-    assert_eq!(
-        Morton::from(&Vec3 { x: 1, y: 0, z: 1 }),
-        Morton::from(5 as u64)
-    );
-    assert_eq!(
-        Morton::from(&Vec3 { x: 0, y: 1, z: 1 }),
-        Morton::from(6 as u64)
-    );
-    assert_eq!(
-        Morton::from(&Vec3 { x: 1, y: 1, z: 1 }),
-        Morton::from(7 as u64)
-    );
-    assert_eq!(
-        Morton::from(&Vec3 { x: 2, y: 0, z: 0 }),
-        Morton::from(8 as u64)
-    );
+    assert_eq!(Morton::from(&Vec3 { x: 0, y: 0, z: 0 }), Morton::from(0u64));
+    assert_eq!(Morton::from(&Vec3 { x: 1, y: 0, z: 0 }), Morton::from(1u64));
+    assert_eq!(Morton::from(&Vec3 { x: 0, y: 1, z: 0 }), Morton::from(2u64));
+    assert_eq!(Morton::from(&Vec3 { x: 1, y: 1, z: 0 }), Morton::from(3u64));
+    assert_eq!(Morton::from(&Vec3 { x: 0, y: 0, z: 1 }), Morton::from(4u64));
+    assert_eq!(Morton::from(&Vec3 { x: 1, y: 0, z: 1 }), Morton::from(5u64));
+    assert_eq!(Morton::from(&Vec3 { x: 0, y: 1, z: 1 }), Morton::from(6u64));
+    assert_eq!(Morton::from(&Vec3 { x: 1, y: 1, z: 1 }), Morton::from(7u64));
+    assert_eq!(Morton::from(&Vec3 { x: 2, y: 0, z: 0 }), Morton::from(8u64));
     assert_eq!(
         Morton::from(&Vec3 { x: 0, y: 2, z: 0 }),
-        Morton::from(16 as u64)
+        Morton::from(16u64)
     );
     assert_eq!(
         Morton::from(&Vec3 { x: 0, y: 0, z: 2 }),
-        Morton::from(32 as u64)
+        Morton::from(32u64)
     );
     assert_eq!(
         Morton::from(&Vec3 { x: 29, y: 20, z: 3 }),
-        Morton::from(13029 as u64)
+        Morton::from(13029u64)
     );
     assert_eq!(
         Morton::from(&Vec3 { x: 23, y: 20, z: 3 }),
-        Morton::from(12525 as u64)
+        Morton::from(12525u64)
     );
 }
 
 #[test]
 fn test_decoding() {
+    assert_eq!(Vec3 { x: 0, y: 0, z: 0 }, Vec3::from(Morton::from(0u64)));
+    assert_eq!(Vec3 { x: 1, y: 0, z: 0 }, Vec3::from(Morton::from(1u64)));
+    assert_eq!(Vec3 { x: 0, y: 1, z: 0 }, Vec3::from(Morton::from(2u64)));
+    assert_eq!(Vec3 { x: 1, y: 1, z: 0 }, Vec3::from(Morton::from(3u64)));
+    assert_eq!(Vec3 { x: 0, y: 0, z: 1 }, Vec3::from(Morton::from(4u64)));
+    assert_eq!(Vec3 { x: 1, y: 0, z: 1 }, Vec3::from(Morton::from(5u64)));
+    assert_eq!(Vec3 { x: 0, y: 1, z: 1 }, Vec3::from(Morton::from(6u64)));
+    assert_eq!(Vec3 { x: 1, y: 1, z: 1 }, Vec3::from(Morton::from(7u64)));
+    assert_eq!(Vec3 { x: 2, y: 0, z: 0 }, Vec3::from(Morton::from(8u64)));
+    assert_eq!(Vec3 { x: 0, y: 2, z: 0 }, Vec3::from(Morton::from(16u64)));
+    assert_eq!(Vec3 { x: 0, y: 0, z: 2 }, Vec3::from(Morton::from(32u64)));
     assert_eq!(
-        Vec3 { x: 0, y: 0, z: 0 },
-        Vec3::from(Morton::from(0 as u64))
-    );
-    assert_eq!(
-        Vec3 { x: 1, y: 0, z: 0 },
-        Vec3::from(Morton::from(1 as u64))
-    );
-    assert_eq!(
-        Vec3 { x: 0, y: 1, z: 0 },
-        Vec3::from(Morton::from(2 as u64))
-    );
-    assert_eq!(
-        Vec3 { x: 1, y: 1, z: 0 },
-        Vec3::from(Morton::from(3 as u64))
-    );
-    assert_eq!(
-        Vec3 { x: 0, y: 0, z: 1 },
-        Vec3::from(Morton::from(4 as u64))
-    );
-    assert_eq!(
-        Vec3 { x: 1, y: 0, z: 1 },
-        Vec3::from(Morton::from(5 as u64))
-    );
-    assert_eq!(
-        Vec3 { x: 0, y: 1, z: 1 },
-        Vec3::from(Morton::from(6 as u64))
-    );
-    assert_eq!(
-        Vec3 { x: 1, y: 1, z: 1 },
-        Vec3::from(Morton::from(7 as u64))
-    );
-    assert_eq!(
-        Vec3 { x: 2, y: 0, z: 0 },
-        Vec3::from(Morton::from(8 as u64))
+        Vec3 { x: 29, y: 20, z: 3 },
+        Vec3::from(Morton::from(13029u64))
     );
     assert_eq!(
-        Vec3 { x: 0, y: 2, z: 0 },
-        Vec3::from(Morton::from(16 as u64))
+        Vec3 { x: 23, y: 20, z: 3 },
+        Vec3::from(Morton::from(12525u64))
     );
+}
+
+#[test]
+fn test_non_cubic() {
+    let log2 = Vec3 { x: 2, y: 1, z: 0 };
+    let shape = Vec3::from(1u32) << log2;
+
+    // bits are interleaved as x, y, x
     assert_eq!(
-        Vec3 { x: 0, y: 0, z: 2 },
-        Vec3::from(Morton::from(32 as u64))
+        Morton::from_vec(&Vec3 { x: 1, y: 1, z: 0 }, log2),
+        Morton(3)
     );
     assert_eq!(
-        Vec3 { x: 29, y: 20, z: 3 },
-        Vec3::from(Morton::from(13029 as u64))
+        Morton::from_vec(&Vec3 { x: 2, y: 0, z: 0 }, log2),
+        Morton(4)
     );
     assert_eq!(
-        Vec3 { x: 23, y: 20, z: 3 },
-        Vec3::from(Morton::from(12525 as u64))
+        Morton::from_vec(&Vec3 { x: 3, y: 1, z: 0 }, log2),
+        Morton(7)
     );
+
+    for idx in 0..shape.product() {
+        let vec = Morton(idx).to_vec(log2);
+        assert!(vec < shape + 1);
+        assert_eq!(Morton::from_vec(&vec, log2), Morton(idx));
+    }
+
+    // iterating over a box yields exactly the indices within it
+    let log2 = Vec3 { x: 3, y: 3, z: 1 };
+    let bbox = Box3::new(Vec3 { x: 1, y: 2, z: 1 }, Vec3 { x: 6, y: 3, z: 2 }).unwrap();
+    let mut idxs: Vec<u64> = Iter::with_shape(log2, bbox).unwrap().collect();
+    idxs.sort();
+
+    let expected: Vec<u64> = (0..(1u64 << 7))
+        .filter(|&idx| {
+            let vec = Morton(idx).to_vec(log2);
+            !(Box3::from(Vec3::from(1u32)) + vec)
+                .intersect(bbox)
+                .is_empty()
+        })
+        .collect();
+    assert_eq!(idxs, expected);
+    assert_eq!(idxs.len(), 5);
 }
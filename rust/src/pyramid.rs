@@ -0,0 +1,458 @@
+use std::collections::HashSet;
+use std::path::Path;
+use {Dataset, Error, Mat, Result, Vec3, VoxelType};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Interpolation {
+    /// Per-channel average, rounded to the nearest integer for integer
+    /// voxel types. Suited for intensity data.
+    Mean,
+    /// Most frequent voxel value, with ties going to the value seen first.
+    /// Suited for segmentations.
+    Mode,
+}
+
+/// Directory name of a magnification, e.g. `2` or `4-4-1`.
+pub fn mag_name(mag: Vec3) -> String {
+    match mag.x == mag.y && mag.y == mag.z {
+        true => mag.x.to_string(),
+        false => format!("{}-{}-{}", mag.x, mag.y, mag.z),
+    }
+}
+
+/// Builds the magnifications `mags` of `src` in directories below `root`
+/// named after them (see `mag_name`). Magnifications are relative to `src`
+/// and each one is computed from the closest preceding magnification it is
+/// a multiple of.
+pub fn build(
+    src: &Dataset,
+    root: &Path,
+    mags: &[Vec3],
+    interpolation: Interpolation,
+) -> Result<Vec<Dataset>> {
+    let mut datasets: Vec<(Vec3, Dataset)> = Vec::with_capacity(mags.len());
+
+    for &mag in mags {
+        let (base_mag, base) = datasets
+            .iter()
+            .rev()
+            .find(|&&(base_mag, _)| mag % base_mag == Vec3::from(0u32))
+            .map(|&(base_mag, ref base)| (base_mag, base))
+            .unwrap_or((Vec3::from(1u32), src));
+
+        let factor = Vec3 {
+            x: mag.x / base_mag.x,
+            y: mag.y / base_mag.y,
+            z: mag.z / base_mag.z,
+        };
+
+        let dst_root = root.join(mag_name(mag));
+        let dataset = downsample(base, &dst_root, factor, interpolation)?;
+        datasets.push((mag, dataset));
+    }
+
+    Ok(datasets.into_iter().map(|(_, dataset)| dataset).collect())
+}
+
+/// Creates a new dataset at `root` which is `src` downsampled by `factor`.
+/// The factors must be powers of two no larger than the file shape (in
+/// voxels). The new dataset is written file by file, using the same number
+/// of threads as `src`. Each thread buffers one destination file, so that
+/// compressed files are written only once, and reads `src` block by block.
+/// The header and fill value are those of `src`, with the voxel size (if
+/// any) scaled by `factor`.
+pub fn downsample(
+    src: &Dataset,
+    root: &Path,
+    factor: Vec3,
+    interpolation: Interpolation,
+) -> Result<Dataset> {
+    let header = src.header();
+    let file_shape = header.file_shape_vx();
+
+    for &(f, file_len_vx) in &[
+        (factor.x, file_shape.x),
+        (factor.y, file_shape.y),
+        (factor.z, file_shape.z),
+    ] {
+        if !f.is_power_of_two() || f > file_len_vx {
+            return Err(Error::invalid(format!(
+                "Invalid downsampling factor {:?}",
+                factor
+            )));
+        }
+    }
+
+    // voxels of the new dataset are larger by `factor`
+    let mut dst_header = header.clone();
+    if let Some(ref mut metadata) = dst_header.metadata {
+        if let Some(ref mut voxel_size_nm) = metadata.voxel_size_nm {
+            voxel_size_nm[0] *= factor.x as f64;
+            voxel_size_nm[1] *= factor.y as f64;
+            voxel_size_nm[2] *= factor.z as f64;
+        }
+    }
+
+    let mut dst = Dataset::create(root, dst_header)?;
+    dst.set_num_threads(src.num_threads());
+
+    if src.fill_value().iter().any(|&byte| byte != 0) {
+        dst.set_fill_value(src.fill_value())?;
+    }
+
+    // destination files which have at least one source file
+    let mut dst_file_ids: Vec<Vec3> = src
+        .list_files()?
+        .into_iter()
+        .map(|(ids, _)| Vec3 {
+            x: ids.x / factor.x,
+            y: ids.y / factor.y,
+            z: ids.z / factor.z,
+        })
+        .collect::<HashSet<Vec3>>()
+        .into_iter()
+        .collect();
+    dst_file_ids.sort_by_key(|ids| (ids.z, ids.y, ids.x));
+
+    let voxel_size = header.voxel_size as usize;
+    let voxel_type = header.voxel_type;
+
+    // source voxels are read in aligned chunks of one block (or of one
+    // window, if that is larger) and downsampled into the destination file
+    let chunk_shape = header.block_shape().elem_max(factor);
+    let dst_chunk_shape = chunk_shape / factor;
+    let num_chunks = file_shape / dst_chunk_shape;
+
+    dst.for_each_file(&dst_file_ids, |cur_dst_ids| {
+        let mut dst_buf = vec![0u8; header.file_size()];
+        let mut src_buf = vec![0u8; chunk_shape.product() as usize * voxel_size];
+
+        {
+            let mut dst_mat = Mat::new(&mut dst_buf, file_shape, voxel_size, voxel_type, false)?;
+            let src_off = cur_dst_ids * file_shape * factor;
+
+            for z in 0..num_chunks.z {
+                for y in 0..num_chunks.y {
+                    for x in 0..num_chunks.x {
+                        let chunk_ids = Vec3 { x, y, z };
+                        let mut src_mat =
+                            Mat::new(&mut src_buf, chunk_shape, voxel_size, voxel_type, false)?;
+                        src.read_mat(src_off + chunk_ids * chunk_shape, &mut src_mat)?;
+
+                        let dst_pos = chunk_ids * dst_chunk_shape;
+                        downsample_mat(&src_mat, &mut dst_mat, dst_pos, factor, interpolation);
+                    }
+                }
+            }
+        }
+
+        let dst_mat = Mat::new(&mut dst_buf, file_shape, voxel_size, voxel_type, false)?;
+        dst.write_mat(cur_dst_ids * file_shape, &dst_mat)
+            .map(|_| ())
+    })?;
+
+    Ok(dst)
+}
+
+// Downsamples all of `src` into `dst`, starting at `dst_pos`. Both
+// matrices must be in Fortran order and the shape of `src` a multiple of
+// `factor`.
+fn downsample_mat(
+    src: &Mat,
+    dst: &mut Mat,
+    dst_pos: Vec3,
+    factor: Vec3,
+    interpolation: Interpolation,
+) {
+    let voxel_size = src.voxel_size;
+    let src_shape = src.shape;
+    let dst_shape = dst.shape;
+    let src_data = src.as_slice();
+    let dst_data = dst.as_mut_slice();
+
+    let mut window: Vec<&[u8]> = Vec::with_capacity(factor.product() as usize);
+    let mut order: Vec<usize> = Vec::with_capacity(window.capacity());
+
+    for z in 0..(src_shape.z / factor.z) {
+        for y in 0..(src_shape.y / factor.y) {
+            for x in 0..(src_shape.x / factor.x) {
+                window.clear();
+
+                for wz in 0..factor.z {
+                    for wy in 0..factor.y {
+                        for wx in 0..factor.x {
+                            let sx = (x * factor.x + wx) as usize;
+                            let sy = (y * factor.y + wy) as usize;
+                            let sz = (z * factor.z + wz) as usize;
+
+                            let idx = sx + src_shape.x as usize * (sy + src_shape.y as usize * sz);
+                            let offset = idx * voxel_size;
+                            window.push(&src_data[offset..(offset + voxel_size)]);
+                        }
+                    }
+                }
+
+                let dx = (dst_pos.x + x) as usize;
+                let dy = (dst_pos.y + y) as usize;
+                let dz = (dst_pos.z + z) as usize;
+
+                let idx = dx + dst_shape.x as usize * (dy + dst_shape.y as usize * dz);
+                let offset = idx * voxel_size;
+                let voxel = &mut dst_data[offset..(offset + voxel_size)];
+
+                match interpolation {
+                    Interpolation::Mean => mean(&window, voxel, src.voxel_type),
+                    Interpolation::Mode => mode(&window, &mut order, voxel),
+                }
+            }
+        }
+    }
+}
+
+// `order` is scratch space for the window indices, sorted by value
+fn mode(window: &[&[u8]], order: &mut Vec<usize>, out: &mut [u8]) {
+    order.clear();
+    order.extend(0..window.len());
+    // stable, so equal values stay in window order
+    order.sort_by_key(|&idx| window[idx]);
+
+    let mut best_idx = 0;
+    let mut best_count = 0;
+
+    for run in order.chunk_by(|&a, &b| window[a] == window[b]) {
+        let first_idx = run[0];
+        if run.len() > best_count || (run.len() == best_count && first_idx < best_idx) {
+            best_idx = first_idx;
+            best_count = run.len();
+        }
+    }
+
+    out.copy_from_slice(window[best_idx]);
+}
+
+macro_rules! mean_int {
+    ($window:expr, $out:expr, $ty:ty) => {{
+        const SIZE: usize = ::std::mem::size_of::<$ty>();
+        let n = $window.len() as i128;
+
+        for (elem_idx, out) in $out.chunks_mut(SIZE).enumerate() {
+            let offset = elem_idx * SIZE;
+            let sum: i128 = $window
+                .iter()
+                .map(|voxel| {
+                    let mut bytes = [0u8; SIZE];
+                    bytes.copy_from_slice(&voxel[offset..(offset + SIZE)]);
+                    <$ty>::from_le_bytes(bytes) as i128
+                })
+                .sum();
+
+            // round half away from zero
+            let mean = match sum < 0 {
+                true => (sum - n / 2) / n,
+                false => (sum + n / 2) / n,
+            };
+
+            out.copy_from_slice(&(mean as $ty).to_le_bytes());
+        }
+    }};
+}
+
+macro_rules! mean_float {
+    ($window:expr, $out:expr, $ty:ty) => {{
+        const SIZE: usize = ::std::mem::size_of::<$ty>();
+        let n = $window.len() as f64;
+
+        for (elem_idx, out) in $out.chunks_mut(SIZE).enumerate() {
+            let offset = elem_idx * SIZE;
+            let sum: f64 = $window
+                .iter()
+                .map(|voxel| {
+                    let mut bytes = [0u8; SIZE];
+                    bytes.copy_from_slice(&voxel[offset..(offset + SIZE)]);
+                    <$ty>::from_le_bytes(bytes) as f64
+                })
+                .sum();
+
+            out.copy_from_slice(&((sum / n) as $ty).to_le_bytes());
+        }
+    }};
+}
+
+fn mean(window: &[&[u8]], out: &mut [u8], voxel_type: VoxelType) {
+    match voxel_type {
+        VoxelType::U8 => mean_int!(window, out, u8),
+        VoxelType::U16 => mean_int!(window, out, u16),
+        VoxelType::U32 => mean_int!(window, out, u32),
+        VoxelType::U64 => mean_int!(window, out, u64),
+        VoxelType::I8 => mean_int!(window, out, i8),
+        VoxelType::I16 => mean_int!(window, out, i16),
+        VoxelType::I32 => mean_int!(window, out, i32),
+        VoxelType::I64 => mean_int!(window, out, i64),
+        VoxelType::F32 => mean_float!(window, out, f32),
+        VoxelType::F64 => mean_float!(window, out, f64),
+    }
+}
+
+#[test]
+fn test_build() {
+    use {BlockType, Header};
+
+    let root = ::std::env::temp_dir().join(format!("wkwrap-pyramid-{}", ::std::process::id()));
+    let _ = ::std::fs::remove_dir_all(&root);
+
+    let header = Header::for_tests(1, 1, 1, BlockType::LZ4, VoxelType::U32);
+    let src = Dataset::create(&root.join("1"), header).unwrap();
+
+    // spans 2 x 2 x 1 files of 4^3 voxels
+    let shape = Vec3 { x: 8, y: 8, z: 4 };
+    let values: Vec<u32> = (0..shape.product() as u32)
+        .map(|i| match i % 8 {
+            0 => 7,
+            _ => i % 3,
+        })
+        .collect();
+    let mut data: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+    let mat = Mat::new(&mut data, shape, 4, VoxelType::U32, false).unwrap();
+    src.write_mat(Vec3::from(0u32), &mat).unwrap();
+
+    let read = |dataset: &Dataset, shape: Vec3| -> Vec<u32> {
+        let mut buf = vec![0u8; shape.product() as usize * 4];
+        {
+            let mut mat = Mat::new(&mut buf, shape, 4, VoxelType::U32, false).unwrap();
+            dataset.read_mat(Vec3::from(0u32), &mut mat).unwrap();
+        }
+        buf.chunks(4)
+            .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+            .collect()
+    };
+
+    // expected values, computed naively
+    let window = |off: Vec3, factor: Vec3| -> Vec<u32> {
+        let mut window = Vec::new();
+        for z in off.z..(off.z + factor.z) {
+            for y in off.y..(off.y + factor.y) {
+                for x in off.x..(off.x + factor.x) {
+                    window.push(values[(x + shape.x * (y + shape.y * z)) as usize]);
+                }
+            }
+        }
+        window
+    };
+
+    let mags = [Vec3::from(2u32), Vec3 { x: 4, y: 4, z: 1 }];
+    let mean_root = root.join("mean");
+    let datasets = build(&src, &mean_root, &mags, Interpolation::Mean).unwrap();
+    assert!(mean_root.join("4-4-1").join("header.wkw").exists());
+
+    let out = read(&datasets[0], Vec3 { x: 4, y: 4, z: 2 });
+    for (idx, &value) in out.iter().enumerate() {
+        let idx = idx as u32;
+        let pos = Vec3 {
+            x: idx % 4,
+            y: (idx / 4) % 4,
+            z: idx / 16,
+        };
+        let window = window(pos * 2, Vec3::from(2u32));
+        let sum: u32 = window.iter().sum();
+        assert_eq!(value, (sum + 4) / 8);
+    }
+
+    let mode_root = root.join("mode");
+    let datasets = build(&src, &mode_root, &mags, Interpolation::Mode).unwrap();
+
+    let out = read(&datasets[1], Vec3 { x: 2, y: 2, z: 4 });
+    for (idx, &value) in out.iter().enumerate() {
+        let idx = idx as u32;
+        let pos = Vec3 {
+            x: idx % 2,
+            y: (idx / 2) % 2,
+            z: idx / 4,
+        };
+        let window = window(
+            Vec3 {
+                x: pos.x * 4,
+                y: pos.y * 4,
+                z: pos.z,
+            },
+            Vec3 { x: 4, y: 4, z: 1 },
+        );
+        let count = |v: u32| window.iter().filter(|&&w| w == v).count();
+        assert!(window.iter().all(|&v| count(v) <= count(value)));
+    }
+
+    assert!(downsample(&src, &root.join("3"), Vec3::from(3u32), Interpolation::Mean).is_err());
+
+    ::std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_header() {
+    use {BlockType, Header, Metadata};
+
+    let root = ::std::env::temp_dir().join(format!("wkwrap-pyramid-hdr-{}", ::std::process::id()));
+    let _ = ::std::fs::remove_dir_all(&root);
+
+    for &version in &[1, 2] {
+        let header = Header {
+            metadata: match version {
+                1 => None,
+                _ => Some(Metadata {
+                    voxel_size_nm: Some([1.0, 2.0, 3.0]),
+                    ..Metadata::default()
+                }),
+            },
+            ..Header::for_tests(version, 1, 1, BlockType::LZ4, VoxelType::U8)
+        };
+
+        let version_root = root.join(version.to_string());
+        let mut src = Dataset::create(&version_root.join("1"), header).unwrap();
+        src.set_fill_value(&[5]).unwrap();
+
+        let mut data = vec![1u8; 4 * 4 * 4];
+        let mat = Mat::new(&mut data, Vec3::from(4u32), 1, VoxelType::U8, false).unwrap();
+        src.write_mat(Vec3::from(0u32), &mat).unwrap();
+
+        let mags = [Vec3::from(2u32), Vec3 { x: 4, y: 4, z: 1 }];
+        build(&src, &version_root, &mags, Interpolation::Mean).unwrap();
+
+        let voxel_sizes_nm = [[2.0, 4.0, 6.0], [4.0, 8.0, 3.0]];
+        for (&mag, &voxel_size_nm) in mags.iter().zip(voxel_sizes_nm.iter()) {
+            // reopen, to check what was written
+            let dataset = Dataset::new(&version_root.join(mag_name(mag))).unwrap();
+            assert_eq!(dataset.fill_value(), &[5]);
+
+            let metadata = dataset.header().metadata.as_ref();
+            match version {
+                1 => assert!(metadata.is_none()),
+                _ => assert_eq!(metadata.unwrap().voxel_size_nm, Some(voxel_size_nm)),
+            }
+
+            // missing files read as the fill value
+            let mut out = vec![0u8; 8];
+            {
+                let mut out_mat =
+                    Mat::new(&mut out, Vec3::from(2u32), 1, VoxelType::U8, false).unwrap();
+                dataset.read_mat(Vec3::from(8u32), &mut out_mat).unwrap();
+            }
+            assert_eq!(out, vec![5u8; 8]);
+        }
+    }
+
+    ::std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_mode() {
+    let mut order = Vec::new();
+    let mut out = [0u8; 2];
+
+    let window: [&[u8]; 6] = [b"ab", b"cd", b"cd", b"ef", b"ab", b"ef"];
+    mode(&window, &mut order, &mut out);
+    assert_eq!(&out, b"ab");
+
+    mode(&window[1..], &mut order, &mut out);
+    assert_eq!(&out, b"cd");
+
+    mode(&window[3..], &mut order, &mut out);
+    assert_eq!(&out, b"ef");
+}
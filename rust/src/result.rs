@@ -1,3 +1,4 @@
 use std::result;
+use Error;
 
-pub type Result<T> = result::Result<T, String>;
+pub type Result<T> = result::Result<T, Error>;
@@ -0,0 +1,281 @@
+//! Storage backends for datasets.
+//!
+//! Objects are addressed by keys relative to the dataset root, with `/` as
+//! separator (e.g. `z0/y1/x2.wkw`).
+
+use file;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use {Error, Result};
+
+#[cfg(feature = "s3")]
+mod s3;
+#[cfg(feature = "s3")]
+pub use self::s3::S3Storage;
+
+pub trait Storage: Debug + Send + Sync {
+    /// Reads the bytes `range` of object `key`. The result is shorter than
+    /// the range if the object ends before it.
+    fn get_range(&self, key: &str, range: Range<u64>) -> Result<Vec<u8>>;
+
+    /// Size of object `key` in bytes.
+    fn size(&self, key: &str) -> Result<u64>;
+
+    fn exists(&self, key: &str) -> Result<bool>;
+
+    /// Atomically creates or replaces object `key`.
+    fn put(&self, key: &str, buf: &[u8]) -> Result<()>;
+
+    /// Names of the objects and prefixes directly below `prefix`, which is
+    /// either empty or ends with a separator. The order is unspecified.
+    fn list(&self, prefix: &str) -> Result<Vec<String>>;
+
+    fn rename(&self, old_key: &str, new_key: &str) -> Result<()>;
+
+    /// Path of object `key` in the local filesystem, if the backend stores
+    /// objects as files. Allows raw files to be written in place and to be
+    /// memory mapped.
+    fn local_path(&self, _key: &str) -> Option<PathBuf> {
+        None
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>> {
+        self.get_range(key, 0..u64::MAX)
+    }
+}
+
+/// Objects stored as files below a root directory.
+#[derive(Debug, Clone)]
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: &Path) -> LocalStorage {
+        LocalStorage {
+            root: root.to_owned(),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        let mut path = self.root.clone();
+        path.extend(key.split('/').filter(|part| !part.is_empty()));
+        path
+    }
+
+    fn create_parent(path: &Path) -> Result<()> {
+        match path.parent() {
+            Some(parent) => fs::create_dir_all(parent)
+                .map_err(|err| Error::io_at("Could not create parent directory", parent, err)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Storage for LocalStorage {
+    fn get_range(&self, key: &str, range: Range<u64>) -> Result<Vec<u8>> {
+        let path = self.path(key);
+        let mut file =
+            fs::File::open(&path).map_err(|err| Error::io_at("Could not open file", &path, err))?;
+
+        let mut buf = Vec::new();
+        file.seek(SeekFrom::Start(range.start))
+            .and_then(|_| {
+                file.take(range.end.saturating_sub(range.start))
+                    .read_to_end(&mut buf)
+            })
+            .map_err(|err| Error::io_at("Could not read file", &path, err))?;
+
+        Ok(buf)
+    }
+
+    fn size(&self, key: &str) -> Result<u64> {
+        let path = self.path(key);
+        fs::metadata(&path)
+            .map(|metadata| metadata.len())
+            .map_err(|err| Error::io_at("Could not stat file", &path, err))
+    }
+
+    fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.path(key).exists())
+    }
+
+    fn put(&self, key: &str, buf: &[u8]) -> Result<()> {
+        let path = self.path(key);
+        Self::create_parent(&path)?;
+
+        // write into temporary file first
+        let tmp_path = file::tmp_path(&path);
+
+        let result = fs::File::create(&tmp_path)
+            .and_then(|mut file| file.write_all(buf))
+            .map_err(|err| Error::io_at("Could not write file", &tmp_path, err))
+            .and_then(|_| {
+                fs::rename(&tmp_path, &path).map_err(|err| Error::rename(&tmp_path, &path, err))
+            });
+
+        // do not leave partial files behind
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+
+        result
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let path = self.path(prefix);
+        let entries = fs::read_dir(&path)
+            .map_err(|err| Error::io_at("Could not list directory", &path, err))?;
+
+        let mut names = Vec::new();
+        for entry in entries {
+            let entry =
+                entry.map_err(|err| Error::io_at("Could not list directory", &path, err))?;
+
+            if let Some(name) = entry.file_name().to_str() {
+                names.push(String::from(name));
+            }
+        }
+
+        Ok(names)
+    }
+
+    fn rename(&self, old_key: &str, new_key: &str) -> Result<()> {
+        let old_path = self.path(old_key);
+        let new_path = self.path(new_key);
+        Self::create_parent(&new_path)?;
+
+        fs::rename(&old_path, &new_path).map_err(|err| Error::rename(&old_path, &new_path, err))
+    }
+
+    fn local_path(&self, key: &str) -> Option<PathBuf> {
+        Some(self.path(key))
+    }
+}
+
+/// Objects stored in memory, e.g. for tests.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    objects: RwLock<BTreeMap<String, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+
+    fn not_found(key: &str) -> Error {
+        let err = io::Error::new(io::ErrorKind::NotFound, "No such object");
+        Error::io_at("Could not access object", Path::new(key), err)
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get_range(&self, key: &str, range: Range<u64>) -> Result<Vec<u8>> {
+        let objects = self.objects.read().unwrap();
+        let buf = objects.get(key).ok_or_else(|| Self::not_found(key))?;
+
+        let len = buf.len() as u64;
+        let start = range.start.min(len) as usize;
+        let end = range.end.min(len).max(start as u64) as usize;
+        Ok(buf[start..end].to_vec())
+    }
+
+    fn size(&self, key: &str) -> Result<u64> {
+        let objects = self.objects.read().unwrap();
+        match objects.get(key) {
+            Some(buf) => Ok(buf.len() as u64),
+            None => Err(Self::not_found(key)),
+        }
+    }
+
+    fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.objects.read().unwrap().contains_key(key))
+    }
+
+    fn put(&self, key: &str, buf: &[u8]) -> Result<()> {
+        let mut objects = self.objects.write().unwrap();
+        objects.insert(String::from(key), buf.to_vec());
+        Ok(())
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let objects = self.objects.read().unwrap();
+
+        let mut names: Vec<String> = objects
+            .keys()
+            .filter_map(|key| key.strip_prefix(prefix))
+            .map(|rest| String::from(rest.split('/').next().unwrap()))
+            .collect();
+
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    fn rename(&self, old_key: &str, new_key: &str) -> Result<()> {
+        let mut objects = self.objects.write().unwrap();
+        let buf = objects
+            .remove(old_key)
+            .ok_or_else(|| Self::not_found(old_key))?;
+
+        objects.insert(String::from(new_key), buf);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_backends() {
+    let root = ::std::env::temp_dir().join(format!("wkwrap-storage-{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+
+    let backends: Vec<Box<dyn Storage>> = vec![
+        Box::new(LocalStorage::new(&root)),
+        Box::new(MemoryStorage::new()),
+    ];
+
+    for storage in &backends {
+        storage.put("header.wkw", b"header").unwrap();
+        storage.put("z0/y0/x0.wkw", b"0123456789").unwrap();
+        storage.put("z0/y1/x0.wkw", b"").unwrap();
+        storage.put("z0/y1/x1.wkw", b"").unwrap();
+
+        let mut names = storage.list("").unwrap();
+        names.sort();
+        assert_eq!(names, vec!["header.wkw", "z0"]);
+
+        let mut names = storage.list("z0/").unwrap();
+        names.sort();
+        assert_eq!(names, vec!["y0", "y1"]);
+        assert_eq!(storage.list("z0/y1/").unwrap().len(), 2);
+
+        assert_eq!(storage.size("z0/y0/x0.wkw").unwrap(), 10);
+        assert_eq!(storage.get_range("z0/y0/x0.wkw", 2..5).unwrap(), b"234");
+        assert_eq!(storage.get_range("z0/y0/x0.wkw", 8..20).unwrap(), b"89");
+
+        storage.rename("z0/y0/x0.wkw", "z1/y0/x0.wkw").unwrap();
+        assert!(!storage.exists("z0/y0/x0.wkw").unwrap());
+        assert_eq!(storage.get("z1/y0/x0.wkw").unwrap(), b"0123456789");
+        assert!(storage.get("z0/y0/x0.wkw").unwrap_err().is_not_found());
+    }
+
+    // failed puts do not leave temporary files behind
+    let storage = LocalStorage::new(&root);
+    storage.put("dir/file", b"").unwrap();
+    assert!(storage.put("dir", b"0123").is_err());
+    let mut names = storage.list("").unwrap();
+    names.sort();
+    assert_eq!(names, vec!["dir", "header.wkw", "z0", "z1"]);
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -1,7 +1,7 @@
 use std::cmp::{max, min, Ordering};
-use Result;
+use {Error, Result};
 
-#[derive(Copy, Clone, PartialEq, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub struct Vec3 {
     pub x: u32,
     pub y: u32,
@@ -18,7 +18,7 @@ impl Box3 {
     pub fn new(min: Vec3, max: Vec3) -> Result<Box3> {
         match min < (max + 1) {
             true => Ok(Box3 { min, max }),
-            false => Err(String::from("Minimum and maximum are in conflict")),
+            false => Err(Error::invalid("Minimum and maximum are in conflict")),
         }
     }
 
@@ -42,6 +42,13 @@ impl Box3 {
             max: self.max.elem_min(rhs.max).elem_max(self.min),
         }
     }
+
+    pub fn union(&self, rhs: Box3) -> Box3 {
+        Box3 {
+            min: self.min.elem_min(rhs.min),
+            max: self.max.elem_max(rhs.max),
+        }
+    }
 }
 
 impl From<Vec3> for Box3 {
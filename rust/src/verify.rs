@@ -0,0 +1,47 @@
+use std::fmt;
+use std::path::PathBuf;
+use Error;
+
+#[derive(Debug)]
+pub enum Problem {
+    Header(Error),
+    JumpTable { block_idx: u64 },
+    FileLength { expected: u64, found: u64 },
+    Block { block_idx: u64, error: Error },
+}
+
+#[derive(Debug)]
+pub struct Report {
+    pub path: PathBuf,
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Problem::Header(ref err) => write!(f, "Invalid header: {}", err),
+            Problem::JumpTable { block_idx } => {
+                write!(
+                    f,
+                    "Jump table entry of block {} is not monotonic",
+                    block_idx
+                )
+            }
+            Problem::FileLength { expected, found } => write!(
+                f,
+                "File has length {} bytes, expected {} bytes",
+                found, expected
+            ),
+            Problem::Block {
+                block_idx,
+                ref error,
+            } => write!(f, "Block {} is corrupt: {}", block_idx, error),
+        }
+    }
+}
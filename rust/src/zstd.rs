@@ -0,0 +1,25 @@
+extern crate zstd as zstd_crate;
+use self::zstd_crate::{bulk, zstd_safe};
+use {Error, Result};
+
+// compression level used if the header does not specify one
+pub const DEFAULT_LEVEL: i32 = 3;
+
+pub fn compress_bound(input_size: usize) -> usize {
+    zstd_safe::compress_bound(input_size)
+}
+
+pub fn compress(src_buf: &[u8], dst_buf: &mut [u8], level: u8) -> Result<usize> {
+    let level = match level {
+        0 => DEFAULT_LEVEL,
+        level => level as i32,
+    };
+
+    bulk::compress_to_buffer(src_buf, dst_buf, level)
+        .map_err(|_| Error::Compression("Error in ZSTD_compress"))
+}
+
+pub fn decompress(src_buf: &[u8], dst_buf: &mut [u8]) -> Result<usize> {
+    bulk::decompress_to_buffer(src_buf, dst_buf)
+        .map_err(|err| Error::Decompression(format!("Error in ZSTD_decompress: {}", err)))
+}
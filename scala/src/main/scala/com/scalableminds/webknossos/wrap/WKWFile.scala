@@ -83,6 +83,9 @@ trait WKWCompressionHelper extends BoxImplicits {
 
   protected def decompressBlock(sourceBlockType: BlockType.Value, numBytesPerBlock: Int)(compressedBlock: Array[Byte]): Box[Array[Byte]] = {
     val result = sourceBlockType match {
+      case BlockType.LZ4 | BlockType.LZ4HC if compressedBlock.isEmpty =>
+        // blocks without data consist of the fill value (zero by default)
+        Full(Array.ofDim[Byte](numBytesPerBlock))
       case BlockType.LZ4 | BlockType.LZ4HC =>
         val rawBlock: Array[Byte] = Array.ofDim[Byte](numBytesPerBlock)
         for {